@@ -0,0 +1,21 @@
+-- Create devices table
+-- One row per client installation (phone, desktop, web) of a user.
+-- The client keeps the device id returned at login and sends it back on the next login.
+CREATE TABLE IF NOT EXISTS devices (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    device_name VARCHAR(255),
+    platform VARCHAR(50), -- 'android', 'ios', 'web', 'macos', 'windows', 'linux'
+    last_ip VARCHAR(45),
+    last_user_agent VARCHAR(512),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    last_seen_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Bind every session (and therefore every issued token) to a device
+-- Sessions opened before this migration have no device
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS device_id UUID REFERENCES devices(id) ON DELETE CASCADE;
+
+-- Create indexes
+CREATE INDEX IF NOT EXISTS idx_devices_user_id ON devices(user_id);
+CREATE INDEX IF NOT EXISTS idx_sessions_device_id ON sessions(device_id);
@@ -0,0 +1,17 @@
+-- Create pending_events table
+-- WebSocket events (message metadata, call signalling, receipts) addressed to a user
+-- who has no live connection. They are replayed in order on the next connection
+-- and deleted once the client acknowledges them.
+-- IMPORTANT: events are the same metadata-only payloads routed over WebSocket, never content
+CREATE TABLE IF NOT EXISTS pending_events (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    seq BIGSERIAL NOT NULL, -- Delivery order
+    recipient_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    event TEXT NOT NULL, -- Serialized WebSocket message
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- Create indexes
+CREATE INDEX IF NOT EXISTS idx_pending_events_recipient_seq ON pending_events(recipient_id, seq);
+CREATE INDEX IF NOT EXISTS idx_pending_events_expires_at ON pending_events(expires_at);
@@ -0,0 +1,7 @@
+-- Add delivery state to messages
+-- A message is 'sent' once stored, 'delivered' when a recipient device acknowledges it,
+-- and 'read' when the recipient opens it (read implies delivered)
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS delivered_at TIMESTAMP WITH TIME ZONE;
+
+-- Messages already read were obviously delivered
+UPDATE messages SET delivered_at = read_at WHERE delivered_at IS NULL AND read_at IS NOT NULL;
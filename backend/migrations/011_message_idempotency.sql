@@ -0,0 +1,8 @@
+-- Add client-generated idempotency key to messages
+-- A client retrying the same message frame reuses its key and gets back the stored message
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS client_message_id VARCHAR(64);
+
+-- A key is unique per sender only
+CREATE UNIQUE INDEX IF NOT EXISTS idx_messages_sender_client_message_id
+    ON messages(sender_id, client_message_id)
+    WHERE client_message_id IS NOT NULL;
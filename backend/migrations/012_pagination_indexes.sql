@@ -0,0 +1,4 @@
+-- Indexes matching the (timestamp, id) cursors used to page through history
+CREATE INDEX IF NOT EXISTS idx_messages_conversation_cursor ON messages(conversation_id, timestamp DESC, id DESC);
+CREATE INDEX IF NOT EXISTS idx_channel_messages_channel_cursor ON channel_messages(channel_id, timestamp DESC, id DESC);
+CREATE INDEX IF NOT EXISTS idx_calls_created_at_cursor ON calls(created_at DESC, id DESC);
@@ -0,0 +1,5 @@
+-- Who can see a user's presence (online status and last seen)
+-- 'everyone': any user, 'contacts': users sharing a conversation or channel, 'nobody': no one
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS last_seen_visibility VARCHAR(20) NOT NULL DEFAULT 'everyone'
+    CHECK (last_seen_visibility IN ('everyone', 'contacts', 'nobody'));
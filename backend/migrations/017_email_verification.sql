@@ -0,0 +1,5 @@
+-- Email ownership, proven by the link sent at registration
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_verified_at TIMESTAMP WITH TIME ZONE;
+
+-- Accounts created before verification existed keep working as before
+UPDATE users SET email_verified_at = created_at WHERE email_verified_at IS NULL;
@@ -0,0 +1,5 @@
+-- Password hashes are stored in PHC string format ($argon2id$v=19$m=...,t=...,p=...$salt$hash).
+-- bcrypt hashes ($2b$...) are still accepted and replaced by Argon2id at the next login.
+ALTER TABLE users ALTER COLUMN password_hash TYPE TEXT;
+
+COMMENT ON COLUMN users.password_hash IS 'PHC string (Argon2id), or legacy bcrypt hash until the next login';
@@ -0,0 +1,5 @@
+-- Profile editing
+-- Usernames can be changed once per cooldown; uploaded avatars are stored on disk under their id,
+-- avatar_url points to the backend route serving them
+ALTER TABLE users ADD COLUMN IF NOT EXISTS username_changed_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS avatar_id UUID;
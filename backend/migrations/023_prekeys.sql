@@ -0,0 +1,24 @@
+-- Signal public key directory
+-- Devices publish PUBLIC keys only, so that anyone can start a session with them without a prior exchange.
+-- Private keys never leave the devices.
+CREATE TABLE IF NOT EXISTS device_keys (
+    device_id UUID PRIMARY KEY REFERENCES devices(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    registration_id INTEGER NOT NULL,
+    identity_key BYTEA NOT NULL,
+    signed_prekey_id INTEGER NOT NULL,
+    signed_prekey BYTEA NOT NULL,
+    signed_prekey_signature BYTEA NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- One-time prekeys are handed out once, then deleted
+CREATE TABLE IF NOT EXISTS one_time_prekeys (
+    device_id UUID NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+    key_id INTEGER NOT NULL,
+    public_key BYTEA NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (device_id, key_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_device_keys_user_id ON device_keys(user_id);
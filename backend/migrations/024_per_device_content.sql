@@ -0,0 +1,11 @@
+-- One ciphertext per (message, destination device)
+-- Signal encrypts a message once for every device of the recipient and for the other devices of the sender.
+-- A row without device is a single ciphertext readable by every device, as stored by older clients.
+ALTER TABLE encrypted_content ADD COLUMN IF NOT EXISTS device_id UUID REFERENCES devices(id) ON DELETE CASCADE;
+
+ALTER TABLE encrypted_content DROP CONSTRAINT IF EXISTS encrypted_content_message_id_key;
+ALTER TABLE encrypted_content DROP CONSTRAINT IF EXISTS encrypted_content_message_device_key;
+ALTER TABLE encrypted_content ADD CONSTRAINT encrypted_content_message_device_key
+    UNIQUE NULLS NOT DISTINCT (message_id, device_id);
+
+COMMENT ON COLUMN encrypted_content.device_id IS 'Only device allowed to fetch this ciphertext, NULL for every device of the participants';
@@ -0,0 +1,20 @@
+-- Sealed sender: messages whose sender is only known inside the encrypted envelope
+-- Contacts derive the delivery token (unidentified access key) from the recipient's profile key,
+-- the server keeps its SHA-256 only. NULL means the user doesn't accept sealed sender messages.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS unidentified_access_key_hash VARCHAR(64);
+
+-- Sealed envelopes wait here until their destination device acknowledges them
+-- IMPORTANT: no sender and no conversation, the recipient device is the only routing metadata
+CREATE TABLE IF NOT EXISTS sealed_messages (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    seq BIGSERIAL NOT NULL, -- Delivery order
+    recipient_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    device_id UUID NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+    envelope BYTEA NOT NULL, -- Sender certificate and ciphertext, encrypted for the device
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- Create indexes
+CREATE INDEX IF NOT EXISTS idx_sealed_messages_device_seq ON sealed_messages(device_id, seq);
+CREATE INDEX IF NOT EXISTS idx_sealed_messages_expires_at ON sealed_messages(expires_at);
@@ -0,0 +1,26 @@
+-- Key transparency: append-only log of the identity keys published by the devices of each user
+-- Entries are hash-chained per user, entry_hash = SHA-256(previous_hash || log_index || device_id || identity_key)
+-- with the first entry chaining from 32 zero bytes. A client checks with an inclusion proof that the key
+-- it was handed is in the log, and that the head it saw before is still part of the chain.
+CREATE TABLE IF NOT EXISTS identity_key_log (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    log_index BIGINT NOT NULL,
+    device_id UUID NOT NULL, -- No foreign key, entries outlive removed devices
+    identity_key BYTEA NOT NULL,
+    previous_hash BYTEA NOT NULL,
+    entry_hash BYTEA NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, log_index)
+);
+
+-- Entries are never rewritten, they only go away with the account
+CREATE OR REPLACE FUNCTION identity_key_log_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'identity_key_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS identity_key_log_append_only ON identity_key_log;
+CREATE TRIGGER identity_key_log_append_only
+    BEFORE UPDATE ON identity_key_log
+    FOR EACH ROW EXECUTE FUNCTION identity_key_log_append_only();
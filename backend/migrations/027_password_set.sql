@@ -0,0 +1,10 @@
+-- Whether the user chose a password: accounts provisioned by SSO get a random one nobody knows,
+-- they confirm sensitive actions with a fresh sign-in instead
+ALTER TABLE users ADD COLUMN IF NOT EXISTS password_set BOOLEAN NOT NULL DEFAULT TRUE;
+
+-- Accounts provisioned by SSO before this column existed, unless a password was set since
+UPDATE users SET password_set = FALSE
+WHERE id IN (SELECT user_id FROM security_events WHERE event_type = 'sso_provisioned')
+  AND id NOT IN (
+      SELECT user_id FROM security_events WHERE event_type IN ('password_changed', 'password_reset')
+  );
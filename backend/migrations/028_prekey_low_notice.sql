@@ -0,0 +1,3 @@
+-- Lowest one-time prekey stock a device was warned about since it last uploaded some (NULL: not warned),
+-- so that concurrent bundle fetches below the threshold only warn it once
+ALTER TABLE device_keys ADD COLUMN IF NOT EXISTS low_prekeys_notified INTEGER;
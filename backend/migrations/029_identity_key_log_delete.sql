@@ -0,0 +1,15 @@
+-- The identity key log was only protected against UPDATE: also reject DELETE, cascades from users included,
+-- unless the transaction erases an account (AccountDeletionService::erase sets kisse.erasing_account)
+CREATE OR REPLACE FUNCTION identity_key_log_append_only() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' AND current_setting('kisse.erasing_account', true) = 'on' THEN
+        RETURN OLD;
+    END IF;
+    RAISE EXCEPTION 'identity_key_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS identity_key_log_append_only ON identity_key_log;
+CREATE TRIGGER identity_key_log_append_only
+    BEFORE UPDATE OR DELETE ON identity_key_log
+    FOR EACH ROW EXECUTE FUNCTION identity_key_log_append_only();
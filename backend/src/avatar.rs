@@ -0,0 +1,107 @@
+use anyhow::Context;
+use image::{imageops::FilterType, DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Square thumbnails generated for every avatar, in pixels, the largest is the default
+pub const SIZES: [u32; 3] = [64, 256, 512];
+
+/// Larger images are refused before being decoded
+const MAX_DIMENSION: u32 = 4096;
+
+/// Memory the decoder may allocate, a `MAX_DIMENSION` square in 8-bit RGBA
+const MAX_DECODE_BYTES: u64 = 4096 * 4096 * 4;
+
+/// URL at which the backend serves an avatar, `?size=` picks a thumbnail
+pub fn url(avatar_id: Uuid) -> String {
+    format!("/api/avatars/{}", avatar_id)
+}
+
+pub fn path(dir: &str, avatar_id: Uuid, size: u32) -> PathBuf {
+    Path::new(dir)
+        .join(avatar_id.to_string())
+        .join(format!("{}.png", size))
+}
+
+/// Decode an uploaded PNG, JPEG or WebP image and render its thumbnails as PNG
+/// 
+/// The image is turned upright according to its EXIF orientation and cropped to a centered square.
+pub fn thumbnails(data: &[u8]) -> anyhow::Result<Vec<(u32, Vec<u8>)>> {
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .context("Failed to read image")?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_BYTES);
+    reader.limits(limits);
+    
+    let mut decoder = reader.into_decoder().context("Unsupported image format")?;
+    let orientation = decoder.orientation().context("Invalid image orientation")?;
+    let mut image = DynamicImage::from_decoder(decoder).context("Invalid image")?;
+    image.apply_orientation(orientation);
+    
+    SIZES
+        .iter()
+        .map(|&size| {
+            let mut png = Vec::new();
+            image
+                .resize_to_fill(size, size, FilterType::Lanczos3)
+                .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+                .context("Failed to encode thumbnail")?;
+            Ok((size, png))
+        })
+        .collect()
+}
+
+pub async fn save(dir: &str, avatar_id: Uuid, thumbnails: &[(u32, Vec<u8>)]) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(Path::new(dir).join(avatar_id.to_string()))
+        .await
+        .context("Failed to create avatar directory")?;
+    for (size, png) in thumbnails {
+        tokio::fs::write(path(dir, avatar_id, *size), png)
+            .await
+            .context("Failed to write avatar")?;
+    }
+    
+    Ok(())
+}
+
+/// Delete the files of a replaced or removed avatar
+pub async fn remove(dir: &str, avatar_id: Uuid) {
+    let avatar_dir = Path::new(dir).join(avatar_id.to_string());
+    if let Err(e) = tokio::fs::remove_dir_all(&avatar_dir).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            tracing::warn!("Failed to remove avatar {}: {}", avatar_dir.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_thumbnails_are_square_pngs() {
+        let mut source = Vec::new();
+        DynamicImage::new_rgb8(800, 300)
+            .write_to(&mut Cursor::new(&mut source), ImageFormat::Jpeg)
+            .unwrap();
+        
+        let thumbnails = thumbnails(&source).unwrap();
+        assert_eq!(thumbnails.iter().map(|(size, _)| *size).collect::<Vec<_>>(), SIZES);
+        for (size, png) in thumbnails {
+            let thumbnail = image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap();
+            assert_eq!((thumbnail.width(), thumbnail.height()), (size, size));
+        }
+        
+        assert!(super::thumbnails(b"not an image").is_err());
+        
+        let mut oversized = Vec::new();
+        DynamicImage::new_luma8(MAX_DIMENSION + 1, 1)
+            .write_to(&mut Cursor::new(&mut oversized), ImageFormat::Png)
+            .unwrap();
+        assert!(super::thumbnails(&oversized).is_err());
+    }
+}
@@ -21,7 +21,7 @@ pub async fn start_background_tasks(state: Arc<AppState>) {
         }
     });
     
-    // Tâche 2: Nettoyage contenu expiré (toutes les heures)
+    // Tâche 2: Nettoyage contenu expiré, événements en attente et messages sealed sender expirés (toutes les heures)
     let db_clone = db.clone();
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(Duration::from_secs(3600));
@@ -30,6 +30,12 @@ pub async fn start_background_tasks(state: Arc<AppState>) {
             if let Err(e) = cleanup_expired_content(db_clone.pool()).await {
                 tracing::error!("Erreur lors du nettoyage du contenu expiré: {}", e);
             }
+            if let Err(e) = cleanup_expired_pending_events(db_clone.pool()).await {
+                tracing::error!("Erreur lors du nettoyage des événements en attente: {}", e);
+            }
+            if let Err(e) = cleanup_expired_sealed_messages(db_clone.pool()).await {
+                tracing::error!("Erreur lors du nettoyage des messages sealed sender: {}", e);
+            }
         }
     });
     
@@ -55,6 +61,110 @@ pub async fn start_background_tasks(state: Arc<AppState>) {
         }
     });
     
+    // Tâche 4: Nettoyage des sessions, échecs de connexion et jetons expirés (toutes les heures)
+    let db_clone = db.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            match SessionService::cleanup_expired(db_clone.pool()).await {
+                Ok(deleted) if deleted > 0 => {
+                    tracing::info!("🧹 {} sessions expirées supprimées", deleted);
+                }
+                Ok(_) => {}
+                Err(e) => tracing::error!("Erreur lors du nettoyage des sessions: {}", e),
+            }
+            if let Err(e) = LoginProtectionService::cleanup(db_clone.pool()).await {
+                tracing::error!("Erreur lors du nettoyage des échecs de connexion: {}", e);
+            }
+            if let Err(e) = UserTokenService::cleanup_expired(db_clone.pool()).await {
+                tracing::error!("Erreur lors du nettoyage des jetons: {}", e);
+            }
+            if let Err(e) = OidcService::cleanup_expired_states(db_clone.pool()).await {
+                tracing::error!("Erreur lors du nettoyage des connexions SSO: {}", e);
+            }
+        }
+    });
+    
+    // Tâche 5: Expiration des indicateurs de frappe sans nouvelles du client (toutes les 2 secondes)
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(2));
+        loop {
+            interval.tick().await;
+            crate::websocket::expire_typing_indicators().await;
+        }
+    });
+    
+    // Tâche 6: Nettoyage des compteurs de rate limiting inactifs (toutes les 5 minutes)
+    let state_clone = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(300));
+        loop {
+            interval.tick().await;
+            if let Err(e) = state_clone.rate_limiter.cleanup().await {
+                tracing::error!("Erreur lors du nettoyage du rate limiting: {}", e);
+            }
+        }
+    });
+    
+    // Tâche 7: Effacement des comptes dont le délai de grâce est écoulé (toutes les heures)
+    let state_clone = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            let user_ids = match AccountDeletionService::get_due_accounts(state_clone.db.pool()).await {
+                Ok(user_ids) => user_ids,
+                Err(e) => {
+                    tracing::error!("Erreur lors de la recherche des comptes à supprimer: {}", e);
+                    continue;
+                }
+            };
+            for user_id in user_ids {
+                if let Err(e) = DataExportService::delete_for_user(state_clone.db.pool(), user_id).await {
+                    tracing::error!("Erreur lors de la suppression des exports du compte {}: {}", user_id, e);
+                    continue;
+                }
+                let avatar_id = match UserService::find_by_id(state_clone.db.pool(), user_id).await {
+                    Ok(user) => user.and_then(|user| user.avatar_id),
+                    Err(e) => {
+                        tracing::error!("Erreur lors de la lecture du compte {}: {}", user_id, e);
+                        continue;
+                    }
+                };
+                match AccountDeletionService::erase(state_clone.db.pool(), user_id).await {
+                    Ok(contacts) => {
+                        tracing::info!("🗑️ Compte {} supprimé", user_id);
+                        if let Some(avatar_id) = avatar_id {
+                            crate::avatar::remove(&state_clone.config.avatar_dir, avatar_id).await;
+                        }
+                        crate::websocket::notify_account_deleted(&state_clone, user_id, &contacts).await;
+                    }
+                    Err(e) => tracing::error!("Erreur lors de la suppression du compte {}: {:?}", user_id, e),
+                }
+            }
+        }
+    });
+    
+    // Tâche 8: Construction des exports de données demandés et suppression des exports expirés (toutes les 30 secondes)
+    let state_clone = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(30));
+        loop {
+            interval.tick().await;
+            if let Err(e) = crate::export::process_pending(&state_clone).await {
+                tracing::error!("Erreur lors de la construction des exports de données: {:?}", e);
+            }
+            match DataExportService::cleanup_expired(state_clone.db.pool()).await {
+                Ok(deleted) if deleted > 0 => {
+                    tracing::info!("🧹 {} exports de données expirés supprimés", deleted);
+                }
+                Ok(_) => {}
+                Err(e) => tracing::error!("Erreur lors du nettoyage des exports de données: {}", e),
+            }
+        }
+    });
+    
     tracing::info!("✅ Tâches en arrière-plan démarrées");
 }
 
@@ -93,6 +203,28 @@ async fn cleanup_expired_content(pool: &PgPool) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Supprime les événements hors ligne jamais acquittés et expirés
+async fn cleanup_expired_pending_events(pool: &PgPool) -> anyhow::Result<()> {
+    let deleted = PendingEventService::cleanup_expired(pool).await?;
+    
+    if deleted > 0 {
+        tracing::info!("🧹 {} événements en attente expirés supprimés", deleted);
+    }
+    
+    Ok(())
+}
+
+/// Supprime les messages sealed sender jamais acquittés par leur appareil et expirés
+async fn cleanup_expired_sealed_messages(pool: &PgPool) -> anyhow::Result<()> {
+    let deleted = SealedMessageService::cleanup_expired(pool).await?;
+    
+    if deleted > 0 {
+        tracing::info!("🧹 {} messages sealed sender expirés supprimés", deleted);
+    }
+    
+    Ok(())
+}
+
 /// Met à jour automatiquement last_seen pour les utilisateurs en ligne
 async fn update_online_users_last_seen(pool: &PgPool) -> anyhow::Result<()> {
     let updated = sqlx::query(
@@ -0,0 +1,128 @@
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::request::Parts,
+};
+use ipnet::IpNet;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use crate::AppState;
+
+/// Network information about the client that sent the request
+///
+/// The IP address is the TCP peer address, unless the peer is one of the
+/// configured trusted proxies (`TRUSTED_PROXIES`): then it is taken from the
+/// headers set by Nginx (`X-Real-IP`, `X-Forwarded-For`), which anyone
+/// reaching the backend directly could forge.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientInfo
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(ClientInfo::from_parts(parts))
+    }
+}
+
+impl ClientInfo {
+    pub fn from_parts(parts: &Parts) -> Self {
+        let header = |name: &str| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+        };
+
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let trusted_proxies = parts
+            .extensions
+            .get::<Arc<AppState>>()
+            .map(|state| state.config.trusted_proxies.as_slice())
+            .unwrap_or_default();
+
+        let ip = client_ip(
+            peer,
+            header("X-Real-IP").as_deref(),
+            header("X-Forwarded-For").as_deref(),
+            trusted_proxies,
+        )
+        .map(|ip| ip.to_string());
+
+        // User agents can be arbitrarily long, keep what fits in a sane column
+        let user_agent = header("User-Agent").map(|ua| ua.chars().take(512).collect());
+
+        ClientInfo { ip, user_agent }
+    }
+}
+
+/// Resolve the client address behind the chain of trusted proxies
+///
+/// `X-Forwarded-For` is read from the right, each proxy appending the
+/// address it received the request from: the first untrusted hop is the
+/// client, anything left of it may have been written by the client itself.
+fn client_ip(
+    peer: Option<IpAddr>,
+    real_ip: Option<&str>,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpNet],
+) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+    let peer = peer?;
+    if !is_trusted(&peer) {
+        return Some(peer);
+    }
+
+    real_ip
+        .and_then(|ip| ip.parse().ok())
+        .or_else(|| {
+            forwarded_for?
+                .rsplit(',')
+                .map_while(|hop| hop.trim().parse::<IpAddr>().ok())
+                .find(|hop| !is_trusted(hop))
+        })
+        .or(Some(peer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forwarding_headers_need_a_trusted_peer() {
+        let proxies: Vec<IpNet> = vec!["172.16.0.0/12".parse().unwrap()];
+        let proxy: IpAddr = "172.18.0.5".parse().unwrap();
+        let direct: IpAddr = "203.0.113.7".parse().unwrap();
+
+        // Reached directly, forged headers are ignored
+        assert_eq!(client_ip(Some(direct), Some("1.2.3.4"), Some("1.2.3.4"), &proxies), Some(direct));
+        assert_eq!(client_ip(Some(direct), Some("1.2.3.4"), None, &[]), Some(direct));
+
+        // Behind the proxy, its headers are used
+        assert_eq!(
+            client_ip(Some(proxy), Some("198.51.100.1"), None, &proxies),
+            Some("198.51.100.1".parse().unwrap())
+        );
+        // The client may prepend anything to X-Forwarded-For, only the hop added by the proxy counts
+        assert_eq!(
+            client_ip(Some(proxy), None, Some("1.2.3.4, 198.51.100.1"), &proxies),
+            Some("198.51.100.1".parse().unwrap())
+        );
+        assert_eq!(client_ip(Some(proxy), None, None, &proxies), Some(proxy));
+    }
+}
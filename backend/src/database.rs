@@ -1,5 +1,4 @@
 use sqlx::{postgres::PgPoolOptions, PgPool};
-use tracing;
 
 pub struct Database {
     pool: PgPool,
@@ -0,0 +1,355 @@
+use anyhow::Context;
+use futures::TryStreamExt;
+use sqlx::PgPool;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::services::{DataExportService, UserService};
+use crate::AppState;
+
+/// Read me placed at the root of every archive
+const README: &str = "Export de vos données Kisse\n\
+\n\
+profile.json            votre profil\n\
+avatar.png              votre photo de profil, si vous en avez une\n\
+conversations.json      vos conversations\n\
+messages.json           métadonnées de vos messages (le serveur ne connaît pas leur contenu)\n\
+channel_messages.json   métadonnées de vos messages dans les canaux\n\
+encrypted_content.json  contenus chiffrés des messages que vous avez envoyés\n\
+encrypted_content/      ces contenus, tels que stockés (chiffrés de bout en bout, lisibles uniquement avec les clés de vos appareils)\n\
+stories.json            vos stories\n\
+story_views.json        les stories que vous avez vues et les vues de vos stories\n\
+channels.json           les canaux dont vous êtes membre\n\
+calls.json              votre historique d'appels\n";
+
+/// JSON files of the archive: name, query returning one row per entry (`$1` is the user id)
+const SECTIONS: &[(&str, &str)] = &[
+    (
+        "conversations.json",
+        r#"
+        SELECT c.id, CASE WHEN c.user1_id = $1 THEN c.user2_id ELSE c.user1_id END AS other_user_id,
+               u.username AS other_username, u.name AS other_name,
+               c.last_message_time, c.created_at
+        FROM conversations c
+        LEFT JOIN users u ON u.id = CASE WHEN c.user1_id = $1 THEN c.user2_id ELSE c.user1_id END
+        WHERE c.user1_id = $1 OR c.user2_id = $1
+        ORDER BY c.created_at
+        "#,
+    ),
+    (
+        "messages.json",
+        r#"
+        SELECT id, conversation_id, sender_id, recipient_id, message_type, timestamp,
+               session_id, client_message_id, delivered_at, is_read, read_at
+        FROM messages
+        WHERE sender_id = $1 OR recipient_id = $1
+        ORDER BY timestamp
+        "#,
+    ),
+    (
+        "channel_messages.json",
+        r#"
+        SELECT id, channel_id, message_type, timestamp, session_id
+        FROM channel_messages
+        WHERE sender_id = $1
+        ORDER BY timestamp
+        "#,
+    ),
+    (
+        "encrypted_content.json",
+        r#"
+        SELECT ec.message_id, ec.device_id,
+               'encrypted_content/' || ec.message_id || COALESCE('-' || ec.device_id, '') || '.bin' AS file,
+               ec.content_hash, octet_length(ec.content_data) AS size_bytes, ec.created_at, ec.expires_at
+        FROM encrypted_content ec
+        INNER JOIN messages m ON m.id = ec.message_id
+        WHERE m.sender_id = $1
+        ORDER BY ec.created_at
+        "#,
+    ),
+    (
+        "stories.json",
+        r#"
+        SELECT id, content_text, media_url, media_type, created_at, expires_at, views_count
+        FROM stories
+        WHERE user_id = $1
+        ORDER BY created_at
+        "#,
+    ),
+    (
+        "story_views.json",
+        r#"
+        SELECT sv.story_id, s.user_id AS story_owner_id, sv.viewer_id, sv.viewed_at
+        FROM story_views sv
+        INNER JOIN stories s ON s.id = sv.story_id
+        WHERE sv.viewer_id = $1 OR s.user_id = $1
+        ORDER BY sv.viewed_at
+        "#,
+    ),
+    (
+        "channels.json",
+        r#"
+        SELECT c.id, c.name, c.description, c.is_private, m.role, m.joined_at,
+               c.creator_id = $1 AS is_owner
+        FROM channel_members m
+        INNER JOIN channels c ON c.id = m.channel_id
+        WHERE m.user_id = $1
+        ORDER BY m.joined_at
+        "#,
+    ),
+    (
+        "calls.json",
+        r#"
+        SELECT call_id, caller_id, recipient_id, call_type, status, started_at, ended_at,
+               duration_seconds, created_at
+        FROM calls
+        WHERE caller_id = $1 OR recipient_id = $1
+        ORDER BY created_at
+        "#,
+    ),
+];
+
+/// Build the export archive of a user at `path` and return its size in bytes
+/// 
+/// The archive is a zip of JSON files plus the encrypted content of the messages the user
+/// sent, as opaque `.bin` files. The password hash, MFA secrets and tokens are never exported.
+pub async fn build_archive(
+    pool: &PgPool,
+    user_id: Uuid,
+    avatar_dir: &str,
+    path: &Path,
+) -> anyhow::Result<u64> {
+    // Files go to the writer as they are read, only a few are held in memory at once
+    let (tx, mut rx) = mpsc::channel(4);
+    let path = path.to_path_buf();
+    let writer = tokio::task::spawn_blocking(move || write_zip(&path, std::iter::from_fn(|| rx.blocking_recv())));
+    
+    if let Err(e) = read_files(pool, user_id, avatar_dir, &tx).await {
+        // The writer then drops the partial archive, unless it already stopped on its own error
+        let _ = tx.send(Err(e)).await;
+    }
+    drop(tx);
+    
+    writer.await.context("Archive writer panicked")?
+}
+
+/// Read every file of the archive and hand it to the writer
+async fn read_files(
+    pool: &PgPool,
+    user_id: Uuid,
+    avatar_dir: &str,
+    tx: &mpsc::Sender<anyhow::Result<(String, Vec<u8>)>>,
+) -> anyhow::Result<()> {
+    let send = |name: String, data: Vec<u8>| async move {
+        tx.send(Ok((name, data)))
+            .await
+            .map_err(|_| anyhow::anyhow!("Archive writer stopped"))
+    };
+    
+    send("README.txt".to_string(), README.as_bytes().to_vec()).await?;
+    
+    let profile = sqlx::query_scalar::<_, String>(
+        r#"
+        SELECT row_to_json(t)::text FROM (
+            SELECT id, email, username, name, avatar_url, last_seen_visibility,
+                   email_verified_at, deletion_scheduled_at, created_at, updated_at
+            FROM users WHERE id = $1
+        ) t
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await
+    .context("Failed to export profile")?;
+    send("profile.json".to_string(), pretty(&profile)?).await?;
+    
+    let avatar_id = sqlx::query_scalar::<_, Option<Uuid>>("SELECT avatar_id FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+        .context("Failed to export avatar")?;
+    if let Some(avatar_id) = avatar_id {
+        let size = crate::avatar::SIZES[crate::avatar::SIZES.len() - 1];
+        let png = tokio::fs::read(crate::avatar::path(avatar_dir, avatar_id, size))
+            .await
+            .context("Failed to read avatar")?;
+        send("avatar.png".to_string(), png).await?;
+    }
+    
+    for (name, query) in SECTIONS {
+        let rows = sqlx::query_scalar::<_, String>(&format!(
+            "SELECT COALESCE(json_agg(t), '[]'::json)::text FROM ({}) t",
+            query
+        ))
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+        .with_context(|| format!("Failed to export {}", name))?;
+        send(name.to_string(), pretty(&rows)?).await?;
+    }
+    
+    let mut blobs = sqlx::query_as::<_, (Uuid, Option<Uuid>, Vec<u8>)>(
+        r#"
+        SELECT ec.message_id, ec.device_id, ec.content_data
+        FROM encrypted_content ec
+        INNER JOIN messages m ON m.id = ec.message_id
+        WHERE m.sender_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .fetch(pool);
+    while let Some((message_id, device_id, data)) = blobs
+        .try_next()
+        .await
+        .context("Failed to export encrypted content")?
+    {
+        // One file per destination device, named like the `file` column of encrypted_content.json
+        let name = match device_id {
+            Some(device_id) => format!("encrypted_content/{}-{}.bin", message_id, device_id),
+            None => format!("encrypted_content/{}.bin", message_id),
+        };
+        send(name, data).await?;
+    }
+    
+    Ok(())
+}
+
+/// Build every pending export, then email its download link
+pub async fn process_pending(state: &AppState) -> anyhow::Result<()> {
+    let pool = state.db.pool();
+    while let Some((export_id, user_id)) = DataExportService::claim_next(pool).await? {
+        let path = Path::new(&state.config.export_dir).join(format!("{}.zip", export_id));
+        let size = match build_archive(pool, user_id, &state.config.avatar_dir, &path).await {
+            Ok(size) => size,
+            Err(e) => {
+                tracing::error!("❌ Data export {} failed: {:?}", export_id, e);
+                DataExportService::fail(pool, export_id, &e.to_string()).await?;
+                continue;
+            }
+        };
+        
+        let expires_at = chrono::Utc::now() + chrono::Duration::hours(state.config.export_expiration_hours);
+        let token = DataExportService::complete(
+            pool,
+            export_id,
+            &path.to_string_lossy(),
+            size,
+            expires_at,
+        )
+        .await?;
+        tracing::info!("📦 Data export {} ready ({} bytes)", export_id, size);
+        
+        if let Some(user) = UserService::find_by_id(pool, user_id).await? {
+            if let Err(e) = state.mailer.send_data_export_ready(&user.email, &token, expires_at).await {
+                tracing::error!("Failed to send data export email: {:?}", e);
+            }
+        }
+    }
+    
+    Ok(())
+}
+
+fn pretty(json: &str) -> anyhow::Result<Vec<u8>> {
+    let value: serde_json::Value = serde_json::from_str(json).context("Invalid export JSON")?;
+    serde_json::to_vec_pretty(&value).context("Failed to serialize export")
+}
+
+/// Write the archive next to its destination first, so a download never sees a partial file
+/// 
+/// The first error among `files` aborts the archive, nothing is left on disk then.
+fn write_zip(
+    path: &Path,
+    files: impl IntoIterator<Item = anyhow::Result<(String, Vec<u8>)>>,
+) -> anyhow::Result<u64> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).context("Failed to create export directory")?;
+    }
+    let partial = PathBuf::from(format!("{}.part", path.display()));
+    
+    let written = write_entries(&partial, files)
+        .and_then(|()| std::fs::rename(&partial, path).context("Failed to move export archive"));
+    if let Err(e) = written {
+        if let Err(remove_error) = std::fs::remove_file(&partial) {
+            if remove_error.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!("Failed to remove {}: {}", partial.display(), remove_error);
+            }
+        }
+        return Err(e);
+    }
+    Ok(std::fs::metadata(path)?.len())
+}
+
+fn write_entries(
+    partial: &Path,
+    files: impl IntoIterator<Item = anyhow::Result<(String, Vec<u8>)>>,
+) -> anyhow::Result<()> {
+    use zip::write::SimpleFileOptions;
+    use zip::CompressionMethod;
+    
+    let file = std::fs::File::create(partial).context("Failed to create export archive")?;
+    let mut zip = zip::ZipWriter::new(file);
+    for entry in files {
+        let (name, data) = entry?;
+        // Encrypted content doesn't compress
+        let method = if name.ends_with(".bin") {
+            CompressionMethod::Stored
+        } else {
+            CompressionMethod::Deflated
+        };
+        zip.start_file(name, SimpleFileOptions::default().compression_method(method))
+            .context("Failed to add file to export archive")?;
+        zip.write_all(&data).context("Failed to write export archive")?;
+    }
+    zip.finish().context("Failed to finish export archive")?;
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    
+    #[test]
+    fn test_archive_keeps_json_and_binary_files() {
+        let dir = std::env::temp_dir().join(format!("kisse-export-{}", Uuid::new_v4()));
+        let path = dir.join("export.zip");
+        let blob = vec![0u8, 159, 146, 150, 255];
+        
+        let size = write_zip(
+            &path,
+            vec![
+                Ok(("profile.json".to_string(), pretty(r#"{"id":"a"}"#).unwrap())),
+                Ok(("encrypted_content/m.bin".to_string(), blob.clone())),
+            ],
+        )
+        .unwrap();
+        assert_eq!(size, std::fs::metadata(&path).unwrap().len());
+        assert!(!dir.join("export.zip.part").exists());
+        
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let mut data = Vec::new();
+        archive.by_name("encrypted_content/m.bin").unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, blob);
+        let mut profile = String::new();
+        archive.by_name("profile.json").unwrap().read_to_string(&mut profile).unwrap();
+        assert!(profile.contains("\"id\": \"a\""));
+        
+        // A failure while reading leaves neither the archive nor its partial file
+        let failed = dir.join("failed.zip");
+        assert!(write_zip(
+            &failed,
+            vec![
+                Ok(("profile.json".to_string(), pretty(r#"{"id":"a"}"#).unwrap())),
+                Err(anyhow::anyhow!("database went away")),
+            ],
+        )
+        .is_err());
+        assert!(!failed.exists());
+        assert!(!dir.join("failed.zip.part").exists());
+        
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,20 +1,27 @@
+use crate::client_info::ClientInfo;
 use crate::models::*;
 use crate::services::*;
 use crate::AppState;
 use axum::{
     extract::{Extension, Path, Query},
     http::StatusCode,
-    response::Json,
+    response::{IntoResponse, Json, Response},
 };
 use serde::Deserialize;
 use uuid::Uuid;
-use chrono::{DateTime, Utc};
+use chrono::Utc;
 use validator::Validate;
 
 pub async fn register(
     Extension(state): Extension<std::sync::Arc<AppState>>,
+    client: ClientInfo,
     Json(payload): Json<RegisterRequest>,
 ) -> Result<Json<AuthResponse>, StatusCode> {
+    // Accounts come from the identity provider when password login is off
+    if !state.config.password_login_enabled {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    
     // Validate request
     if let Err(validation_errors) = payload.validate() {
         tracing::warn!("Validation error: {:?}", validation_errors);
@@ -46,6 +53,7 @@ pub async fn register(
         &payload.username,
         &payload.password,
         payload.name.as_deref(),
+        &state.config.password_hashing,
     )
     .await
     .map_err(|e| {
@@ -58,67 +66,1379 @@ pub async fn register(
         }
     })?;
     
-    // Generate token
-    let token = AuthService::generate_token(
+    // Register the device, open a session and generate tokens
+    let device = DeviceService::register_device(
+        state.db.pool(),
         user.id,
-        &state.config.jwt_secret,
-        state.config.jwt_expiration,
+        None,
+        payload.device_name.as_deref(),
+        payload.platform.as_deref(),
+        client.ip.as_deref(),
+        client.user_agent.as_deref(),
     )
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to register device: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    send_email_verification(&state, user.id, &user.email).await;
+    let response = open_session(&state, user, device.id).await?;
     
-    Ok(Json(AuthResponse {
-        token,
-        user: user.into(),
-    }))
+    Ok(Json(response))
+}
+
+const EMAIL_VERIFICATION_EXPIRATION_HOURS: i64 = 24;
+const EMAIL_VERIFICATION_RESEND_COOLDOWN: i64 = 60;
+
+async fn send_email_verification(state: &AppState, user_id: Uuid, email: &str) {
+    let sent = async {
+        let token = UserTokenService::issue(
+            state.db.pool(),
+            user_id,
+            UserTokenService::VERIFY_EMAIL,
+            chrono::Duration::hours(EMAIL_VERIFICATION_EXPIRATION_HOURS),
+        )
+        .await?;
+        state.mailer.send_email_verification(email, &token).await
+    };
+    if let Err(e) = sent.await {
+        tracing::error!("Failed to send verification email: {:?}", e);
+    }
+}
+
+/// Confirm the email address with the token received by email
+pub async fn verify_email(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    client: ClientInfo,
+    Json(payload): Json<VerifyEmailRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = UserTokenService::consume(
+        state.db.pool(),
+        UserTokenService::VERIFY_EMAIL,
+        &payload.token,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to consume verification token: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?
+    .ok_or(StatusCode::BAD_REQUEST)?;
+    
+    UserService::mark_email_verified(state.db.pool(), user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to verify email: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    record_security_event(&state, user_id, "email_verified", &client).await;
+    
+    Ok(StatusCode::OK)
+}
+
+/// Send a new verification link, at most once per cooldown
+pub async fn resend_email_verification(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<StatusCode, Response> {
+    let user = UserService::find_by_id(state.db.pool(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+        .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+    if user.is_email_verified() {
+        return Err(StatusCode::CONFLICT.into_response());
+    }
+    
+    let last_sent = UserTokenService::last_issued_at(
+        state.db.pool(),
+        user_id,
+        UserTokenService::VERIFY_EMAIL,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to get last verification email: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+    if let Some(last_sent) = last_sent {
+        let next_allowed = last_sent + chrono::Duration::seconds(EMAIL_VERIFICATION_RESEND_COOLDOWN);
+        let wait = next_allowed - Utc::now();
+        if let Ok(wait) = wait.to_std() {
+            return Err(crate::rate_limit::too_many_requests(wait));
+        }
+    }
+    
+    send_email_verification(&state, user.id, &user.email).await;
+    
+    Ok(StatusCode::ACCEPTED)
 }
 
+// Lifetime of the token between the password and the second factor, in seconds
+const MFA_TOKEN_EXPIRATION: i64 = 300;
+
 pub async fn login(
     Extension(state): Extension<std::sync::Arc<AppState>>,
+    client: ClientInfo,
     Json(payload): Json<LoginRequest>,
-) -> Result<Json<AuthResponse>, StatusCode> {
+) -> Result<Json<LoginResponse>, Response> {
+    if !state.config.password_login_enabled {
+        return Err(StatusCode::FORBIDDEN.into_response());
+    }
+    
     // Validate request
     if let Err(validation_errors) = payload.validate() {
         tracing::warn!("Validation error: {:?}", validation_errors);
-        return Err(StatusCode::BAD_REQUEST);
+        return Err(StatusCode::BAD_REQUEST.into_response());
     }
     
-    // Find user
+    // Repeated failures must wait or are locked out, before the password is even checked
+    check_login_throttle(&state, &payload.email, &client).await?;
+    
+    // Find user and verify password
     let user = UserService::find_by_email(state.db.pool(), &payload.email)
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-        .ok_or(StatusCode::UNAUTHORIZED)?;
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    let password_ok = match &user {
+        Some(user) => AuthService::verify_password(&payload.password, &user.password_hash)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?,
+        None => {
+            AuthService::verify_dummy_password(&payload.password, &state.config.password_hashing);
+            false
+        }
+    };
     
-    // Verify password
-    if !AuthService::verify_password(&payload.password, &user.password_hash)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-    {
-        return Err(StatusCode::UNAUTHORIZED);
+    let user = match user {
+        Some(user) if password_ok => user,
+        user => {
+            record_failed_login(&state, &payload.email, user.as_ref(), &client).await;
+            return Err(StatusCode::UNAUTHORIZED.into_response());
+        }
+    };
+    
+    // The password is only known now: upgrade bcrypt or outdated Argon2 hashes
+    if AuthService::needs_rehash(&user.password_hash, &state.config.password_hashing) {
+        if let Err(e) = UserService::update_password(
+            state.db.pool(),
+            user.id,
+            &payload.password,
+            &state.config.password_hashing,
+        )
+        .await
+        {
+            tracing::warn!("Failed to rehash password of {}: {:?}", user.id, e);
+        }
     }
     
-    // Generate token
-    let token = AuthService::generate_token(
+    // With two-factor authentication the login is completed by /auth/mfa/verify,
+    // failures are only forgotten once both factors succeeded
+    let mfa_enabled = MfaService::is_enabled(state.db.pool(), user.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to check MFA: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+    if mfa_enabled {
+        let mfa_token = AuthService::generate_mfa_token(
+            user.id,
+            &state.jwt_keys,
+            MFA_TOKEN_EXPIRATION,
+        )
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+        
+        return Ok(Json(LoginResponse::MfaRequired(MfaChallengeResponse {
+            mfa_required: true,
+            mfa_token,
+            expires_in: MFA_TOKEN_EXPIRATION,
+        })));
+    }
+    
+    let response = complete_login(
+        &state,
+        user,
+        payload.device_id,
+        payload.device_name.as_deref(),
+        payload.platform.as_deref(),
+        &client,
+    )
+    .await
+    .map_err(IntoResponse::into_response)?;
+    
+    Ok(Json(LoginResponse::Authenticated(response)))
+}
+
+/// Second login step for accounts with two-factor authentication
+pub async fn verify_mfa(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    client: ClientInfo,
+    Json(payload): Json<MfaVerifyRequest>,
+) -> Result<Json<AuthResponse>, Response> {
+    if payload.validate().is_err() {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    }
+    
+    let user_id = AuthService::verify_mfa_token(&payload.mfa_token, &state.jwt_keys)
+        .map_err(|_| StatusCode::UNAUTHORIZED.into_response())?;
+    let user = UserService::find_by_id(state.db.pool(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+        .ok_or_else(|| StatusCode::UNAUTHORIZED.into_response())?;
+    
+    // Codes are guessed against the same failure counters as passwords
+    check_login_throttle(&state, &user.email, &client).await?;
+    
+    let valid = MfaService::verify_code(state.db.pool(), &user, &payload.code)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to verify MFA code: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+    if !valid {
+        record_failed_login(&state, &user.email, Some(&user), &client).await;
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    }
+    
+    let response = complete_login(
+        &state,
+        user,
+        payload.device_id,
+        payload.device_name.as_deref(),
+        payload.platform.as_deref(),
+        &client,
+    )
+    .await
+    .map_err(IntoResponse::into_response)?;
+    
+    Ok(Json(response))
+}
+
+/// Reject the attempt with 429 while the account or IP must wait
+async fn check_login_throttle(state: &AppState, email: &str, client: &ClientInfo) -> Result<(), Response> {
+    let wait = LoginProtectionService::check(state.db.pool(), email, client.ip.as_deref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to check login throttle: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+    
+    match wait {
+        Some(wait) => Err(crate::rate_limit::too_many_requests(
+            wait.to_std().unwrap_or_default(),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Check a secret re-entered by a signed-in user to confirm a sensitive action
+/// 
+/// Guesses go through the same throttle and failure counters as logins: a stolen session
+/// must not be a faster way than the login form to find the password or the second factor.
+async fn check_reauthentication(
+    state: &AppState,
+    user: &User,
+    client: &ClientInfo,
+    check: impl std::future::Future<Output = anyhow::Result<bool>>,
+) -> Result<(), Response> {
+    check_login_throttle(state, &user.email, client).await?;
+    
+    let valid = check.await.map_err(|e| {
+        tracing::error!("Failed to check credentials: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+    if !valid {
+        record_failed_login(state, &user.email, Some(user), client).await;
+        return Err(StatusCode::FORBIDDEN.into_response());
+    }
+    Ok(())
+}
+
+/// Register the device and open a session once every factor was checked
+async fn complete_login(
+    state: &AppState,
+    user: User,
+    device_id: Option<Uuid>,
+    device_name: Option<&str>,
+    platform: Option<&str>,
+    client: &ClientInfo,
+) -> Result<AuthResponse, StatusCode> {
+    if let Err(e) = LoginProtectionService::reset_account(state.db.pool(), &user.email).await {
+        tracing::warn!("Failed to reset login throttle: {:?}", e);
+    }
+    record_security_event(state, user.id, "login_succeeded", client).await;
+    
+    let device = DeviceService::register_device(
+        state.db.pool(),
         user.id,
-        &state.config.jwt_secret,
-        state.config.jwt_expiration,
+        device_id,
+        device_name,
+        platform,
+        client.ip.as_deref(),
+        client.user_agent.as_deref(),
     )
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to register device: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
     
-    Ok(Json(AuthResponse {
-        token,
-        user: user.into(),
+    open_session(state, user, device.id).await
+}
+
+// Lifetime of a pending single sign-on, in seconds
+const OIDC_LOGIN_EXPIRATION: i64 = 600;
+
+/// Start a single sign-on: the client opens `authorization_url` in a browser
+pub async fn oidc_authorize(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+) -> Result<Json<OidcAuthorizeResponse>, StatusCode> {
+    let oidc = state.oidc.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    
+    let request = oidc.authorization_request().await.map_err(|e| {
+        tracing::error!("Failed to start SSO: {:?}", e);
+        StatusCode::BAD_GATEWAY
+    })?;
+    OidcService::save_login_state(
+        state.db.pool(),
+        &request.state,
+        &request.code_verifier,
+        &request.nonce,
+        chrono::Duration::seconds(OIDC_LOGIN_EXPIRATION),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to save SSO state: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    
+    Ok(Json(OidcAuthorizeResponse {
+        authorization_url: request.url,
+        state: request.state,
     }))
 }
 
-pub async fn get_me(
+/// Finish a single sign-on with the code from the provider's redirect
+/// 
+/// The provider account is matched to a user by its link, then by verified email,
+/// and a user is provisioned when none exists (if allowed).
+pub async fn oidc_callback(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    client: ClientInfo,
+    Json(payload): Json<OidcCallbackRequest>,
+) -> Result<Json<LoginResponse>, StatusCode> {
+    let oidc = state.oidc.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let oidc_config = state.config.oidc.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    if payload.validate().is_err() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    
+    let (code_verifier, nonce) = OidcService::take_login_state(state.db.pool(), &payload.state)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to take SSO state: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    
+    let identity = oidc
+        .exchange_code(&payload.code, &code_verifier, &nonce)
+        .await
+        .map_err(|e| {
+            tracing::warn!("SSO code exchange failed: {:?}", e);
+            StatusCode::UNAUTHORIZED
+        })?;
+    
+    // Only a verified address may take over an existing account
+    let email = match &identity.email {
+        Some(email) if identity.is_email_verified() => email.clone(),
+        _ => return Err(StatusCode::FORBIDDEN),
+    };
+    
+    let linked = OidcService::find_user(state.db.pool(), &identity.iss, &identity.sub)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let user = match linked {
+        Some(user) => user,
+        None => {
+            let existing = UserService::find_by_email(state.db.pool(), &email)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let (user, event) = match existing {
+                // Whoever registered an unverified account may still hold its password, or
+                // its second factor: the address owner must verify it before linking
+                Some(user) if !user.is_email_verified() => return Err(StatusCode::CONFLICT),
+                Some(user) => (user, "sso_linked"),
+                None if oidc_config.auto_provision => {
+                    let user = UserService::create_sso_user(
+                        state.db.pool(),
+                        &email,
+                        identity.preferred_username.as_deref(),
+                        identity.name.as_deref(),
+                        &state.config.password_hashing,
+                    )
+                    .await
+                    .map_err(|e| {
+                        tracing::error!("Failed to provision SSO user: {:?}", e);
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    })?;
+                    (user, "sso_provisioned")
+                }
+                None => return Err(StatusCode::FORBIDDEN),
+            };
+            record_security_event(&state, user.id, event, &client).await;
+            user
+        }
+    };
+    
+    OidcService::link_identity(state.db.pool(), user.id, &identity.iss, &identity.sub, Some(&email))
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to link SSO identity: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if !user.is_email_verified() {
+        UserService::mark_email_verified(state.db.pool(), user.id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    
+    // The provider vouches for the identity, not for the second factor enabled here
+    let mfa_enabled = MfaService::is_enabled(state.db.pool(), user.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to check MFA: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if mfa_enabled {
+        let mfa_token = AuthService::generate_mfa_token(user.id, &state.jwt_keys, MFA_TOKEN_EXPIRATION)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        
+        return Ok(Json(LoginResponse::MfaRequired(MfaChallengeResponse {
+            mfa_required: true,
+            mfa_token,
+            expires_in: MFA_TOKEN_EXPIRATION,
+        })));
+    }
+    
+    let response = complete_login(
+        &state,
+        user,
+        payload.device_id,
+        payload.device_name.as_deref(),
+        payload.platform.as_deref(),
+        &client,
+    )
+    .await?;
+    
+    Ok(Json(LoginResponse::Authenticated(response)))
+}
+
+/// Count a failed login, and email an unlock link when it locks the account
+async fn record_failed_login(
+    state: &AppState,
+    email: &str,
+    user: Option<&User>,
+    client: &ClientInfo,
+) {
+    let locked = LoginProtectionService::record_failure(state.db.pool(), email, client.ip.as_deref())
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!("Failed to record login failure: {:?}", e);
+            false
+        });
+    
+    let Some(user) = user else {
+        return;
+    };
+    record_security_event(state, user.id, "login_failed", client).await;
+    if !locked {
+        return;
+    }
+    
+    tracing::warn!("🔒 Account {} locked after repeated login failures", user.id);
+    record_security_event(state, user.id, "account_locked", client).await;
+    let sent = async {
+        let token = UserTokenService::issue(
+            state.db.pool(),
+            user.id,
+            UserTokenService::UNLOCK_ACCOUNT,
+            chrono::Duration::hours(24),
+        )
+        .await?;
+        state.mailer.send_unlock_account(&user.email, &token).await
+    };
+    if let Err(e) = sent.await {
+        tracing::error!("Failed to send unlock email: {:?}", e);
+    }
+}
+
+async fn record_security_event(state: &AppState, user_id: Uuid, event_type: &str, client: &ClientInfo) {
+    if let Err(e) = SecurityEventService::record(
+        state.db.pool(),
+        user_id,
+        event_type,
+        client.ip.as_deref(),
+        client.user_agent.as_deref(),
+    )
+    .await
+    {
+        tracing::warn!("Failed to record security event {}: {:?}", event_type, e);
+    }
+}
+
+/// Lift a login lockout with the token received by email
+pub async fn unlock_account(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    client: ClientInfo,
+    Json(payload): Json<UnlockAccountRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = UserTokenService::consume(
+        state.db.pool(),
+        UserTokenService::UNLOCK_ACCOUNT,
+        &payload.token,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to consume unlock token: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?
+    .ok_or(StatusCode::BAD_REQUEST)?;
+    
+    let user = UserService::find_by_id(state.db.pool(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    
+    LoginProtectionService::reset_account(state.db.pool(), &user.email)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to unlock account: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    record_security_event(&state, user.id, "account_unlocked", &client).await;
+    
+    Ok(StatusCode::OK)
+}
+
+/// Change the password of the current user, signing out every other device
+pub async fn change_password(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    Extension(SessionId(session_id)): Extension<SessionId>,
+    client: ClientInfo,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> Result<StatusCode, Response> {
+    if payload.validate().is_err() {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    }
+    
+    let user = UserService::find_by_id(state.db.pool(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+        .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+    
+    check_reauthentication(&state, &user, &client, async {
+        AuthService::verify_password(&payload.current_password, &user.password_hash)
+    })
+    .await?;
+    
+    UserService::update_password(
+        state.db.pool(),
+        user_id,
+        &payload.new_password,
+        &state.config.password_hashing,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to update password: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+    revoke_sessions(&state, user_id, Some(session_id))
+        .await
+        .map_err(IntoResponse::into_response)?;
+    record_security_event(&state, user_id, "password_changed", &client).await;
+    
+    if let Err(e) = state.mailer.send_password_changed(&user.email).await {
+        tracing::error!("Failed to send password change notification: {:?}", e);
+    }
+    
+    Ok(StatusCode::OK)
+}
+
+/// Email a password reset link
+/// 
+/// Always answers 202 so the response doesn't reveal which emails have an account.
+pub async fn forgot_password(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Json(payload): Json<ForgotPasswordRequest>,
+) -> Result<StatusCode, StatusCode> {
+    if !state.config.password_login_enabled {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    
+    if payload.validate().is_err() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    
+    let user = UserService::find_by_email(state.db.pool(), &payload.email)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    
+    if let Some(user) = user {
+        let sent = async {
+            let token = UserTokenService::issue(
+                state.db.pool(),
+                user.id,
+                UserTokenService::PASSWORD_RESET,
+                chrono::Duration::hours(1),
+            )
+            .await?;
+            state.mailer.send_password_reset(&user.email, &token).await
+        };
+        if let Err(e) = sent.await {
+            tracing::error!("Failed to send password reset email: {:?}", e);
+        }
+    }
+    
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Choose a new password with the token received by email, every session is revoked
+pub async fn reset_password(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    client: ClientInfo,
+    Json(payload): Json<ResetPasswordRequest>,
+) -> Result<StatusCode, Response> {
+    if !state.config.password_login_enabled {
+        return Err(StatusCode::FORBIDDEN.into_response());
+    }
+    
+    if payload.validate().is_err() {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    }
+    
+    let user_id = UserTokenService::find_valid(
+        state.db.pool(),
+        UserTokenService::PASSWORD_RESET,
+        &payload.token,
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+    .ok_or_else(|| StatusCode::BAD_REQUEST.into_response())?;
+    let user = UserService::find_by_id(state.db.pool(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+        .ok_or_else(|| StatusCode::BAD_REQUEST.into_response())?;
+    
+    // Access to the mailbox alone must not bypass the second factor
+    if MfaService::is_enabled(state.db.pool(), user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+    {
+        let code = payload
+            .mfa_code
+            .as_deref()
+            .ok_or_else(|| StatusCode::FORBIDDEN.into_response())?;
+        check_reauthentication(
+            &state,
+            &user,
+            &client,
+            MfaService::verify_code(state.db.pool(), &user, code),
+        )
+        .await?;
+    }
+    
+    // Consuming is atomic, a token used concurrently is only accepted once
+    UserTokenService::consume(
+        state.db.pool(),
+        UserTokenService::PASSWORD_RESET,
+        &payload.token,
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+    .ok_or_else(|| StatusCode::BAD_REQUEST.into_response())?;
+    
+    UserService::update_password(
+        state.db.pool(),
+        user.id,
+        &payload.new_password,
+        &state.config.password_hashing,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to reset password: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+    revoke_sessions(&state, user.id, None)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    if let Err(e) = LoginProtectionService::reset_account(state.db.pool(), &user.email).await {
+        tracing::warn!("Failed to reset login throttle: {:?}", e);
+    }
+    record_security_event(&state, user.id, "password_reset", &client).await;
+    
+    Ok(StatusCode::OK)
+}
+
+/// Revoke the sessions of a user (but `keep`) and close their WebSockets
+async fn revoke_sessions(state: &AppState, user_id: Uuid, keep: Option<Uuid>) -> Result<(), StatusCode> {
+    let revoked = SessionService::revoke_other_sessions(state.db.pool(), user_id, keep)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to revoke sessions: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    for session_id in revoked {
+        crate::websocket::disconnect_session(session_id).await;
+    }
+    
+    Ok(())
+}
+
+pub async fn get_mfa_status(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<MfaStatusResponse>, StatusCode> {
+    let enabled = MfaService::is_enabled(state.db.pool(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let recovery_codes_remaining = MfaService::recovery_codes_remaining(state.db.pool(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    
+    Ok(Json(MfaStatusResponse {
+        enabled,
+        recovery_codes_remaining,
+    }))
+}
+
+/// Start TOTP enrollment: the secret must be added to an authenticator app then confirmed
+pub async fn setup_totp(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<TotpSetupResponse>, StatusCode> {
+    let user = UserService::find_by_id(state.db.pool(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    
+    if MfaService::is_enabled(state.db.pool(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        return Err(StatusCode::CONFLICT);
+    }
+    
+    let setup = MfaService::start_enrollment(state.db.pool(), &user)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to start TOTP enrollment: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    
+    Ok(Json(setup))
+}
+
+pub async fn confirm_totp(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    client: ClientInfo,
+    Json(payload): Json<MfaCodeRequest>,
+) -> Result<Json<RecoveryCodesResponse>, StatusCode> {
+    let user = UserService::find_by_id(state.db.pool(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    
+    let recovery_codes = MfaService::confirm_enrollment(state.db.pool(), &user, payload.code.trim())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to confirm TOTP enrollment: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    record_security_event(&state, user_id, "mfa_enabled", &client).await;
+    
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+pub async fn disable_mfa(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    client: ClientInfo,
+    Json(payload): Json<DisableMfaRequest>,
+) -> Result<StatusCode, Response> {
+    let user = UserService::find_by_id(state.db.pool(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+        .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+    
+    // Both factors are required, a stolen session alone can't remove the second one
+    check_reauthentication(&state, &user, &client, async {
+        AuthService::verify_password(&payload.password, &user.password_hash)
+    })
+    .await?;
+    check_reauthentication(
+        &state,
+        &user,
+        &client,
+        MfaService::verify_code(state.db.pool(), &user, &payload.code),
+    )
+    .await?;
+    
+    MfaService::disable(state.db.pool(), user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to disable MFA: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+    record_security_event(&state, user_id, "mfa_disabled", &client).await;
+    
+    Ok(StatusCode::OK)
+}
+
+pub async fn regenerate_recovery_codes(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    client: ClientInfo,
+    Json(payload): Json<MfaCodeRequest>,
+) -> Result<Json<RecoveryCodesResponse>, Response> {
+    let user = UserService::find_by_id(state.db.pool(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+        .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+    
+    check_reauthentication(
+        &state,
+        &user,
+        &client,
+        MfaService::verify_code(state.db.pool(), &user, &payload.code),
+    )
+    .await?;
+    
+    let recovery_codes = MfaService::regenerate_recovery_codes(state.db.pool(), user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to regenerate recovery codes: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+    record_security_event(&state, user_id, "recovery_codes_regenerated", &client).await;
+    
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+pub async fn get_security_events(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    Query(query): Query<MessageQuery>,
+) -> Result<Json<Vec<SecurityEvent>>, StatusCode> {
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+    
+    let events = SecurityEventService::get_user_events(state.db.pool(), user_id, limit)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to get security events: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    
+    Ok(Json(events))
+}
+
+pub async fn refresh_token(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    client: ClientInfo,
+    Json(payload): Json<RefreshTokenRequest>,
+) -> Result<Json<AuthResponse>, StatusCode> {
+    let outcome = SessionService::rotate_refresh_token(
+        state.db.pool(),
+        &payload.refresh_token,
+        state.config.refresh_token_expiration,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to rotate refresh token: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    
+    let (session, refresh_token) = match outcome {
+        RefreshOutcome::Rotated { session, refresh_token } => (session, refresh_token),
+        RefreshOutcome::Reused { session_id } => {
+            tracing::warn!("⚠️ Refresh token reuse detected, session {} revoked", session_id);
+            crate::websocket::disconnect_session(session_id).await;
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        RefreshOutcome::Invalid => return Err(StatusCode::UNAUTHORIZED),
+    };
+    
+    let user = UserService::find_by_id(state.db.pool(), session.user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    
+    // Sessions opened before the device registry existed get a device on first refresh
+    let device_id = match session.device_id {
+        Some(device_id) => {
+            if let Err(e) = DeviceService::touch(
+                state.db.pool(),
+                device_id,
+                client.ip.as_deref(),
+                client.user_agent.as_deref(),
+            )
+            .await
+            {
+                tracing::warn!("Failed to update device {}: {:?}", device_id, e);
+            }
+            device_id
+        }
+        None => {
+            SessionService::revoke_session(state.db.pool(), session.id)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let device = DeviceService::register_device(
+                state.db.pool(),
+                user.id,
+                None,
+                None,
+                None,
+                client.ip.as_deref(),
+                client.user_agent.as_deref(),
+            )
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            return Ok(Json(open_session(&state, user, device.id).await?));
+        }
+    };
+    
+    let token = AuthService::generate_token(
+        &session,
+        &state.jwt_keys,
+        state.config.jwt_expiration,
+    )
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    
+    Ok(Json(AuthResponse {
+        token,
+        refresh_token,
+        expires_in: state.config.jwt_expiration,
+        device_id,
+        user: user.into(),
+    }))
+}
+
+pub async fn logout(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(SessionId(session_id)): Extension<SessionId>,
+) -> Result<StatusCode, StatusCode> {
+    SessionService::revoke_session(state.db.pool(), session_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to revoke session: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    crate::websocket::disconnect_session(session_id).await;
+    
+    Ok(StatusCode::OK)
+}
+
+/// List the active sessions (one per signed-in device) of the current user
+pub async fn get_sessions(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    Extension(SessionId(current_session_id)): Extension<SessionId>,
+) -> Result<Json<Vec<SessionResponse>>, StatusCode> {
+    let sessions = SessionService::get_user_sessions(state.db.pool(), user_id, current_session_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to get sessions: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    
+    Ok(Json(sessions))
+}
+
+/// Revoke one of the current user's sessions and close its live WebSocket
+pub async fn revoke_session(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    Path(session_id): Path<Uuid>,
+) -> Result<StatusCode, StatusCode> {
+    let revoked = SessionService::revoke_user_session(state.db.pool(), session_id, user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to revoke session: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    
+    if !revoked {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    
+    crate::websocket::disconnect_session(session_id).await;
+    
+    Ok(StatusCode::OK)
+}
+
+/// Open a new session for the user on a device and build the token pair
+async fn open_session(
+    state: &AppState,
+    user: User,
+    device_id: Uuid,
+) -> Result<AuthResponse, StatusCode> {
+    let (session, refresh_token) = SessionService::create_session(
+        state.db.pool(),
+        user.id,
+        device_id,
+        state.config.refresh_token_expiration,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to create session: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    
+    let token = AuthService::generate_token(
+        &session,
+        &state.jwt_keys,
+        state.config.jwt_expiration,
+    )
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    
+    Ok(AuthResponse {
+        token,
+        refresh_token,
+        expires_in: state.config.jwt_expiration,
+        device_id,
+        user: user.into(),
+    })
+}
+
+/// Public keys verifying the tokens issued by this backend
+pub async fn get_jwks(Extension(state): Extension<std::sync::Arc<AppState>>) -> Response {
+    (
+        [(axum::http::header::CACHE_CONTROL, "public, max-age=300")],
+        Json(state.jwt_keys.jwks().clone()),
+    )
+        .into_response()
+}
+
+// Seconds after signing in during which an account without a password may confirm its deletion
+const REAUTHENTICATION_WINDOW: i64 = 300;
+
+/// Schedule the erasure of the current account after the grace period
+pub async fn delete_account(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    Extension(SessionId(session_id)): Extension<SessionId>,
+    client: ClientInfo,
+    Json(payload): Json<DeleteAccountRequest>,
+) -> Result<(StatusCode, Json<AccountDeletionResponse>), Response> {
+    let user = UserService::find_by_id(state.db.pool(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+        .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+    
+    if user.password_set && state.config.password_login_enabled {
+        let password = payload
+            .password
+            .as_deref()
+            .ok_or_else(|| StatusCode::FORBIDDEN.into_response())?;
+        check_reauthentication(&state, &user, &client, async {
+            AuthService::verify_password(password, &user.password_hash)
+        })
+        .await?;
+    } else {
+        // Without a password to type, signing in again through SSO confirms the request
+        let signed_in_at = SessionService::get_created_at(state.db.pool(), session_id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+            .ok_or_else(|| StatusCode::UNAUTHORIZED.into_response())?;
+        if Utc::now() - signed_in_at > chrono::Duration::seconds(REAUTHENTICATION_WINDOW) {
+            return Err(StatusCode::FORBIDDEN.into_response());
+        }
+    }
+    if user.deletion_scheduled_at.is_some() {
+        return Err(StatusCode::CONFLICT.into_response());
+    }
+    
+    let deletion_scheduled_at = AccountDeletionService::schedule(
+        state.db.pool(),
+        user_id,
+        chrono::Duration::days(state.config.account_deletion_grace_days),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to schedule account deletion: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+    record_security_event(&state, user_id, "account_deletion_requested", &client).await;
+    
+    if let Err(e) = state
+        .mailer
+        .send_account_deletion_scheduled(&user.email, deletion_scheduled_at)
+        .await
+    {
+        tracing::error!("Failed to send account deletion email: {:?}", e);
+    }
+    
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(AccountDeletionResponse {
+            deletion_scheduled_at,
+        }),
+    ))
+}
+
+/// Keep the account during its grace period
+pub async fn cancel_account_deletion(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    client: ClientInfo,
+) -> Result<StatusCode, StatusCode> {
+    let cancelled = AccountDeletionService::cancel(state.db.pool(), user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to cancel account deletion: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if !cancelled {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    record_security_event(&state, user_id, "account_deletion_cancelled", &client).await;
+    
+    Ok(StatusCode::OK)
+}
+
+/// Queue an export of the user's data, the download link is sent by email once ready
+pub async fn request_data_export(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    client: ClientInfo,
+) -> Result<(StatusCode, Json<DataExport>), StatusCode> {
+    let export = DataExportService::request(state.db.pool(), user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to request data export: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::CONFLICT)?;
+    record_security_event(&state, user_id, "data_export_requested", &client).await;
+    
+    Ok((StatusCode::ACCEPTED, Json(export)))
+}
+
+pub async fn get_data_exports(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<Vec<DataExport>>, StatusCode> {
+    let exports = DataExportService::list_for_user(state.db.pool(), user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to list data exports: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    
+    Ok(Json(exports))
+}
+
+#[derive(Deserialize)]
+pub struct DownloadQuery {
+    token: String,
+}
+
+/// Stream an export archive, the token of the emailed link is the only credential
+pub async fn download_data_export(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Query(query): Query<DownloadQuery>,
+) -> Result<Response, StatusCode> {
+    use axum::http::header;
+    
+    let file_path = DataExportService::find_download(state.db.pool(), &query.token)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to find data export: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let file = tokio::fs::File::open(&file_path).await.map_err(|e| {
+        tracing::error!("Failed to open export archive {}: {}", file_path, e);
+        StatusCode::NOT_FOUND
+    })?;
+    let length = file
+        .metadata()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .len();
+    
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_LENGTH, length.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"kisse-export.zip\"".to_string(),
+            ),
+            (header::CACHE_CONTROL, "no-store".to_string()),
+        ],
+        axum::body::Body::from_stream(tokio_util::io::ReaderStream::new(file)),
+    )
+        .into_response())
+}
+
+pub async fn get_me(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<UserResponse>, StatusCode> {
+    let user = UserService::find_by_id(state.db.pool(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    
+    Ok(Json(user.into()))
+}
+
+/// Edit the profile, the username can only change once per cooldown
+pub async fn update_profile(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    Json(payload): Json<UpdateProfileRequest>,
+) -> Result<Json<UserResponse>, Response> {
+    if let Err(validation_errors) = payload.validate() {
+        tracing::warn!("Validation error: {:?}", validation_errors);
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    }
+    
+    let user = UserService::find_by_id(state.db.pool(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+        .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+    
+    let username = payload
+        .username
+        .as_deref()
+        .map(str::trim)
+        .filter(|username| user.username.as_deref() != Some(*username));
+    if let Some(username) = username {
+        if !UserService::is_valid_username(username) {
+            return Err(StatusCode::BAD_REQUEST.into_response());
+        }
+        if let Some(changed_at) = user.username_changed_at {
+            let next_allowed = changed_at + chrono::Duration::days(state.config.username_change_cooldown_days);
+            if let Ok(wait) = (next_allowed - Utc::now()).to_std() {
+                return Err(crate::rate_limit::too_many_requests(wait));
+            }
+        }
+    }
+    let name = payload
+        .name
+        .as_deref()
+        .map(|name| Some(name.trim()).filter(|name| !name.is_empty()));
+    
+    let user = UserService::update_profile(state.db.pool(), user_id, username, name)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to update profile: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?
+        .ok_or_else(|| StatusCode::CONFLICT.into_response())?;
+    
+    Ok(Json(user.into()))
+}
+
+/// Replace the avatar with an uploaded image (multipart field `avatar`)
+pub async fn upload_avatar(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    mut multipart: axum::extract::Multipart,
+) -> Result<Json<UserResponse>, StatusCode> {
+    let mut data = None;
+    while let Some(mut field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+        if field.name() != Some("avatar") {
+            continue;
+        }
+        // Read in chunks so an oversized upload is refused without being buffered
+        let mut bytes = Vec::new();
+        while let Some(chunk) = field.chunk().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+            if bytes.len() + chunk.len() > state.config.avatar_max_bytes {
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        data = Some(bytes);
+    }
+    let data = data.ok_or(StatusCode::BAD_REQUEST)?;
+    
+    let thumbnails = tokio::task::spawn_blocking(move || crate::avatar::thumbnails(&data))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| {
+            tracing::warn!("Rejected avatar upload: {:?}", e);
+            StatusCode::UNPROCESSABLE_ENTITY
+        })?;
+    
+    let avatar_id = Uuid::new_v4();
+    crate::avatar::save(&state.config.avatar_dir, avatar_id, &thumbnails)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to save avatar: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let previous = match UserService::set_avatar(state.db.pool(), user_id, Some(avatar_id)).await {
+        Ok(previous) => previous,
+        Err(e) => {
+            tracing::error!("Failed to set avatar: {:?}", e);
+            crate::avatar::remove(&state.config.avatar_dir, avatar_id).await;
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    if let Some(previous) = previous {
+        crate::avatar::remove(&state.config.avatar_dir, previous).await;
+    }
+    
+    get_me(Extension(state), Extension(user_id)).await
+}
+
+pub async fn delete_avatar(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<StatusCode, StatusCode> {
+    let previous = UserService::set_avatar(state.db.pool(), user_id, None)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to remove avatar: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if let Some(previous) = previous {
+        crate::avatar::remove(&state.config.avatar_dir, previous).await;
+    }
+    
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+pub struct AvatarQuery {
+    size: Option<u32>,
+}
+
+/// Serve an avatar thumbnail, avatars are public and never change once uploaded
+pub async fn get_avatar(
     Extension(state): Extension<std::sync::Arc<AppState>>,
-    Extension(user_id): Extension<Uuid>,
-) -> Result<Json<UserResponse>, StatusCode> {
-    let user = UserService::find_by_id(state.db.pool(), user_id)
+    Path(avatar_id): Path<Uuid>,
+    Query(query): Query<AvatarQuery>,
+) -> Result<Response, StatusCode> {
+    use axum::http::header;
+    
+    let size = query.size.unwrap_or(crate::avatar::SIZES[crate::avatar::SIZES.len() - 1]);
+    if !crate::avatar::SIZES.contains(&size) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let png = tokio::fs::read(crate::avatar::path(&state.config.avatar_dir, avatar_id, size))
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-        .ok_or(StatusCode::NOT_FOUND)?;
+        .map_err(|_| StatusCode::NOT_FOUND)?;
     
-    Ok(Json(user.into()))
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/png"),
+            (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+        ],
+        png,
+    )
+        .into_response())
 }
 
 pub async fn get_conversations(
@@ -148,11 +1468,12 @@ pub async fn search_users(
 ) -> Result<Json<Vec<UserResponse>>, StatusCode> {
     let search_query = query.q.unwrap_or_else(|| "".to_string()).trim().to_string();
     let limit = query.limit.unwrap_or(50).min(100); // Max 100 results
+    let include_unverified = state.config.unverified_policy.discoverable;
     
     // Always search, even if query is empty (returns all users)
     let users = if search_query.is_empty() {
         // Return all users if no search query
-        UserService::get_all_users(state.db.pool(), limit, 0, Some(user_id))
+        UserService::get_all_users(state.db.pool(), limit, 0, Some(user_id), include_unverified)
             .await
             .map_err(|e| {
                 tracing::error!("Failed to get users: {:?}", e);
@@ -160,7 +1481,13 @@ pub async fn search_users(
             })?
     } else {
         // Search users by email, username, or name
-        UserService::search_users(state.db.pool(), &search_query, limit, Some(user_id))
+        UserService::search_users(
+            state.db.pool(),
+            &search_query,
+            limit,
+            Some(user_id),
+            include_unverified,
+        )
             .await
             .map_err(|e| {
                 tracing::error!("Failed to search users: {:?}", e);
@@ -190,6 +1517,10 @@ pub async fn find_user_by_email(
         })?
         .ok_or(StatusCode::NOT_FOUND)?;
     
+    if !user.is_email_verified() && !state.config.unverified_policy.discoverable {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    
     // Don't return the current user
     if user.id == user_id {
         return Err(StatusCode::BAD_REQUEST);
@@ -245,8 +1576,9 @@ pub async fn create_conversation(
     .await
     .unwrap_or(0);
     
-    let participant_status = crate::services::PresenceService::get_presence(
+    let participant_status = crate::services::PresenceService::get_visible_presence(
         state.db.pool(),
+        user_id,
         payload.participant_id,
     )
     .await
@@ -273,7 +1605,7 @@ pub async fn create_story(
     Extension(user_id): Extension<Uuid>,
     Json(payload): Json<CreateStoryRequest>,
 ) -> Result<Json<StoryResponse>, StatusCode> {
-    if let Err(_) = payload.validate() {
+    if payload.validate().is_err() {
         return Err(StatusCode::BAD_REQUEST);
     }
     
@@ -348,7 +1680,7 @@ pub async fn create_channel(
     Extension(user_id): Extension<Uuid>,
     Json(payload): Json<CreateChannelRequest>,
 ) -> Result<Json<ChannelResponse>, StatusCode> {
-    if let Err(_) = payload.validate() {
+    if payload.validate().is_err() {
         return Err(StatusCode::BAD_REQUEST);
     }
     
@@ -411,8 +1743,10 @@ pub async fn get_channel_messages(
     Extension(state): Extension<std::sync::Arc<AppState>>,
     Extension(user_id): Extension<Uuid>,
     Path(channel_id): Path<Uuid>,
-    Query(query): Query<MessageQuery>,
-) -> Result<Json<Vec<ChannelMessageResponse>>, StatusCode> {
+    Query(query): Query<PageQuery>,
+) -> Result<Json<PaginatedResponse<ChannelMessageResponse>>, StatusCode> {
+    let page = query.into_page_request()?;
+    
     // Verify user is member of channel
     let is_member: bool = sqlx::query_scalar(
         "SELECT EXISTS(SELECT 1 FROM channel_members WHERE channel_id = $1 AND user_id = $2)",
@@ -427,9 +1761,7 @@ pub async fn get_channel_messages(
         return Err(StatusCode::FORBIDDEN);
     }
     
-    let limit = query.limit.unwrap_or(50);
-    
-    let messages = ChannelService::get_channel_messages(state.db.pool(), channel_id, limit)
+    let messages = ChannelService::get_channel_messages(state.db.pool(), channel_id, &page)
         .await
         .map_err(|e| {
             tracing::error!("Failed to get channel messages: {:?}", e);
@@ -444,24 +1776,51 @@ pub struct MessageQuery {
     limit: Option<i64>,
 }
 
+#[derive(Deserialize)]
+pub struct PageQuery {
+    limit: Option<i64>,
+    before: Option<String>,
+    after: Option<String>,
+}
+
+impl PageQuery {
+    fn into_page_request(self) -> Result<PageRequest, StatusCode> {
+        // Scrolling in both directions at once is ambiguous
+        if self.before.is_some() && self.after.is_some() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        
+        let decode = |cursor: Option<String>| match cursor {
+            Some(cursor) => Cursor::decode(&cursor).map(Some).ok_or(StatusCode::BAD_REQUEST),
+            None => Ok(None),
+        };
+        
+        Ok(PageRequest {
+            limit: self.limit.unwrap_or(50).clamp(1, 100),
+            before: decode(self.before)?,
+            after: decode(self.after)?,
+        })
+    }
+}
+
 pub async fn get_messages(
     Extension(state): Extension<std::sync::Arc<AppState>>,
     Extension(user_id): Extension<Uuid>,
     Path(conversation_id): Path<Uuid>,
-    Query(query): Query<MessageQuery>,
-) -> Result<Json<Vec<MessageResponse>>, StatusCode> {
-    let limit = query.limit.unwrap_or(50);
+    Query(query): Query<PageQuery>,
+) -> Result<Json<PaginatedResponse<MessageResponse>>, StatusCode> {
+    let page = query.into_page_request()?;
     
     let messages = MessageService::get_conversation_messages(
         state.db.pool(),
         conversation_id,
         user_id,
-        limit,
+        &page,
     )
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     
-    Ok(Json(messages.into_iter().map(Into::into).collect()))
+    Ok(Json(messages.map(Into::into)))
 }
 
 pub async fn mark_message_read(
@@ -469,10 +1828,15 @@ pub async fn mark_message_read(
     Extension(user_id): Extension<Uuid>,
     Path(message_id): Path<Uuid>,
 ) -> Result<StatusCode, StatusCode> {
-    MessageService::mark_as_read(state.db.pool(), message_id, user_id)
+    let message = MessageService::mark_as_read(state.db.pool(), message_id, user_id)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     
+    // Notify the sender unless the message was already read
+    if let Some(message) = message {
+        crate::websocket::notify_message_read(&state, &message).await;
+    }
+    
     Ok(StatusCode::OK)
 }
 
@@ -481,6 +1845,14 @@ pub async fn start_call(
     Extension(user_id): Extension<Uuid>,
     Json(payload): Json<crate::models::CallRequestPayload>,
 ) -> Result<Json<crate::models::Call>, StatusCode> {
+    if !state.config.unverified_policy.can_call
+        && !UserService::is_email_verified(state.db.pool(), user_id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    
     // Check if user already has an active call
     if let Ok(Some(_)) = crate::services::CallService::get_active_call(state.db.pool(), user_id).await {
         return Err(StatusCode::CONFLICT);
@@ -501,14 +1873,14 @@ pub async fn start_call(
 pub async fn get_call_history(
     Extension(state): Extension<std::sync::Arc<AppState>>,
     Extension(user_id): Extension<Uuid>,
-    Query(query): Query<MessageQuery>,
-) -> Result<Json<Vec<crate::models::CallHistoryResponse>>, StatusCode> {
-    let limit = query.limit.unwrap_or(50);
+    Query(query): Query<PageQuery>,
+) -> Result<Json<PaginatedResponse<crate::models::CallHistoryResponse>>, StatusCode> {
+    let page = query.into_page_request()?;
     
     let calls = crate::services::CallService::get_user_call_history(
         state.db.pool(),
         user_id,
-        limit,
+        &page,
     )
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
@@ -555,11 +1927,13 @@ pub async fn update_presence(
 
 pub async fn get_presence(
     Extension(state): Extension<std::sync::Arc<AppState>>,
-    Extension(_user_id): Extension<Uuid>,
+    Extension(user_id): Extension<Uuid>,
     Path(target_user_id): Path<Uuid>,
 ) -> Result<Json<crate::models::UserPresence>, StatusCode> {
-    let presence = crate::services::PresenceService::get_presence(
+    // Hidden presence is indistinguishable from an unknown user
+    let presence = crate::services::PresenceService::get_visible_presence(
         state.db.pool(),
+        user_id,
         target_user_id,
     )
     .await
@@ -569,6 +1943,49 @@ pub async fn get_presence(
     Ok(Json(presence))
 }
 
+pub async fn get_presence_settings(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<PresenceSettings>, StatusCode> {
+    let settings = PresenceService::get_settings(state.db.pool(), user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to get presence settings: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    
+    Ok(Json(settings))
+}
+
+pub async fn update_presence_settings(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    Json(payload): Json<PresenceSettings>,
+) -> Result<Json<PresenceSettings>, StatusCode> {
+    let valid_visibilities = ["everyone", "contacts", "nobody"];
+    if !valid_visibilities.contains(&payload.last_seen_visibility.as_str()) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    
+    // Contacts that could see the presence until now get a last "offline" when it becomes hidden
+    if payload.last_seen_visibility == "nobody" {
+        crate::websocket::hide_presence(&state, user_id).await;
+    }
+    
+    let settings = PresenceService::update_settings(
+        state.db.pool(),
+        user_id,
+        &payload.last_seen_visibility,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to update presence settings: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    
+    Ok(Json(settings))
+}
+
 /// Store encrypted content for a message
 /// 
 /// SECURITY: This endpoint stores encrypted content as opaque binary data.
@@ -576,13 +1993,18 @@ pub async fn get_presence(
 pub async fn store_encrypted_content(
     Extension(state): Extension<std::sync::Arc<AppState>>,
     Extension(user_id): Extension<Uuid>,
+    Path(message_id): Path<Uuid>,
     Json(payload): Json<crate::models::EncryptedContentRequest>,
 ) -> Result<StatusCode, StatusCode> {
+    if payload.message_id != message_id {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    
     // Verify that the user is the sender of the message
     let message = sqlx::query_as::<_, crate::models::Message>(
         "SELECT * FROM messages WHERE id = $1",
     )
-    .bind(payload.message_id)
+    .bind(message_id)
     .fetch_optional(state.db.pool())
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
@@ -594,20 +2016,48 @@ pub async fn store_encrypted_content(
     
     // Decode base64 content
     use base64::{Engine as _, engine::general_purpose};
-    let content_data = general_purpose::STANDARD
-        .decode(&payload.content_data)
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let decode = |content: &str| general_purpose::STANDARD.decode(content).map_err(|_| StatusCode::BAD_REQUEST);
+    let mut blobs = Vec::with_capacity(payload.device_contents.len() + 1);
+    if let Some(content_data) = &payload.content_data {
+        blobs.push((None, decode(content_data)?, payload.content_hash.clone()));
+    }
+    for content in &payload.device_contents {
+        blobs.push((
+            Some(content.device_id),
+            decode(&content.content_data)?,
+            content.content_hash.clone(),
+        ));
+    }
+    if blobs.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    
+    // Ciphertexts can only be addressed to the devices of the recipient or the sender's other devices
+    let device_ids: Vec<Uuid> = payload.device_contents.iter().map(|content| content.device_id).collect();
+    if !device_ids.is_empty()
+        && !DeviceService::all_belong_to(
+            state.db.pool(),
+            &device_ids,
+            &[message.sender_id, message.recipient_id],
+        )
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
     
     // Store as opaque binary (backend cannot read it)
     crate::services::EncryptedContentService::store_content(
         state.db.pool(),
-        payload.message_id,
-        &content_data,
-        payload.content_hash.as_deref(),
+        message_id,
+        &blobs,
         payload.expires_at,
     )
     .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .map_err(|e| {
+        tracing::error!("Failed to store encrypted content: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
     
     Ok(StatusCode::CREATED)
 }
@@ -616,9 +2066,11 @@ pub async fn store_encrypted_content(
 /// 
 /// SECURITY: This endpoint returns encrypted content as opaque binary data.
 /// The backend cannot decrypt it - decryption happens client-side.
+/// A device only gets the ciphertext addressed to it, or the one shared by every device.
 pub async fn get_encrypted_content(
     Extension(state): Extension<std::sync::Arc<AppState>>,
     Extension(user_id): Extension<Uuid>,
+    Extension(DeviceId(device_id)): Extension<DeviceId>,
     Path(message_id): Path<Uuid>,
 ) -> Result<Json<crate::models::EncryptedContentResponse>, StatusCode> {
     // Verify that the user is the sender or recipient
@@ -636,37 +2088,446 @@ pub async fn get_encrypted_content(
     }
     
     // Get encrypted content (opaque binary)
-    let content_data = crate::services::EncryptedContentService::get_content(
+    let content = crate::services::EncryptedContentService::get_content(
         state.db.pool(),
         message_id,
+        device_id,
     )
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
     .ok_or(StatusCode::NOT_FOUND)?;
     
-    // Get hash if available
-    let content_hash: Option<String> = sqlx::query_scalar(
-        "SELECT content_hash FROM encrypted_content WHERE message_id = $1",
+    use base64::{Engine as _, engine::general_purpose};
+    Ok(Json(crate::models::EncryptedContentResponse {
+        message_id: content.message_id,
+        device_id: content.device_id,
+        content_data: general_purpose::STANDARD.encode(&content.content_data),
+        content_hash: content.content_hash,
+        created_at: content.created_at,
+    }))
+}
+
+// Most one-time prekeys accepted in one upload, and stored per device
+const MAX_ONE_TIME_PREKEYS_PER_UPLOAD: usize = 100;
+const MAX_ONE_TIME_PREKEYS_PER_DEVICE: i64 = 200;
+// A device is asked for more one-time prekeys when its stock drops below this
+const PREKEY_LOW_THRESHOLD: i64 = 10;
+// New identity keys a device may publish in 24 hours
+const MAX_IDENTITY_KEY_CHANGES_PER_DAY: i64 = 5;
+
+/// Decode a Base64 public key (32 bytes, or 33 with the Signal key type prefix)
+fn decode_public_key(key: &str) -> Result<Vec<u8>, StatusCode> {
+    use base64::{Engine as _, engine::general_purpose};
+    
+    let key = general_purpose::STANDARD.decode(key).map_err(|_| StatusCode::BAD_REQUEST)?;
+    if !matches!(key.len(), 32 | 33) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(key)
+}
+
+fn decode_one_time_prekeys(keys: &[OneTimePrekey]) -> Result<Vec<(i32, Vec<u8>)>, StatusCode> {
+    if keys.len() > MAX_ONE_TIME_PREKEYS_PER_UPLOAD {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    keys.iter()
+        .map(|key| Ok((key.key_id, decode_public_key(&key.public_key)?)))
+        .collect()
+}
+
+/// Refuse uploads that would make a device store too many one-time prekeys
+async fn check_one_time_prekey_capacity(
+    state: &AppState,
+    device_id: Uuid,
+    uploaded: usize,
+) -> Result<(), StatusCode> {
+    let stored = PrekeyService::count_one_time_prekeys(state.db.pool(), device_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if stored + uploaded as i64 > MAX_ONE_TIME_PREKEYS_PER_DEVICE {
+        return Err(StatusCode::CONFLICT);
+    }
+    Ok(())
+}
+
+/// Publish the public keys of the current device
+/// 
+/// SECURITY: Only public keys are accepted, the private keys stay on the device.
+/// The signature of the signed prekey is checked by the devices fetching it.
+pub async fn upload_keys(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    Extension(DeviceId(device_id)): Extension<DeviceId>,
+    Json(payload): Json<UploadKeysRequest>,
+) -> Result<StatusCode, StatusCode> {
+    use base64::{Engine as _, engine::general_purpose};
+    
+    // Keys belong to a device, sessions from before devices existed must log in again
+    let device_id = device_id.ok_or(StatusCode::FORBIDDEN)?;
+    
+    let identity_key = decode_public_key(&payload.identity_key)?;
+    let signed_prekey = decode_public_key(&payload.signed_prekey.public_key)?;
+    let signature = general_purpose::STANDARD
+        .decode(&payload.signed_prekey.signature)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    if signature.len() != 64 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let one_time_prekeys = decode_one_time_prekeys(&payload.one_time_prekeys)?;
+    check_one_time_prekey_capacity(&state, device_id, one_time_prekeys.len()).await?;
+    
+    // Each new identity key alerts every contact, a device can't keep rotating it
+    if IdentityKeyLogService::exceeds_change_limit(
+        state.db.pool(),
+        user_id,
+        device_id,
+        &identity_key,
+        Utc::now() - chrono::Duration::days(1),
+        MAX_IDENTITY_KEY_CHANGES_PER_DAY,
     )
-    .bind(message_id)
-    .fetch_optional(state.db.pool())
     .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+    
+    let log_entry = PrekeyService::upload_keys(
+        state.db.pool(),
+        user_id,
+        device_id,
+        payload.registration_id,
+        &identity_key,
+        (payload.signed_prekey.key_id, &signed_prekey, &signature),
+        &one_time_prekeys,
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to store device keys: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    
+    // Contacts must verify the new key (reinstall, new device, or someone impersonating the user)
+    if let Some(entry) = log_entry {
+        tracing::info!("🔑 Identity key of device {} logged at index {}", device_id, entry.log_index);
+        crate::websocket::notify_identity_changed(&state, &entry).await;
+    }
+    
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Top up the one-time prekeys of the current device
+pub async fn upload_one_time_prekeys(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(DeviceId(device_id)): Extension<DeviceId>,
+    Json(payload): Json<UploadOneTimePrekeysRequest>,
+) -> Result<Json<PrekeyCountResponse>, StatusCode> {
+    let device_id = device_id.ok_or(StatusCode::FORBIDDEN)?;
+    let one_time_prekeys = decode_one_time_prekeys(&payload.one_time_prekeys)?;
+    check_one_time_prekey_capacity(&state, device_id, one_time_prekeys.len()).await?;
+    
+    let added = PrekeyService::add_one_time_prekeys(state.db.pool(), device_id, &one_time_prekeys)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to store one-time prekeys: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    // The identity key and signed prekey must be published first
+    if !added {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    
+    get_prekey_count(Extension(state), Extension(DeviceId(Some(device_id)))).await
+}
+
+pub async fn get_prekey_count(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(DeviceId(device_id)): Extension<DeviceId>,
+) -> Result<Json<PrekeyCountResponse>, StatusCode> {
+    let device_id = device_id.ok_or(StatusCode::FORBIDDEN)?;
+    let one_time_prekeys = PrekeyService::count_one_time_prekeys(state.db.pool(), device_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    
+    Ok(Json(PrekeyCountResponse { one_time_prekeys }))
+}
+
+#[derive(Deserialize)]
+pub struct PrekeyBundleQuery {
+    device_id: Option<Uuid>,
+}
+
+/// Fetch the prekey bundles of a user's devices to start Signal sessions with them
+/// 
+/// Each fetch consumes one one-time prekey per device.
+pub async fn get_prekey_bundle(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    Path(target_user_id): Path<Uuid>,
+    Query(query): Query<PrekeyBundleQuery>,
+) -> Result<Json<PrekeyBundleResponse>, Response> {
+    use base64::{Engine as _, engine::general_purpose};
+    
+    // Every fetch consumes a one-time prekey per device, don't let anyone drain them
+    let key = format!("{}:{}", user_id, target_user_id);
+    if let crate::rate_limit::RateLimitDecision::Limited { retry_after } = state
+        .rate_limiter
+        .check("prekey_bundle", &key, state.config.rate_limit.prekey_bundle)
+        .await
+    {
+        return Err(crate::rate_limit::too_many_requests(retry_after));
+    }
+    
+    let device_keys = PrekeyService::get_device_keys(state.db.pool(), target_user_id, query.device_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to get device keys: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+    if device_keys.is_empty() {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    }
+    
+    let mut devices = Vec::with_capacity(device_keys.len());
+    for keys in device_keys {
+        let claimed = PrekeyService::claim_one_time_prekey(state.db.pool(), keys.device_id)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to claim one-time prekey: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            })?;
+        // Notify once below the threshold, and once more when it runs out: concurrent
+        // fetches may skip any given count, the notice is recorded to not repeat it
+        if let Some(claimed) = &claimed {
+            if claimed.remaining < PREKEY_LOW_THRESHOLD {
+                match PrekeyService::mark_low_prekeys_notified(state.db.pool(), keys.device_id, claimed.remaining).await {
+                    Ok(true) => {
+                        crate::websocket::notify_prekeys_low(&state, keys.user_id, keys.device_id, claimed.remaining).await;
+                    }
+                    Ok(false) => {}
+                    Err(e) => tracing::warn!("Failed to record low prekeys notice: {:?}", e),
+                }
+            }
+        }
+        
+        devices.push(DevicePrekeyBundle {
+            device_id: keys.device_id,
+            registration_id: keys.registration_id,
+            identity_key: general_purpose::STANDARD.encode(&keys.identity_key),
+            signed_prekey: SignedPrekey {
+                key_id: keys.signed_prekey_id,
+                public_key: general_purpose::STANDARD.encode(&keys.signed_prekey),
+                signature: general_purpose::STANDARD.encode(&keys.signed_prekey_signature),
+            },
+            one_time_prekey: claimed.map(|claimed| OneTimePrekey {
+                key_id: claimed.key_id,
+                public_key: general_purpose::STANDARD.encode(&claimed.public_key),
+            }),
+        });
+    }
     
-    let created_at: DateTime<Utc> = sqlx::query_scalar(
-        "SELECT created_at FROM encrypted_content WHERE message_id = $1",
+    Ok(Json(PrekeyBundleResponse {
+        user_id: target_user_id,
+        devices,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct InclusionProofQuery {
+    identity_key: String,
+    device_id: Option<Uuid>,
+}
+
+/// Prove that an identity key was published by a user, from the key transparency log
+pub async fn get_identity_key_proof(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Path(target_user_id): Path<Uuid>,
+    Query(query): Query<InclusionProofQuery>,
+) -> Result<Json<InclusionProofResponse>, StatusCode> {
+    let identity_key = decode_public_key(&query.identity_key)?;
+    let entries = IdentityKeyLogService::get_inclusion_proof(
+        state.db.pool(),
+        target_user_id,
+        &identity_key,
+        query.device_id,
     )
-    .bind(message_id)
-    .fetch_one(state.db.pool())
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     
-    use base64::{Engine as _, engine::general_purpose};
-    Ok(Json(crate::models::EncryptedContentResponse {
-        message_id,
-        content_data: general_purpose::STANDARD.encode(&content_data),
-        content_hash,
-        created_at,
+    // Never hand out a proof that doesn't hold, a broken chain means the log was tampered with
+    if !IdentityKeyLogService::verify_chain(&entries) {
+        tracing::error!("❌ Identity key log of user {} is not a valid hash chain", target_user_id);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    
+    let mut entries = entries.into_iter().map(IdentityKeyLogEntryResponse::from);
+    let entry = entries.next().ok_or(StatusCode::NOT_FOUND)?;
+    let following: Vec<IdentityKeyLogEntryResponse> = entries.collect();
+    let head = following.last().unwrap_or(&entry);
+    
+    Ok(Json(InclusionProofResponse {
+        user_id: target_user_id,
+        head_index: head.log_index,
+        head_hash: head.entry_hash.clone(),
+        entry,
+        following,
     }))
 }
 
+// Delivery tokens are 16 bytes, as derived by Signal clients from the profile key
+const UNIDENTIFIED_ACCESS_KEY_BYTES: usize = 16;
+// Most devices a sealed message can be addressed to
+const MAX_SEALED_ENVELOPES: usize = 32;
+// Largest sealed envelope, attachments are uploaded separately
+const MAX_SEALED_ENVELOPE_BYTES: usize = 256 * 1024;
+
+fn decode_access_key(key: &str) -> Option<Vec<u8>> {
+    use base64::{Engine as _, engine::general_purpose};
+    
+    general_purpose::STANDARD
+        .decode(key.trim())
+        .ok()
+        .filter(|key| key.len() == UNIDENTIFIED_ACCESS_KEY_BYTES)
+}
+
+/// Publish the delivery token contacts must present to send sealed sender messages
+pub async fn set_unidentified_access_key(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+    Json(payload): Json<UnidentifiedAccessKeyRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let access_key = decode_access_key(&payload.access_key).ok_or(StatusCode::BAD_REQUEST)?;
+    
+    SealedMessageService::set_access_key(state.db.pool(), user_id, Some(&access_key))
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to set unidentified access key: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Stop accepting sealed sender messages
+pub async fn delete_unidentified_access_key(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<StatusCode, StatusCode> {
+    SealedMessageService::set_access_key(state.db.pool(), user_id, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Send a sealed sender message
+/// 
+/// SECURITY: The request carries no user token, the sender is only named inside the
+/// envelopes which are encrypted for the recipient devices. The backend stores and
+/// routes the recipient device only, never the sender or the conversation.
+pub async fn send_sealed_message(
+    Extension(state): Extension<std::sync::Arc<AppState>>,
+    headers: axum::http::HeaderMap,
+    Json(payload): Json<SealedMessageRequest>,
+) -> Result<StatusCode, StatusCode> {
+    // Unknown recipients and wrong tokens look the same, so users can't be enumerated
+    let access_key = headers
+        .get("Unidentified-Access-Key")
+        .and_then(|value| value.to_str().ok())
+        .and_then(decode_access_key)
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let allowed = SealedMessageService::verify_access_key(state.db.pool(), payload.recipient_id, &access_key)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !allowed {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    
+    if payload.envelopes.is_empty() || payload.envelopes.len() > MAX_SEALED_ENVELOPES {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    use base64::{Engine as _, engine::general_purpose};
+    let mut envelopes = Vec::with_capacity(payload.envelopes.len());
+    for envelope in &payload.envelopes {
+        let content = general_purpose::STANDARD
+            .decode(&envelope.content)
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        if content.is_empty() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        if content.len() > MAX_SEALED_ENVELOPE_BYTES {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        envelopes.push((envelope.device_id, content));
+    }
+    
+    // Envelopes can only be addressed to the recipient's own devices, once each
+    let mut device_ids: Vec<Uuid> = envelopes.iter().map(|(device_id, _)| *device_id).collect();
+    device_ids.sort();
+    device_ids.dedup();
+    if device_ids.len() != envelopes.len()
+        || !DeviceService::all_belong_to(state.db.pool(), &device_ids, &[payload.recipient_id])
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    
+    let messages = SealedMessageService::store(state.db.pool(), payload.recipient_id, &envelopes)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to store sealed message: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        // A device that does not pick up its envelopes stops accepting new ones
+        .ok_or(StatusCode::INSUFFICIENT_STORAGE)?;
+    
+    // Devices without a live connection get them when they reconnect
+    crate::websocket::notify_sealed_messages(payload.recipient_id, &messages).await;
+    
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{Engine as _, engine::general_purpose};
+    
+    #[test]
+    fn test_decode_public_key_accepts_raw_and_prefixed_keys() {
+        assert_eq!(decode_public_key(&general_purpose::STANDARD.encode([7u8; 32])), Ok(vec![7u8; 32]));
+        assert_eq!(decode_public_key(&general_purpose::STANDARD.encode([5u8; 33])), Ok(vec![5u8; 33]));
+        
+        for key in [
+            general_purpose::STANDARD.encode([7u8; 31]),
+            general_purpose::STANDARD.encode([7u8; 64]),
+            String::new(),
+            "not base64!".to_string(),
+        ] {
+            assert_eq!(decode_public_key(&key), Err(StatusCode::BAD_REQUEST));
+        }
+    }
+    
+    #[test]
+    fn test_decode_one_time_prekeys_rejects_bad_batches() {
+        let prekey = |key_id: i32, len: usize| OneTimePrekey {
+            key_id,
+            public_key: general_purpose::STANDARD.encode(vec![key_id as u8; len]),
+        };
+        
+        assert_eq!(
+            decode_one_time_prekeys(&[prekey(1, 33), prekey(2, 32)]),
+            Ok(vec![(1, vec![1; 33]), (2, vec![2; 32])])
+        );
+        assert_eq!(decode_one_time_prekeys(&[]), Ok(vec![]));
+        
+        // One invalid key fails the whole upload
+        assert_eq!(
+            decode_one_time_prekeys(&[prekey(1, 33), prekey(2, 16)]),
+            Err(StatusCode::BAD_REQUEST)
+        );
+        
+        let too_many: Vec<_> = (0..=MAX_ONE_TIME_PREKEYS_PER_UPLOAD as i32).map(|key_id| prekey(key_id, 33)).collect();
+        assert_eq!(decode_one_time_prekeys(&too_many), Err(StatusCode::BAD_REQUEST));
+        assert!(decode_one_time_prekeys(&too_many[1..]).is_ok());
+    }
+}
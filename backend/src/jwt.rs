@@ -0,0 +1,311 @@
+use anyhow::Context;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::config::Config;
+
+/// `kid` of the shared secret used when no key directory is configured
+const SECRET_KEY_ID: &str = "hs256";
+
+#[derive(Clone)]
+struct VerificationKey {
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+#[derive(Clone)]
+struct SigningKey {
+    kid: String,
+    algorithm: Algorithm,
+    key: EncodingKey,
+}
+
+/// Keys used to sign and verify the JWTs issued by the backend
+/// 
+/// Every `<kid>.pem` file of the key directory is a verification key: an Ed25519 (EdDSA)
+/// or RSA (RS256) private or public key. New tokens are signed with the private key named
+/// by `JWT_SIGNING_KEY_ID`, tokens signed by the other keys stay valid until they expire.
+/// Without a key directory the backend falls back to `JWT_SECRET` (HS256), for development.
+#[derive(Clone)]
+pub struct JwtKeys {
+    signing: SigningKey,
+    verification: HashMap<String, VerificationKey>,
+    jwks: serde_json::Value,
+}
+
+/// A key file once parsed
+struct LoadedKey {
+    algorithm: Algorithm,
+    decoding: DecodingKey,
+    encoding: Option<EncodingKey>,
+    jwk: serde_json::Value,
+}
+
+impl JwtKeys {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        match &config.jwt_keys_dir {
+            Some(dir) => Self::from_dir(dir, config.jwt_signing_key_id.as_deref()),
+            None => Ok(Self::from_secret(&config.jwt_secret)),
+        }
+    }
+    
+    pub fn from_secret(secret: &str) -> Self {
+        let mut verification = HashMap::new();
+        verification.insert(
+            SECRET_KEY_ID.to_string(),
+            VerificationKey {
+                algorithm: Algorithm::HS256,
+                key: DecodingKey::from_secret(secret.as_bytes()),
+            },
+        );
+        
+        JwtKeys {
+            signing: SigningKey {
+                kid: SECRET_KEY_ID.to_string(),
+                algorithm: Algorithm::HS256,
+                key: EncodingKey::from_secret(secret.as_bytes()),
+            },
+            verification,
+            // A shared secret is never published
+            jwks: serde_json::json!({ "keys": [] }),
+        }
+    }
+    
+    pub fn from_dir(dir: impl AsRef<Path>, signing_key_id: Option<&str>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref();
+        let mut keys = Vec::new();
+        for entry in std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read JWT key directory {}", dir.display()))?
+        {
+            let path = entry?.path();
+            let Some(kid) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".pem"))
+            else {
+                continue;
+            };
+            let pem = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read JWT key {}", path.display()))?;
+            let key = load_key(kid, &pem)
+                .with_context(|| format!("Invalid JWT key {}", path.display()))?;
+            keys.push((kid.to_string(), key));
+        }
+        keys.sort_by(|(a, _), (b, _)| a.cmp(b));
+        
+        let signing_kid = match signing_key_id {
+            Some(kid) => kid.to_string(),
+            None => {
+                let private: Vec<&String> = keys
+                    .iter()
+                    .filter(|(_, key)| key.encoding.is_some())
+                    .map(|(kid, _)| kid)
+                    .collect();
+                match private.as_slice() {
+                    [kid] => kid.to_string(),
+                    [] => anyhow::bail!("No private key in {}", dir.display()),
+                    _ => anyhow::bail!("Several private keys in {}, set JWT_SIGNING_KEY_ID", dir.display()),
+                }
+            }
+        };
+        
+        let mut signing = None;
+        let mut verification = HashMap::new();
+        let mut jwks = Vec::new();
+        for (kid, key) in keys {
+            if kid == signing_kid {
+                signing = key.encoding.map(|encoding| SigningKey {
+                    kid: kid.clone(),
+                    algorithm: key.algorithm,
+                    key: encoding,
+                });
+            }
+            jwks.push(key.jwk);
+            verification.insert(
+                kid,
+                VerificationKey {
+                    algorithm: key.algorithm,
+                    key: key.decoding,
+                },
+            );
+        }
+        let signing = signing
+            .with_context(|| format!("No private key {}.pem in {}", signing_kid, dir.display()))?;
+        
+        Ok(JwtKeys {
+            signing,
+            verification,
+            jwks: serde_json::json!({ "keys": jwks }),
+        })
+    }
+    
+    /// `kid` of the key signing new tokens
+    pub fn signing_key_id(&self) -> &str {
+        &self.signing.kid
+    }
+    
+    pub fn verification_key_ids(&self) -> Vec<&str> {
+        let mut kids: Vec<&str> = self.verification.keys().map(String::as_str).collect();
+        kids.sort();
+        kids
+    }
+    
+    /// Public verification keys, served at `/.well-known/jwks.json`
+    pub fn jwks(&self) -> &serde_json::Value {
+        &self.jwks
+    }
+    
+    pub fn encode<T: Serialize>(&self, claims: &T) -> anyhow::Result<String> {
+        let mut header = Header::new(self.signing.algorithm);
+        header.kid = Some(self.signing.kid.clone());
+        encode(&header, claims, &self.signing.key).context("Failed to sign token")
+    }
+    
+    /// Verify the signature and expiry of a token with the key named by its `kid`
+    pub fn decode<T: DeserializeOwned>(&self, token: &str) -> anyhow::Result<T> {
+        let header = decode_header(token).context("Invalid token header")?;
+        // Tokens issued before key ids were introduced carry no kid
+        let kid = header.kid.as_deref().unwrap_or(SECRET_KEY_ID);
+        let key = self
+            .verification
+            .get(kid)
+            .with_context(|| format!("Unknown signing key {}", kid))?;
+        
+        // The algorithm comes from our key, never from the token
+        let token_data = decode::<T>(token, &key.key, &Validation::new(key.algorithm))
+            .context("Invalid token signature")?;
+        Ok(token_data.claims)
+    }
+}
+
+fn load_key(kid: &str, pem: &str) -> anyhow::Result<LoadedKey> {
+    use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey};
+    use rsa::pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey, EncodeRsaPrivateKey};
+    use rsa::traits::PublicKeyParts;
+    
+    if let Ok(private) = ed25519_dalek::SigningKey::from_pkcs8_pem(pem) {
+        let der = private.to_pkcs8_der().context("Failed to encode Ed25519 key")?;
+        return ed25519_key(
+            kid,
+            &private.verifying_key(),
+            Some(EncodingKey::from_ed_der(der.as_bytes())),
+        );
+    }
+    if let Ok(public) = ed25519_dalek::VerifyingKey::from_public_key_pem(pem) {
+        return ed25519_key(kid, &public, None);
+    }
+    
+    let private = rsa::RsaPrivateKey::from_pkcs8_pem(pem)
+        .or_else(|_| rsa::RsaPrivateKey::from_pkcs1_pem(pem))
+        .ok();
+    let (public, encoding) = match private {
+        Some(private) => {
+            let der = private.to_pkcs1_der().context("Failed to encode RSA key")?;
+            (
+                private.to_public_key(),
+                Some(EncodingKey::from_rsa_der(der.as_bytes())),
+            )
+        }
+        None => (
+            rsa::RsaPublicKey::from_public_key_pem(pem)
+                .or_else(|_| rsa::RsaPublicKey::from_pkcs1_pem(pem))
+                .context("Not an Ed25519 or RSA key")?,
+            None,
+        ),
+    };
+    let n = URL_SAFE_NO_PAD.encode(public.n().to_bytes_be());
+    let e = URL_SAFE_NO_PAD.encode(public.e().to_bytes_be());
+    
+    Ok(LoadedKey {
+        algorithm: Algorithm::RS256,
+        decoding: DecodingKey::from_rsa_components(&n, &e)?,
+        encoding,
+        jwk: serde_json::json!({
+            "kty": "RSA",
+            "use": "sig",
+            "alg": "RS256",
+            "kid": kid,
+            "n": n,
+            "e": e,
+        }),
+    })
+}
+
+fn ed25519_key(
+    kid: &str,
+    public: &ed25519_dalek::VerifyingKey,
+    encoding: Option<EncodingKey>,
+) -> anyhow::Result<LoadedKey> {
+    let x = URL_SAFE_NO_PAD.encode(public.as_bytes());
+    
+    Ok(LoadedKey {
+        algorithm: Algorithm::EdDSA,
+        decoding: DecodingKey::from_ed_components(&x)?,
+        encoding,
+        jwk: serde_json::json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "use": "sig",
+            "alg": "EdDSA",
+            "kid": kid,
+            "x": x,
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::pkcs8::{spki::der::pem::LineEnding, EncodePrivateKey, EncodePublicKey};
+    use serde::Deserialize;
+    
+    #[derive(Serialize, Deserialize)]
+    struct TestClaims {
+        sub: String,
+        exp: usize,
+    }
+    
+    fn claims() -> TestClaims {
+        TestClaims {
+            sub: "user".to_string(),
+            exp: chrono::Utc::now().timestamp() as usize + 60,
+        }
+    }
+    
+    #[test]
+    fn test_rotation_keeps_old_tokens_valid() {
+        let dir = std::env::temp_dir().join(format!("kisse-jwt-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let old = ed25519_dalek::SigningKey::from_bytes(&[1; 32]);
+        let new = ed25519_dalek::SigningKey::from_bytes(&[2; 32]);
+        std::fs::write(dir.join("2026-01.pem"), old.to_pkcs8_pem(LineEnding::LF).unwrap().as_bytes()).unwrap();
+        
+        let keys = JwtKeys::from_dir(&dir, None).unwrap();
+        let old_token = keys.encode(&claims()).unwrap();
+        
+        // Rotation: the new key signs, the old one is only kept to verify
+        std::fs::write(dir.join("2026-02.pem"), new.to_pkcs8_pem(LineEnding::LF).unwrap().as_bytes()).unwrap();
+        std::fs::write(
+            dir.join("2026-01.pem"),
+            old.verifying_key().to_public_key_pem(LineEnding::LF).unwrap(),
+        )
+        .unwrap();
+        let keys = JwtKeys::from_dir(&dir, Some("2026-02")).unwrap();
+        
+        assert_eq!(keys.signing_key_id(), "2026-02");
+        assert_eq!(keys.verification_key_ids(), vec!["2026-01", "2026-02"]);
+        assert_eq!(keys.jwks()["keys"].as_array().unwrap().len(), 2);
+        assert!(keys.decode::<TestClaims>(&old_token).is_ok());
+        assert!(keys.decode::<TestClaims>(&keys.encode(&claims()).unwrap()).is_ok());
+        
+        // A token signed with a shared secret can't pass for one of ours
+        let forged = JwtKeys::from_secret("secret").encode(&claims()).unwrap();
+        assert!(keys.decode::<TestClaims>(&forged).is_err());
+        
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
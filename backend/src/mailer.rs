@@ -0,0 +1,273 @@
+use anyhow::Context;
+use axum::async_trait;
+use chrono::Utc;
+use std::path::PathBuf;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::config::MailConfig;
+
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// How emails leave the backend
+#[async_trait]
+pub trait MailTransport: Send + Sync {
+    async fn send(&self, from: &str, mail: &Mail) -> anyhow::Result<()>;
+}
+
+/// Writes emails to the logs, for local development
+pub struct LogTransport;
+
+#[async_trait]
+impl MailTransport for LogTransport {
+    async fn send(&self, from: &str, mail: &Mail) -> anyhow::Result<()> {
+        tracing::info!(
+            "📧 Email from {} to {}: {}\n{}",
+            from,
+            mail.to,
+            mail.subject,
+            mail.body
+        );
+        Ok(())
+    }
+}
+
+/// Writes each email to its own file, for tests and local development
+pub struct FileTransport {
+    dir: PathBuf,
+}
+
+impl FileTransport {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileTransport { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl MailTransport for FileTransport {
+    async fn send(&self, from: &str, mail: &Mail) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .context("Failed to create mail directory")?;
+        
+        let path = self.dir.join(format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S%.3f"),
+            Uuid::new_v4()
+        ));
+        let content = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\n\r\n{}\r\n",
+            from,
+            mail.to,
+            mail.subject,
+            Utc::now().to_rfc2822(),
+            mail.body
+        );
+        tokio::fs::write(&path, content)
+            .await
+            .context("Failed to write email")?;
+        
+        tracing::info!("📧 Email to {} written to {}", mail.to, path.display());
+        Ok(())
+    }
+}
+
+/// Sends emails through an SMTP relay (STARTTLS), for production
+pub struct SmtpTransport {
+    transport: lettre::AsyncSmtpTransport<lettre::Tokio1Executor>,
+}
+
+impl SmtpTransport {
+    pub fn new(config: &MailConfig) -> anyhow::Result<Self> {
+        use lettre::transport::smtp::authentication::Credentials;
+        
+        let mut builder = lettre::AsyncSmtpTransport::<lettre::Tokio1Executor>::starttls_relay(
+            &config.smtp_host,
+        )
+        .context("Invalid SMTP host")?
+        .port(config.smtp_port);
+        if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        
+        Ok(SmtpTransport {
+            transport: builder.build(),
+        })
+    }
+}
+
+#[async_trait]
+impl MailTransport for SmtpTransport {
+    async fn send(&self, from: &str, mail: &Mail) -> anyhow::Result<()> {
+        use lettre::AsyncTransport;
+        
+        let message = lettre::Message::builder()
+            .from(from.parse().context("Invalid sender address")?)
+            .to(mail.to.parse().context("Invalid recipient address")?)
+            .subject(mail.subject.clone())
+            .body(mail.body.clone())
+            .context("Failed to build email")?;
+        
+        self.transport
+            .send(message)
+            .await
+            .context("Failed to send email")?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Mailer {
+    transport: Arc<dyn MailTransport>,
+    from: String,
+    app_url: String,
+}
+
+impl Mailer {
+    pub fn new(transport: Arc<dyn MailTransport>, from: String, app_url: String) -> Self {
+        Mailer {
+            transport,
+            from,
+            app_url,
+        }
+    }
+    
+    pub fn from_config(config: &MailConfig) -> anyhow::Result<Self> {
+        let transport: Arc<dyn MailTransport> = match config.transport.as_str() {
+            "smtp" => Arc::new(SmtpTransport::new(config)?),
+            "file" => Arc::new(FileTransport::new(&config.dir)),
+            _ => Arc::new(LogTransport),
+        };
+        Ok(Mailer::new(transport, config.from.clone(), config.app_url.clone()))
+    }
+    
+    /// Link to a page of the client application
+    pub fn link(&self, path: &str, token: &str) -> String {
+        format!("{}/{}?token={}", self.app_url.trim_end_matches('/'), path, token)
+    }
+    
+    pub async fn send(&self, mail: Mail) -> anyhow::Result<()> {
+        self.transport.send(&self.from, &mail).await
+    }
+    
+    pub async fn send_email_verification(&self, to: &str, token: &str) -> anyhow::Result<()> {
+        self.send(Mail {
+            to: to.to_string(),
+            subject: "Confirmez votre adresse email Kisse".to_string(),
+            body: format!(
+                "Bienvenue sur Kisse !\n\n\
+                 Pour confirmer votre adresse email, ouvrez ce lien dans les 24 heures :\n{}\n\n\
+                 Si vous n'avez pas créé de compte, ignorez cet email.",
+                self.link("verify-email", token)
+            ),
+        })
+        .await
+    }
+    
+    pub async fn send_unlock_account(&self, to: &str, token: &str) -> anyhow::Result<()> {
+        self.send(Mail {
+            to: to.to_string(),
+            subject: "Votre compte Kisse a été verrouillé".to_string(),
+            body: format!(
+                "Trop de tentatives de connexion ont échoué sur votre compte, il a été verrouillé temporairement.\n\n\
+                 Si c'était vous, vous pouvez le déverrouiller immédiatement :\n{}\n\n\
+                 Sinon, nous vous conseillons de changer votre mot de passe.",
+                self.link("unlock", token)
+            ),
+        })
+        .await
+    }
+    
+    pub async fn send_password_reset(&self, to: &str, token: &str) -> anyhow::Result<()> {
+        self.send(Mail {
+            to: to.to_string(),
+            subject: "Réinitialisation de votre mot de passe Kisse".to_string(),
+            body: format!(
+                "Une réinitialisation du mot de passe de votre compte a été demandée.\n\n\
+                 Pour choisir un nouveau mot de passe, ouvrez ce lien dans l'heure :\n{}\n\n\
+                 Si vous n'êtes pas à l'origine de cette demande, ignorez cet email.",
+                self.link("reset-password", token)
+            ),
+        })
+        .await
+    }
+    
+    pub async fn send_account_deletion_scheduled(
+        &self,
+        to: &str,
+        scheduled_at: chrono::DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        self.send(Mail {
+            to: to.to_string(),
+            subject: "Suppression de votre compte Kisse".to_string(),
+            body: format!(
+                "La suppression de votre compte a été demandée. Il sera définitivement effacé le {}, \
+                 avec vos messages, vos stories et vos appels.\n\n\
+                 Pour l'annuler, ouvrez l'application avant cette date et annulez la suppression depuis les paramètres du compte.",
+                scheduled_at.format("%d/%m/%Y à %H:%M UTC")
+            ),
+        })
+        .await
+    }
+    
+    pub async fn send_data_export_ready(
+        &self,
+        to: &str,
+        token: &str,
+        expires_at: chrono::DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        self.send(Mail {
+            to: to.to_string(),
+            subject: "Votre export de données Kisse est prêt".to_string(),
+            body: format!(
+                "L'archive contenant vos données est prête. Vous pouvez la télécharger jusqu'au {} :\n{}\n\n\
+                 Ce lien donne accès à vos données personnelles, ne le partagez pas.",
+                expires_at.format("%d/%m/%Y à %H:%M UTC"),
+                self.link("api/exports/download", token)
+            ),
+        })
+        .await
+    }
+    
+    pub async fn send_password_changed(&self, to: &str) -> anyhow::Result<()> {
+        self.send(Mail {
+            to: to.to_string(),
+            subject: "Votre mot de passe Kisse a été modifié".to_string(),
+            body: "Le mot de passe de votre compte vient d'être modifié et vos autres appareils ont été déconnectés.\n\n\
+                   Si vous n'êtes pas à l'origine de ce changement, réinitialisez immédiatement votre mot de passe."
+                .to_string(),
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[tokio::test]
+    async fn test_file_transport_writes_one_file_per_email() {
+        let dir = std::env::temp_dir().join(format!("kisse-mail-{}", Uuid::new_v4()));
+        let mailer = Mailer::new(
+            Arc::new(FileTransport::new(&dir)),
+            "Kisse <no-reply@kisse.local>".to_string(),
+            "http://localhost:8080/".to_string(),
+        );
+        
+        mailer.send_password_reset("user@example.com", "abc").await.unwrap();
+        
+        let mut entries = std::fs::read_dir(&dir).unwrap();
+        let path = entries.next().unwrap().unwrap().path();
+        assert!(entries.next().is_none());
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains("To: user@example.com"));
+        assert!(content.contains("http://localhost:8080/reset-password?token=abc"));
+        
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
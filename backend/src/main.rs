@@ -3,15 +3,22 @@ use axum::{
     routing::get,
     Router,
 };
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tower_http::cors::CorsLayer;
-use tracing_subscriber;
 
+mod avatar;
+mod client_info;
 mod config;
 mod database;
+mod export;
 mod handlers;
+mod jwt;
+mod mailer;
 mod models;
+mod oidc;
+mod rate_limit;
 mod routes;
 mod security;
 mod services;
@@ -20,6 +27,10 @@ mod background;
 
 use config::Config;
 use database::Database;
+use jwt::JwtKeys;
+use oidc::OidcClient;
+use mailer::Mailer;
+use rate_limit::{InMemoryStore, PostgresStore, RateLimitStore, RateLimiter};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -54,10 +65,40 @@ async fn main() -> anyhow::Result<()> {
         return Err(e);
     }
     
+    // Rate limit buckets must be shared when several instances run behind the load balancer
+    let rate_limit_store: Arc<dyn RateLimitStore> = match config.rate_limit.backend.as_str() {
+        "postgres" => Arc::new(PostgresStore::new(db.pool().clone())),
+        _ => Arc::new(InMemoryStore::default()),
+    };
+    tracing::info!("   Rate limit backend: {}", config.rate_limit.backend);
+    tracing::info!("   Mail transport: {}", config.mail.transport);
+    
+    let jwt_keys = JwtKeys::from_config(&config)?;
+    tracing::info!(
+        "   JWT signing key: {} (verification keys: {})",
+        jwt_keys.signing_key_id(),
+        jwt_keys.verification_key_ids().join(", ")
+    );
+    
+    let oidc = match &config.oidc {
+        Some(oidc_config) => {
+            tracing::info!("   SSO: {}", oidc_config.issuer);
+            Some(Arc::new(OidcClient::new(oidc_config.clone())?))
+        }
+        None => None,
+    };
+    if !config.password_login_enabled {
+        tracing::info!("   Password login disabled");
+    }
+    
     // Create shared state
     let app_state = Arc::new(AppState {
         db: db.clone(),
         config: config.clone(),
+        rate_limiter: RateLimiter::new(rate_limit_store),
+        mailer: Mailer::from_config(&config.mail)?,
+        jwt_keys,
+        oidc,
     });
     
     // Start background tasks
@@ -66,6 +107,7 @@ async fn main() -> anyhow::Result<()> {
     // Build the application router
     let app = Router::new()
         .route("/health", get(health_check))
+        .route("/.well-known/jwks.json", get(handlers::get_jwks))
         .nest("/api", routes::create_api_routes())
         .route("/ws", get(websocket::handle_websocket))
         .layer(Extension(app_state))
@@ -75,7 +117,12 @@ async fn main() -> anyhow::Result<()> {
     let listener = TcpListener::bind(&config.server_address).await?;
     tracing::info!("🚀 Server listening on {}", config.server_address);
     
-    axum::serve(listener, app).await?;
+    // Connect info gives access to the peer address when there is no reverse proxy
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
     
     Ok(())
 }
@@ -88,5 +135,9 @@ async fn health_check() -> &'static str {
 pub struct AppState {
     pub db: Database,
     pub config: Config,
+    pub rate_limiter: RateLimiter,
+    pub mailer: Mailer,
+    pub jwt_keys: JwtKeys,
+    pub oidc: Option<Arc<OidcClient>>,
 }
 
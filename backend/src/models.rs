@@ -14,6 +14,17 @@ pub struct User {
     pub avatar_url: Option<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub deletion_scheduled_at: Option<DateTime<Utc>>,
+    pub username_changed_at: Option<DateTime<Utc>>,
+    pub avatar_id: Option<Uuid>,
+    pub password_set: bool, // False for accounts provisioned by SSO until a password is reset
+}
+
+impl User {
+    pub fn is_email_verified(&self) -> bool {
+        self.email_verified_at.is_some()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,6 +34,9 @@ pub struct UserResponse {
     pub username: Option<String>,
     pub name: Option<String>,
     pub avatar_url: Option<String>,
+    pub email_verified: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deletion_scheduled_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
 }
 
@@ -34,6 +48,8 @@ impl From<User> for UserResponse {
             username: user.username,
             name: user.name,
             avatar_url: user.avatar_url,
+            email_verified: user.email_verified_at.is_some(),
+            deletion_scheduled_at: user.deletion_scheduled_at,
             created_at: user.created_at,
         }
     }
@@ -45,6 +61,11 @@ pub struct LoginRequest {
     pub email: String,
     #[validate(length(min = 1, message = "Password is required"))]
     pub password: String,
+    pub device_id: Option<Uuid>, // Returned by a previous login on the same device
+    #[validate(length(max = 255, message = "Device name must be less than 255 characters"))]
+    pub device_name: Option<String>,
+    #[validate(length(max = 50, message = "Platform must be less than 50 characters"))]
+    pub platform: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Validate)]
@@ -57,14 +78,232 @@ pub struct RegisterRequest {
     pub password: String,
     #[validate(length(max = 255, message = "Name must be less than 255 characters"))]
     pub name: Option<String>,
+    #[validate(length(max = 255, message = "Device name must be less than 255 characters"))]
+    pub device_name: Option<String>,
+    #[validate(length(max = 50, message = "Platform must be less than 50 characters"))]
+    pub platform: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthResponse {
     pub token: String,
+    pub refresh_token: String,
+    pub expires_in: i64, // Access token lifetime in seconds
+    pub device_id: Uuid,
     pub user: UserResponse,
 }
 
+/// First login step for accounts with two-factor authentication
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MfaChallengeResponse {
+    pub mfa_required: bool,
+    pub mfa_token: String, // Short-lived, only accepted by /auth/mfa/verify
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Authenticated(AuthResponse),
+    MfaRequired(MfaChallengeResponse),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct MfaVerifyRequest {
+    pub mfa_token: String,
+    #[validate(length(min = 6, max = 32, message = "Invalid code"))]
+    pub code: String, // TOTP code or recovery code
+    pub device_id: Option<Uuid>,
+    #[validate(length(max = 255, message = "Device name must be less than 255 characters"))]
+    pub device_name: Option<String>,
+    #[validate(length(max = 50, message = "Platform must be less than 50 characters"))]
+    pub platform: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcAuthorizeResponse {
+    pub authorization_url: String,
+    pub state: String,
+}
+
+/// Code and state received by the client on the provider's redirect
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct OidcCallbackRequest {
+    pub code: String,
+    pub state: String,
+    pub device_id: Option<Uuid>,
+    #[validate(length(max = 255, message = "Device name must be less than 255 characters"))]
+    pub device_name: Option<String>,
+    #[validate(length(max = 50, message = "Platform must be less than 50 characters"))]
+    pub platform: Option<String>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct UserMfa {
+    pub user_id: Uuid,
+    pub totp_secret: String,
+    pub enabled_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MfaStatusResponse {
+    pub enabled: bool,
+    pub recovery_codes_remaining: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TotpSetupResponse {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MfaCodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DisableMfaRequest {
+    pub password: String,
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    #[validate(length(min = 8, message = "Password must be at least 8 characters"))]
+    pub new_password: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct ForgotPasswordRequest {
+    #[validate(email)]
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    #[validate(length(min = 8, message = "Password must be at least 8 characters"))]
+    pub new_password: String,
+    pub mfa_code: Option<String>, // Required when two-factor authentication is enabled
+}
+
+/// Fields left out are unchanged, an empty name clears it
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct UpdateProfileRequest {
+    #[validate(length(min = 3, max = 50, message = "Username must be between 3 and 50 characters"))]
+    pub username: Option<String>,
+    #[validate(length(max = 255, message = "Name must be less than 255 characters"))]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteAccountRequest {
+    pub password: Option<String>, // Not needed for accounts without a usable password
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountDeletionResponse {
+    pub deletion_scheduled_at: DateTime<Utc>,
+}
+
+/// Personal data export, the archive is built in the background
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct DataExport {
+    pub id: Uuid,
+    pub status: String, // 'pending', 'processing', 'ready', 'failed'
+    pub size_bytes: Option<i64>,
+    pub created_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnlockAccountRequest {
+    pub token: String,
+}
+
+/// Server-side session backing a chain of access/refresh tokens
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub device_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Failed login attempts for an account or an IP
+#[derive(Debug, Clone, FromRow)]
+pub struct LoginThrottle {
+    pub failures: i32,
+    pub last_failure_at: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct SecurityEvent {
+    pub id: Uuid,
+    pub event_type: String, // 'login_succeeded', 'login_failed', 'account_locked', 'account_unlocked'
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Client installation a session is bound to
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Device {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub device_name: Option<String>,
+    pub platform: Option<String>,
+    pub last_ip: Option<String>,
+    pub last_user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+}
+
+/// Active session as listed to its owner
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct SessionResponse {
+    pub id: Uuid,
+    pub device_id: Option<Uuid>,
+    pub device_name: Option<String>,
+    pub platform: Option<String>,
+    pub last_ip: Option<String>,
+    pub last_user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub is_current: bool,
+}
+
+/// Session id of the authenticated request (inserted by the auth middleware)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionId(pub Uuid);
+
+/// Device of the authenticated request, sessions opened before devices existed have none
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceId(pub Option<Uuid>);
+
 /// Message metadata structure
 /// 
 /// SECURITY NOTE: This struct contains ONLY metadata, NEVER encrypted content.
@@ -82,6 +321,8 @@ pub struct Message {
     pub session_id: Option<String>, // Signal session ID (reference only, no keys)
     pub is_read: bool,
     pub read_at: Option<DateTime<Utc>>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    pub client_message_id: Option<String>, // Sender-generated idempotency key
     // NOTE: NO content field - backend is blind to message content
     // NOTE: NO encryption keys - all keys managed client-side
 }
@@ -91,6 +332,7 @@ pub struct MessageRequest {
     pub recipient_id: Uuid,
     pub message_type: String,
     pub session_id: Option<String>,
+    pub client_message_id: Option<String>, // Idempotency key, reused when retrying the same message
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -103,6 +345,10 @@ pub struct MessageResponse {
     pub timestamp: DateTime<Utc>,
     pub session_id: Option<String>,
     pub is_read: bool,
+    pub status: String, // 'sent', 'delivered', 'read'
+    pub delivered_at: Option<DateTime<Utc>>,
+    pub read_at: Option<DateTime<Utc>>,
+    pub client_message_id: Option<String>,
 }
 
 /// Encrypted content storage (opaque to backend)
@@ -110,25 +356,208 @@ pub struct MessageResponse {
 /// SECURITY: This structure stores encrypted content as opaque binary data.
 /// The backend cannot read or decrypt this content - it's just a storage layer.
 /// All encryption/decryption is handled client-side using Signal Protocol.
+/// 
+/// Signal produces one ciphertext per destination device, sent in `device_contents`.
+/// `content_data` alone stores a single ciphertext readable by every device (older clients).
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EncryptedContentRequest {
     pub message_id: Uuid,
-    pub content_data: String, // Base64 encoded encrypted content
+    pub content_data: Option<String>, // Base64 encoded encrypted content
     pub content_hash: Option<String>, // SHA-256 hash for integrity
     pub expires_at: Option<DateTime<Utc>>, // Optional expiration
+    #[serde(default)]
+    pub device_contents: Vec<DeviceEncryptedContent>,
+}
+
+/// Ciphertext for one device of the recipient, or one of the sender's other devices
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceEncryptedContent {
+    pub device_id: Uuid,
+    pub content_data: String, // Base64 encoded encrypted content
+    pub content_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct EncryptedContent {
+    pub message_id: Uuid,
+    pub device_id: Option<Uuid>,
+    pub content_data: Vec<u8>,
+    pub content_hash: Option<String>,
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EncryptedContentResponse {
     pub message_id: Uuid,
+    pub device_id: Option<Uuid>, // None for a ciphertext shared by every device
     pub content_data: String, // Base64 encoded encrypted content
     pub content_hash: Option<String>,
     pub created_at: DateTime<Utc>,
 }
 
+/// Public keys of a device as stored in the directory
+#[derive(Debug, Clone, FromRow)]
+pub struct DeviceKeys {
+    pub device_id: Uuid,
+    pub user_id: Uuid,
+    pub registration_id: i32,
+    pub identity_key: Vec<u8>,
+    pub signed_prekey_id: i32,
+    pub signed_prekey: Vec<u8>,
+    pub signed_prekey_signature: Vec<u8>,
+}
+
+/// Public keys published by a device (Signal X3DH), keys are Base64 encoded
+/// 
+/// SECURITY: Only public keys and the signature of the signed prekey are sent, never private keys.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadKeysRequest {
+    pub registration_id: i32,
+    pub identity_key: String,
+    pub signed_prekey: SignedPrekey,
+    #[serde(default)]
+    pub one_time_prekeys: Vec<OneTimePrekey>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedPrekey {
+    pub key_id: i32,
+    pub public_key: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OneTimePrekey {
+    pub key_id: i32,
+    pub public_key: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadOneTimePrekeysRequest {
+    pub one_time_prekeys: Vec<OneTimePrekey>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrekeyCountResponse {
+    pub one_time_prekeys: i64,
+}
+
+/// What a device needs to start a session with every device of a user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrekeyBundleResponse {
+    pub user_id: Uuid,
+    pub devices: Vec<DevicePrekeyBundle>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DevicePrekeyBundle {
+    pub device_id: Uuid,
+    pub registration_id: i32,
+    pub identity_key: String,
+    pub signed_prekey: SignedPrekey,
+    /// `None` once the device ran out of one-time prekeys, X3DH then works without one
+    pub one_time_prekey: Option<OneTimePrekey>,
+}
+
+/// Entry of the key transparency log, chained to the previous entry of the same user
+#[derive(Debug, Clone, FromRow)]
+pub struct IdentityKeyLogEntry {
+    pub user_id: Uuid,
+    pub log_index: i64,
+    pub device_id: Uuid,
+    pub identity_key: Vec<u8>,
+    pub previous_hash: Vec<u8>,
+    pub entry_hash: Vec<u8>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Log entry as returned to clients, keys and hashes are Base64 encoded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdentityKeyLogEntryResponse {
+    pub log_index: i64,
+    pub device_id: Uuid,
+    pub identity_key: String,
+    pub previous_hash: String,
+    pub entry_hash: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<IdentityKeyLogEntry> for IdentityKeyLogEntryResponse {
+    fn from(entry: IdentityKeyLogEntry) -> Self {
+        use base64::{engine::general_purpose, Engine as _};
+        IdentityKeyLogEntryResponse {
+            log_index: entry.log_index,
+            device_id: entry.device_id,
+            identity_key: general_purpose::STANDARD.encode(&entry.identity_key),
+            previous_hash: general_purpose::STANDARD.encode(&entry.previous_hash),
+            entry_hash: general_purpose::STANDARD.encode(&entry.entry_hash),
+            created_at: entry.created_at,
+        }
+    }
+}
+
+/// Proof that an identity key is in the log of a user
+/// 
+/// Hashing forward from `entry` through `following` gives `head_hash`, the current head of the log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InclusionProofResponse {
+    pub user_id: Uuid,
+    pub entry: IdentityKeyLogEntryResponse,
+    pub following: Vec<IdentityKeyLogEntryResponse>,
+    pub head_index: i64,
+    pub head_hash: String,
+}
+
+// Sealed sender models
+
+/// Delivery token of a user, derived by clients from the profile key they share with contacts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnidentifiedAccessKeyRequest {
+    pub access_key: String, // Base64, 16 bytes
+}
+
+/// Message sent without authentication, the sender is only named inside the envelopes
+/// 
+/// Authorized by the recipient's delivery token in the `Unidentified-Access-Key` header.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedMessageRequest {
+    pub recipient_id: Uuid,
+    pub envelopes: Vec<SealedEnvelope>,
+}
+
+/// Envelope for one device of the recipient
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedEnvelope {
+    pub device_id: Uuid,
+    pub content: String, // Base64 encoded sealed envelope
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct SealedMessage {
+    pub id: Uuid,
+    pub seq: i64,
+    pub device_id: Uuid,
+    pub envelope: Vec<u8>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Message {
+    /// Delivery state as shown to the sender: 'sent', 'delivered' or 'read'
+    pub fn status(&self) -> &'static str {
+        if self.is_read {
+            "read"
+        } else if self.delivered_at.is_some() {
+            "delivered"
+        } else {
+            "sent"
+        }
+    }
+}
+
 impl From<Message> for MessageResponse {
     fn from(message: Message) -> Self {
         MessageResponse {
+            status: message.status().to_string(),
             id: message.id,
             conversation_id: message.conversation_id,
             sender_id: message.sender_id,
@@ -137,6 +566,109 @@ impl From<Message> for MessageResponse {
             timestamp: message.timestamp,
             session_id: message.session_id,
             is_read: message.is_read,
+            delivered_at: message.delivered_at,
+            read_at: message.read_at,
+            client_message_id: message.client_message_id,
+        }
+    }
+}
+
+/// Position in a list ordered by (timestamp, id)
+/// 
+/// Sent to clients as an opaque string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub timestamp: DateTime<Utc>,
+    pub id: Uuid,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        use base64::{engine::general_purpose, Engine as _};
+        general_purpose::URL_SAFE_NO_PAD
+            .encode(format!("{}|{}", self.timestamp.timestamp_micros(), self.id))
+    }
+    
+    pub fn decode(cursor: &str) -> Option<Self> {
+        use base64::{engine::general_purpose, Engine as _};
+        let raw = general_purpose::URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        let raw = String::from_utf8(raw).ok()?;
+        let (micros, id) = raw.split_once('|')?;
+        Some(Cursor {
+            timestamp: DateTime::from_timestamp_micros(micros.parse().ok()?)?,
+            id: Uuid::parse_str(id).ok()?,
+        })
+    }
+}
+
+/// Page of a history list, newest items first
+/// 
+/// `has_more` tells whether more items exist in the requested direction:
+/// older ones by default or with `before`, newer ones with `after`.
+/// Pass `oldest_cursor` as `before` to scroll back, `newest_cursor` as `after` to catch up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaginatedResponse<T> {
+    pub items: Vec<T>,
+    pub has_more: bool,
+    pub oldest_cursor: Option<String>,
+    pub newest_cursor: Option<String>,
+}
+
+impl<T> PaginatedResponse<T> {
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> PaginatedResponse<U> {
+        PaginatedResponse {
+            items: self.items.into_iter().map(f).collect(),
+            has_more: self.has_more,
+            oldest_cursor: self.oldest_cursor,
+            newest_cursor: self.newest_cursor,
+        }
+    }
+}
+
+/// Validated pagination parameters
+#[derive(Debug, Clone)]
+pub struct PageRequest {
+    pub limit: i64,
+    pub before: Option<Cursor>,
+    pub after: Option<Cursor>,
+}
+
+impl PageRequest {
+    /// Reading forward (towards newer items) only when `after` is used alone
+    pub fn is_forward(&self) -> bool {
+        self.before.is_none() && self.after.is_some()
+    }
+    
+    pub fn cursor(&self) -> Option<Cursor> {
+        self.before.or(self.after)
+    }
+    
+    /// SQL ordering and comparison operator for the (timestamp, id) row comparison
+    pub fn sql_order(&self) -> (&'static str, &'static str) {
+        if self.is_forward() {
+            ("ASC", ">")
+        } else {
+            ("DESC", "<")
+        }
+    }
+    
+    /// Build the page from rows fetched with `LIMIT limit + 1`
+    pub fn build_page<T>(
+        &self,
+        mut rows: Vec<T>,
+        cursor_of: impl Fn(&T) -> Cursor,
+    ) -> PaginatedResponse<T> {
+        let has_more = rows.len() as i64 > self.limit;
+        rows.truncate(self.limit as usize);
+        if self.is_forward() {
+            rows.reverse();
+        }
+        
+        PaginatedResponse {
+            oldest_cursor: rows.last().map(|row| cursor_of(row).encode()),
+            newest_cursor: rows.first().map(|row| cursor_of(row).encode()),
+            has_more,
+            items: rows,
         }
     }
 }
@@ -243,11 +775,21 @@ pub struct UpdatePresenceRequest {
     pub status: String, // 'online', 'offline', 'away'
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceSettings {
+    pub last_seen_visibility: String, // 'everyone', 'contacts', 'nobody'
+}
+
+/// Typing state of a user in a conversation or channel
+/// 
+/// `user_id` and `timestamp` are set by the server, values sent by clients are ignored.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TypingIndicator {
+    #[serde(default)]
     pub user_id: Uuid,
     pub conversation_id: Uuid,
     pub is_typing: bool,
+    #[serde(default)]
     pub timestamp: DateTime<Utc>,
 }
 
@@ -310,6 +852,94 @@ pub enum WebSocketMessage {
     Error {
         payload: ErrorPayload,
     },
+    #[serde(rename = "message_ack")]
+    MessageAck {
+        payload: MessageAckPayload,
+    },
+    #[serde(rename = "message_status")]
+    MessageStatus {
+        payload: MessageStatusPayload,
+    },
+    #[serde(rename = "pending_event")]
+    PendingEvent {
+        payload: PendingEventPayload,
+    },
+    #[serde(rename = "pending_event_ack")]
+    PendingEventAck {
+        payload: PendingEventAckPayload,
+    },
+    #[serde(rename = "contact_deleted")]
+    ContactDeleted {
+        payload: ContactDeletedPayload,
+    },
+    #[serde(rename = "prekeys_low")]
+    PrekeysLow {
+        payload: PrekeysLowPayload,
+    },
+    #[serde(rename = "identity_changed")]
+    IdentityChanged {
+        payload: IdentityChangedPayload,
+    },
+    #[serde(rename = "sealed_message")]
+    SealedMessage {
+        payload: SealedMessagePayload,
+    },
+    #[serde(rename = "sealed_message_ack")]
+    SealedMessageAck {
+        payload: SealedMessageAckPayload,
+    },
+}
+
+/// Sealed sender envelope pushed to its destination device, to be acknowledged with `sealed_message_ack`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedMessagePayload {
+    pub id: Uuid,
+    pub device_id: Uuid,
+    pub envelope: String, // Base64 encoded sealed envelope
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedMessageAckPayload {
+    pub message_ids: Vec<Uuid>,
+}
+
+/// Pushed to a user when one of their devices is running out of one-time prekeys
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrekeysLowPayload {
+    pub device_id: Uuid,
+    pub remaining: i64,
+}
+
+/// Pushed to everyone sharing a conversation with a user whose device published a new identity key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdentityChangedPayload {
+    pub user_id: Uuid,
+    pub device_id: Uuid,
+    pub identity_key: String, // Base64
+    pub log_index: i64,
+    pub entry_hash: String, // Base64, head of the user's key transparency log
+}
+
+/// Pushed to the contacts of an erased account so they drop it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContactDeletedPayload {
+    pub user_id: Uuid,
+}
+
+/// Sent by the recipient's device once message metadata has been received
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageAckPayload {
+    pub message_ids: Vec<Uuid>,
+}
+
+/// Delivery state change pushed to the sender of a message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageStatusPayload {
+    pub message_id: Uuid,
+    pub conversation_id: Uuid,
+    pub status: String, // 'delivered' or 'read'
+    pub timestamp: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -321,6 +951,33 @@ pub struct HeartbeatPayload {
 pub struct ErrorPayload {
     pub message: String,
     pub code: Option<String>,
+    /// Seconds to wait before retrying, set when the frame was rate limited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+/// Event queued while the recipient was offline
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct PendingEvent {
+    pub id: Uuid,
+    pub seq: i64,
+    pub recipient_id: Uuid,
+    pub event: String, // Serialized WebSocketMessage
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Replay of a queued event, to be acknowledged with `pending_event_ack`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingEventPayload {
+    pub event_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub event: Box<WebSocketMessage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingEventAckPayload {
+    pub event_ids: Vec<Uuid>,
 }
 
 // Stories models
@@ -0,0 +1,347 @@
+use anyhow::Context;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use tokio::sync::RwLock;
+
+use crate::config::OidcConfig;
+use crate::services::AuthService;
+
+/// The part of the provider metadata (`/.well-known/openid-configuration`) we use
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Identity asserted by the provider in the ID token
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub email: Option<String>,
+    /// Some providers send `"true"` as a string
+    #[serde(default)]
+    email_verified: Option<serde_json::Value>,
+    pub name: Option<String>,
+    pub preferred_username: Option<String>,
+    nonce: Option<String>,
+}
+
+impl IdTokenClaims {
+    pub fn is_email_verified(&self) -> bool {
+        match &self.email_verified {
+            Some(serde_json::Value::Bool(verified)) => *verified,
+            Some(serde_json::Value::String(verified)) => verified == "true",
+            _ => false,
+        }
+    }
+}
+
+/// Authorization request to redirect the user to, and the secrets to keep until the callback
+pub struct AuthorizationRequest {
+    pub url: String,
+    pub state: String,
+    pub code_verifier: String,
+    pub nonce: String,
+}
+
+/// OpenID Connect relying party: authorization code flow with PKCE
+pub struct OidcClient {
+    config: OidcConfig,
+    http: reqwest::Client,
+    provider: RwLock<Option<ProviderMetadata>>,
+    jwks: RwLock<Option<JwkSet>>,
+}
+
+impl OidcClient {
+    pub fn new(config: OidcConfig) -> anyhow::Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()
+            .context("Failed to build HTTP client")?;
+        
+        Ok(OidcClient {
+            config,
+            http,
+            provider: RwLock::new(None),
+            jwks: RwLock::new(None),
+        })
+    }
+    
+    /// Provider metadata, discovered on first use so the backend starts even if the provider is down
+    async fn provider(&self) -> anyhow::Result<ProviderMetadata> {
+        if let Some(provider) = self.provider.read().await.as_ref() {
+            return Ok(provider.clone());
+        }
+        
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer.trim_end_matches('/')
+        );
+        let provider: ProviderMetadata = self
+            .http
+            .get(&url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context("Failed to fetch OIDC provider metadata")?
+            .json()
+            .await
+            .context("Invalid OIDC provider metadata")?;
+        if provider.issuer.trim_end_matches('/') != self.config.issuer.trim_end_matches('/') {
+            anyhow::bail!("OIDC provider announces issuer {}", provider.issuer);
+        }
+        
+        *self.provider.write().await = Some(provider.clone());
+        Ok(provider)
+    }
+    
+    pub async fn authorization_request(&self) -> anyhow::Result<AuthorizationRequest> {
+        use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+        use sha2::{Digest, Sha256};
+        
+        let provider = self.provider().await?;
+        let state = AuthService::generate_opaque_token();
+        let nonce = AuthService::generate_opaque_token();
+        let code_verifier = AuthService::generate_opaque_token();
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+        
+        let url = reqwest::Url::parse_with_params(
+            &provider.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", self.config.client_id.as_str()),
+                ("redirect_uri", self.config.redirect_uri.as_str()),
+                ("scope", self.config.scopes.as_str()),
+                ("state", state.as_str()),
+                ("nonce", nonce.as_str()),
+                ("code_challenge", code_challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .context("Invalid authorization endpoint")?;
+        
+        Ok(AuthorizationRequest {
+            url: url.to_string(),
+            state,
+            code_verifier,
+            nonce,
+        })
+    }
+    
+    /// Redeem the authorization code and return the validated ID token claims
+    pub async fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> anyhow::Result<IdTokenClaims> {
+        let provider = self.provider().await?;
+        
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.config.redirect_uri.as_str()),
+            ("client_id", self.config.client_id.as_str()),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret.as_str()));
+        }
+        
+        let tokens: TokenResponse = self
+            .http
+            .post(&provider.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context("OIDC token request failed")?
+            .json()
+            .await
+            .context("Invalid OIDC token response")?;
+        
+        self.validate_id_token(&provider, &tokens.id_token, nonce).await
+    }
+    
+    async fn validate_id_token(
+        &self,
+        provider: &ProviderMetadata,
+        id_token: &str,
+        nonce: &str,
+    ) -> anyhow::Result<IdTokenClaims> {
+        let header = decode_header(id_token).context("Invalid ID token header")?;
+        // Only asymmetric signatures, an HMAC would be keyed with our own client secret
+        if !matches!(
+            header.alg,
+            Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512
+                | Algorithm::ES256
+                | Algorithm::ES384
+                | Algorithm::EdDSA
+        ) {
+            anyhow::bail!("Unsupported ID token algorithm {:?}", header.alg);
+        }
+        let key = self.decoding_key(provider, header.kid.as_deref()).await?;
+        
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&provider.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        let claims = decode::<IdTokenClaims>(id_token, &key, &validation)
+            .context("Invalid ID token")?
+            .claims;
+        
+        if claims.nonce.as_deref() != Some(nonce) {
+            anyhow::bail!("ID token nonce mismatch");
+        }
+        Ok(claims)
+    }
+    
+    /// Key of the provider that signed a token, the key set is fetched again for unknown keys
+    async fn decoding_key(&self, provider: &ProviderMetadata, kid: Option<&str>) -> anyhow::Result<DecodingKey> {
+        let find = |jwks: &JwkSet| match kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            None => jwks.keys.first().cloned(),
+        };
+        
+        if let Some(jwk) = self.jwks.read().await.as_ref().and_then(find) {
+            return DecodingKey::from_jwk(&jwk).context("Invalid provider key");
+        }
+        
+        let jwks: JwkSet = self
+            .http
+            .get(&provider.jwks_uri)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context("Failed to fetch provider keys")?
+            .json()
+            .await
+            .context("Invalid provider key set")?;
+        let jwk = find(&jwks).context("Unknown provider signing key")?;
+        *self.jwks.write().await = Some(jwks);
+        
+        DecodingKey::from_jwk(&jwk).context("Invalid provider key")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jwt::JwtKeys;
+    use axum::{extract::Form, routing::{get, post}, Json, Router};
+    use ed25519_dalek::pkcs8::{spki::der::pem::LineEnding, EncodePrivateKey};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    
+    /// Minimal identity provider: discovery, keys, and a token endpoint checking PKCE
+    async fn start_mock_idp(keys: JwtKeys, nonce: Arc<Mutex<Option<String>>>, challenge: Arc<Mutex<Option<String>>>) -> String {
+        use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+        use sha2::{Digest, Sha256};
+        
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let keys = Arc::new(keys);
+        
+        let discovery = {
+            let issuer = issuer.clone();
+            move || async move {
+                Json(serde_json::json!({
+                    "issuer": issuer,
+                    "authorization_endpoint": format!("{}/authorize", issuer),
+                    "token_endpoint": format!("{}/token", issuer),
+                    "jwks_uri": format!("{}/jwks", issuer),
+                }))
+            }
+        };
+        let jwks = {
+            let keys = keys.clone();
+            move || async move { Json(keys.jwks().clone()) }
+        };
+        let token = {
+            let issuer = issuer.clone();
+            move |Form(form): Form<HashMap<String, String>>| async move {
+                let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+                let expected = challenge.lock().unwrap().clone().unwrap();
+                if URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) != expected {
+                    return Err(axum::http::StatusCode::BAD_REQUEST);
+                }
+                let id_token = keys
+                    .encode(&serde_json::json!({
+                        "iss": issuer,
+                        "sub": "employee-42",
+                        "aud": "kisse",
+                        "exp": chrono::Utc::now().timestamp() + 60,
+                        "email": "jane@corp.example",
+                        "email_verified": true,
+                        "nonce": nonce.lock().unwrap().clone(),
+                    }))
+                    .unwrap();
+                Ok(Json(serde_json::json!({ "id_token": id_token, "token_type": "Bearer" })))
+            }
+        };
+        
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(jwks))
+            .route("/token", post(token));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        issuer
+    }
+    
+    #[tokio::test]
+    async fn test_authorization_code_flow_with_mock_idp() {
+        let dir = std::env::temp_dir().join(format!("kisse-idp-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = ed25519_dalek::SigningKey::from_bytes(&[3; 32]);
+        std::fs::write(dir.join("idp.pem"), key.to_pkcs8_pem(LineEnding::LF).unwrap().as_bytes()).unwrap();
+        let keys = JwtKeys::from_dir(&dir, None).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        
+        let nonce = Arc::new(Mutex::new(None));
+        let challenge = Arc::new(Mutex::new(None));
+        let issuer = start_mock_idp(keys, nonce.clone(), challenge.clone()).await;
+        let client = OidcClient::new(OidcConfig {
+            issuer,
+            client_id: "kisse".to_string(),
+            client_secret: None,
+            redirect_uri: "kisse://sso".to_string(),
+            scopes: "openid email".to_string(),
+            auto_provision: true,
+        })
+        .unwrap();
+        
+        let request = client.authorization_request().await.unwrap();
+        let url = reqwest::Url::parse(&request.url).unwrap();
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["state"], request.state);
+        assert_eq!(params["code_challenge_method"], "S256");
+        *challenge.lock().unwrap() = Some(params["code_challenge"].clone());
+        *nonce.lock().unwrap() = Some(params["nonce"].clone());
+        
+        // A replayed ID token from another authorization request is refused
+        assert!(client.exchange_code("code", &request.code_verifier, "other-nonce").await.is_err());
+        // So is a code redeemed without the PKCE verifier
+        assert!(client.exchange_code("code", "wrong-verifier", &request.nonce).await.is_err());
+        
+        let claims = client
+            .exchange_code("code", &request.code_verifier, &request.nonce)
+            .await
+            .unwrap();
+        assert_eq!(claims.sub, "employee-42");
+        assert_eq!(claims.email.as_deref(), Some("jane@corp.example"));
+        assert!(claims.is_email_verified());
+    }
+}
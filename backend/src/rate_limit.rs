@@ -0,0 +1,311 @@
+use axum::{
+    async_trait,
+    extract::Request,
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::client_info::ClientInfo;
+use crate::AppState;
+
+/// Token bucket size and the time it takes to refill completely
+/// 
+/// Written `<requests>/<seconds>` in the configuration, e.g. `10/60`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub burst: u32,
+    pub period: Duration,
+}
+
+impl Quota {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (burst, seconds) = value.trim().split_once('/')?;
+        let burst: u32 = burst.trim().parse().ok()?;
+        let seconds: u64 = seconds.trim().parse().ok()?;
+        if burst == 0 || seconds == 0 {
+            return None;
+        }
+        Some(Quota {
+            burst,
+            period: Duration::from_secs(seconds),
+        })
+    }
+    
+    /// Tokens added back per second
+    fn refill_rate(&self) -> f64 {
+        self.burst as f64 / self.period.as_secs_f64()
+    }
+    
+    /// Wait before the next token when `tokens` are left in the bucket
+    fn retry_after(&self, tokens: f64) -> Duration {
+        Duration::from_secs_f64(((1.0 - tokens) / self.refill_rate()).max(0.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitDecision {
+    Allowed,
+    Limited { retry_after: Duration },
+}
+
+/// Where token buckets live
+/// 
+/// The in-process store is enough for a single instance, several instances
+/// behind the load balancer must share their buckets (see `PostgresStore`).
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Take one token from the bucket identified by `key`
+    async fn take(&self, key: &str, quota: Quota) -> anyhow::Result<RateLimitDecision>;
+    
+    /// Forget buckets that have been full for a while
+    async fn cleanup(&self) -> anyhow::Result<()>;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    period: Duration,
+}
+
+#[derive(Default)]
+pub struct InMemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[async_trait]
+impl RateLimitStore for InMemoryStore {
+    async fn take(&self, key: &str, quota: Quota) -> anyhow::Result<RateLimitDecision> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: quota.burst as f64,
+            updated_at: now,
+            period: quota.period,
+        });
+        
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * quota.refill_rate()).min(quota.burst as f64);
+        bucket.updated_at = now;
+        bucket.period = quota.period;
+        
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(RateLimitDecision::Allowed)
+        } else {
+            Ok(RateLimitDecision::Limited {
+                retry_after: quota.retry_after(bucket.tokens),
+            })
+        }
+    }
+    
+    async fn cleanup(&self) -> anyhow::Result<()> {
+        let now = Instant::now();
+        // A bucket untouched for a whole period is full again, same as a missing one
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| now.duration_since(bucket.updated_at) < bucket.period);
+        Ok(())
+    }
+}
+
+/// Buckets shared by every backend instance through the database
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+impl PostgresStore {
+    pub fn new(pool: PgPool) -> Self {
+        PostgresStore { pool }
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for PostgresStore {
+    async fn take(&self, key: &str, quota: Quota) -> anyhow::Result<RateLimitDecision> {
+        use anyhow::Context;
+        
+        // Refill and take a token in a single statement so concurrent requests can't both win
+        let (allowed, tokens): (bool, f64) = sqlx::query_as(
+            r#"
+            INSERT INTO rate_limit_buckets AS b (key, tokens, allowed, updated_at, expires_at)
+            VALUES ($1, $2 - 1, TRUE, NOW(), NOW() + $4 * INTERVAL '1 second')
+            ON CONFLICT (key) DO UPDATE SET
+                allowed = LEAST($2, b.tokens + EXTRACT(EPOCH FROM NOW() - b.updated_at) * $3) >= 1,
+                tokens = LEAST($2, b.tokens + EXTRACT(EPOCH FROM NOW() - b.updated_at) * $3)
+                    - CASE
+                        WHEN LEAST($2, b.tokens + EXTRACT(EPOCH FROM NOW() - b.updated_at) * $3) >= 1
+                        THEN 1
+                        ELSE 0
+                    END,
+                updated_at = NOW(),
+                expires_at = NOW() + $4 * INTERVAL '1 second'
+            RETURNING allowed, tokens
+            "#,
+        )
+        .bind(key)
+        .bind(quota.burst as f64)
+        .bind(quota.refill_rate())
+        .bind(quota.period.as_secs_f64())
+        .fetch_one(&self.pool)
+        .await
+        .context("Failed to take rate limit token")?;
+        
+        if allowed {
+            Ok(RateLimitDecision::Allowed)
+        } else {
+            Ok(RateLimitDecision::Limited {
+                retry_after: quota.retry_after(tokens),
+            })
+        }
+    }
+    
+    async fn cleanup(&self) -> anyhow::Result<()> {
+        use anyhow::Context;
+        
+        sqlx::query("DELETE FROM rate_limit_buckets WHERE expires_at < NOW()")
+            .execute(&self.pool)
+            .await
+            .context("Failed to cleanup rate limit buckets")?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    pub fn new(store: Arc<dyn RateLimitStore>) -> Self {
+        RateLimiter { store }
+    }
+    
+    /// Take a token for `subject` (IP address or user id) in the given scope
+    /// 
+    /// Fails open: a broken store must not take the whole API down.
+    pub async fn check(&self, scope: &str, subject: &str, quota: Quota) -> RateLimitDecision {
+        let key = format!("{}:{}", scope, subject);
+        match self.store.take(&key, quota).await {
+            Ok(RateLimitDecision::Limited { retry_after }) => {
+                tracing::warn!("🚦 Rate limit exceeded for {}", key);
+                RateLimitDecision::Limited { retry_after }
+            }
+            Ok(decision) => decision,
+            Err(e) => {
+                tracing::error!("Rate limit store failure: {:?}", e);
+                RateLimitDecision::Allowed
+            }
+        }
+    }
+    
+    pub async fn cleanup(&self) -> anyhow::Result<()> {
+        self.store.cleanup().await
+    }
+}
+
+/// Seconds to announce in `Retry-After`, never 0
+pub fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs_f64().ceil().max(1.0) as u64
+}
+
+pub fn too_many_requests(retry_after: Duration) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after_secs(retry_after).to_string())],
+        "Too Many Requests",
+    )
+        .into_response()
+}
+
+/// Limit unauthenticated routes (login, register, refresh) by client IP
+pub async fn ip_rate_limit_middleware(client: ClientInfo, request: Request, next: Next) -> Response {
+    let Some(state) = request.extensions().get::<Arc<AppState>>().cloned() else {
+        return next.run(request).await;
+    };
+    let ip = client.ip.unwrap_or_else(|| "unknown".to_string());
+    
+    // Sealed sender messages and public files are public too, but far more frequent than logins:
+    // they must neither be held to the login budget nor use it up
+    let path = request.uri().path();
+    let (scope, quota) = if path == "/api/messages/sealed" {
+        ("sealed_message", state.config.rate_limit.sealed_message)
+    } else if path.starts_with("/api/avatars/") || path == "/api/exports/download" {
+        ("public_file", state.config.rate_limit.api)
+    } else {
+        ("auth", state.config.rate_limit.auth)
+    };
+    
+    match state.rate_limiter.check(scope, &ip, quota).await {
+        RateLimitDecision::Allowed => next.run(request).await,
+        RateLimitDecision::Limited { retry_after } => too_many_requests(retry_after),
+    }
+}
+
+/// Limit authenticated routes by user id, runs after `auth_middleware`
+pub async fn user_rate_limit_middleware(request: Request, next: Next) -> Response {
+    let (Some(state), Some(user_id)) = (
+        request.extensions().get::<Arc<AppState>>().cloned(),
+        request.extensions().get::<Uuid>().copied(),
+    ) else {
+        return next.run(request).await;
+    };
+    
+    // User lookups are cheap to abuse for account enumeration, they get their own budget
+    let path = request.uri().path();
+    let (scope, quota) = if path.starts_with("/api/users/search")
+        || path.starts_with("/api/users/find-by-email")
+    {
+        ("search", state.config.rate_limit.search)
+    } else {
+        ("api", state.config.rate_limit.api)
+    };
+    
+    match state.rate_limiter.check(scope, &user_id.to_string(), quota).await {
+        RateLimitDecision::Allowed => next.run(request).await,
+        RateLimitDecision::Limited { retry_after } => too_many_requests(retry_after),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_quota_parse() {
+        assert_eq!(
+            Quota::parse("10/60"),
+            Some(Quota {
+                burst: 10,
+                period: Duration::from_secs(60)
+            })
+        );
+        assert_eq!(Quota::parse("0/60"), None);
+        assert_eq!(Quota::parse("10"), None);
+    }
+    
+    #[tokio::test]
+    async fn test_in_memory_bucket_limits_burst() {
+        let store = InMemoryStore::default();
+        let quota = Quota::parse("2/60").unwrap();
+        
+        assert_eq!(store.take("k", quota).await.unwrap(), RateLimitDecision::Allowed);
+        assert_eq!(store.take("k", quota).await.unwrap(), RateLimitDecision::Allowed);
+        match store.take("k", quota).await.unwrap() {
+            RateLimitDecision::Limited { retry_after } => {
+                assert!(retry_after > Duration::from_secs(25));
+                assert!(retry_after <= Duration::from_secs(30));
+            }
+            RateLimitDecision::Allowed => panic!("third request should be limited"),
+        }
+        
+        // Buckets are independent
+        assert_eq!(store.take("other", quota).await.unwrap(), RateLimitDecision::Allowed);
+    }
+}
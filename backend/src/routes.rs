@@ -1,27 +1,67 @@
 use axum::{
-    extract::{Extension, Request},
+    extract::Request,
     http::StatusCode,
     middleware::Next,
     response::Response,
-    routing::{get, post},
+    routing::{delete, get, post, put},
     Router,
 };
 
 use crate::handlers;
-use crate::services::AuthService;
+use crate::models::{DeviceId, SessionId};
+use crate::rate_limit::{ip_rate_limit_middleware, user_rate_limit_middleware};
+use crate::services::{AuthService, SessionService};
 use crate::AppState;
 
 pub fn create_api_routes() -> Router {
-    // NOTE: Rate limiting sera implémenté avec un middleware personnalisé
-    // ou une bibliothèque externe (ex: tower-governor avec Redis en production)
-    // Pour l'instant, la validation des données est la priorité
-    
+    // Rate limiting: par IP pour les routes publiques, par utilisateur pour les routes protégées
     let public_routes = Router::new()
         .route("/auth/register", post(handlers::register))
-        .route("/auth/login", post(handlers::login));
+        .route("/auth/login", post(handlers::login))
+        .route("/auth/refresh", post(handlers::refresh_token))
+        .route("/auth/unlock", post(handlers::unlock_account))
+        .route("/auth/mfa/verify", post(handlers::verify_mfa))
+        .route("/auth/password/forgot", post(handlers::forgot_password))
+        .route("/auth/password/reset", post(handlers::reset_password))
+        .route("/auth/verify-email", post(handlers::verify_email))
+        .route("/auth/oidc/authorize", get(handlers::oidc_authorize))
+        .route("/auth/oidc/callback", post(handlers::oidc_callback))
+        .route("/exports/download", get(handlers::download_data_export))
+        .route("/avatars/:id", get(handlers::get_avatar))
+        .route("/messages/sealed", post(handlers::send_sealed_message))
+        .layer(axum::middleware::from_fn(ip_rate_limit_middleware));
     
     let protected_routes = Router::new()
         .route("/auth/me", get(handlers::get_me))
+        .route("/auth/logout", post(handlers::logout))
+        .route("/auth/sessions", get(handlers::get_sessions))
+        .route("/auth/sessions/:id", delete(handlers::revoke_session))
+        .route("/auth/security-events", get(handlers::get_security_events))
+        .route("/auth/password", post(handlers::change_password))
+        .route("/auth/verify-email/resend", post(handlers::resend_email_verification))
+        .route("/auth/mfa", get(handlers::get_mfa_status))
+        .route("/auth/mfa/totp/setup", post(handlers::setup_totp))
+        .route("/auth/mfa/totp/confirm", post(handlers::confirm_totp))
+        .route("/auth/mfa/disable", post(handlers::disable_mfa))
+        .route("/auth/mfa/recovery-codes", post(handlers::regenerate_recovery_codes))
+        .route(
+            "/users/me",
+            delete(handlers::delete_account).patch(handlers::update_profile),
+        )
+        .route(
+            "/users/me/avatar",
+            // The size limit is enforced by the handler, from the configuration
+            put(handlers::upload_avatar)
+                .delete(handlers::delete_avatar)
+                .layer(axum::extract::DefaultBodyLimit::disable()),
+        )
+        .route("/users/me/deletion/cancel", post(handlers::cancel_account_deletion))
+        .route(
+            "/users/me/unidentified-access-key",
+            put(handlers::set_unidentified_access_key).delete(handlers::delete_unidentified_access_key),
+        )
+        .route("/users/me/export", post(handlers::request_data_export))
+        .route("/users/me/exports", get(handlers::get_data_exports))
         .route("/users/search", get(handlers::search_users))
         .route("/users/find-by-email", get(handlers::find_user_by_email))
         .route("/conversations", get(handlers::get_conversations))
@@ -30,6 +70,11 @@ pub fn create_api_routes() -> Router {
         .route("/messages/:id/read", post(handlers::mark_message_read))
         .route("/messages/:id/content", post(handlers::store_encrypted_content))
         .route("/messages/:id/content", get(handlers::get_encrypted_content))
+        .route("/keys", put(handlers::upload_keys))
+        .route("/keys/one-time", post(handlers::upload_one_time_prekeys))
+        .route("/keys/count", get(handlers::get_prekey_count))
+        .route("/keys/:user_id", get(handlers::get_prekey_bundle))
+        .route("/keys/:user_id/proof", get(handlers::get_identity_key_proof))
         .route("/stories", get(handlers::get_stories))
         .route("/stories", post(handlers::create_story))
         .route("/stories/:id/view", post(handlers::view_story))
@@ -40,7 +85,13 @@ pub fn create_api_routes() -> Router {
         .route("/calls/history", get(handlers::get_call_history))
         .route("/calls/active", get(handlers::get_active_call))
         .route("/presence", post(handlers::update_presence))
+        .route(
+            "/presence/settings",
+            get(handlers::get_presence_settings).put(handlers::update_presence_settings),
+        )
         .route("/presence/:id", get(handlers::get_presence))
+        // Layers run bottom-up: authenticate first so the limiter knows the user
+        .layer(axum::middleware::from_fn(user_rate_limit_middleware))
         .layer(axum::middleware::from_fn(auth_middleware));
     
     Router::new()
@@ -52,12 +103,21 @@ async fn auth_middleware(
     mut request: Request,
     next: Next,
 ) -> Result<Response, StatusCode> {
-    use uuid::Uuid;
-    
     // Skip auth for public routes
     let path = request.uri().path().to_string(); // Clone the path to avoid borrow issues
     // Les routes sont montées sous /api, donc le path complet est /api/auth/...
-    if path.starts_with("/api/auth/register") || path.starts_with("/api/auth/login") {
+    if path.starts_with("/api/auth/register")
+        || path.starts_with("/api/auth/login")
+        || path.starts_with("/api/auth/refresh")
+        || path.starts_with("/api/auth/unlock")
+        || path.starts_with("/api/auth/mfa/verify")
+        || path.starts_with("/api/auth/password/")
+        || path == "/api/auth/verify-email"
+        || path.starts_with("/api/auth/oidc/")
+        || path == "/api/exports/download"
+        || path.starts_with("/api/avatars/")
+        || path == "/api/messages/sealed"
+    {
         return Ok(next.run(request).await);
     }
     
@@ -71,25 +131,41 @@ async fn auth_middleware(
         let state = request
             .extensions()
             .get::<std::sync::Arc<AppState>>()
+            .cloned()
             .ok_or_else(|| {
                 tracing::error!("AppState not found in extensions");
                 StatusCode::INTERNAL_SERVER_ERROR
             })?;
         
-        match AuthService::verify_token(token, &state.config.jwt_secret) {
-            Ok(user_id) => {
-                // In Axum 0.7, insert the value directly (not the Extension wrapper)
-                // The Extension extractor will wrap it automatically
-                request.extensions_mut().insert(user_id);
-                tracing::info!("✅ Authenticated user: {} for path: {}", user_id, path);
-                return Ok(next.run(request).await);
-            }
+        let claims = match AuthService::verify_token(token, &state.jwt_keys) {
+            Ok(claims) => claims,
             Err(e) => {
                 tracing::warn!("❌ Token verification failed for path {}: {:?}", path, e);
                 tracing::warn!("   Token (first 20 chars): {}", &token[..token.len().min(20)]);
                 return Err(StatusCode::UNAUTHORIZED);
             }
+        };
+        let user_id = claims.user_id().map_err(|_| StatusCode::UNAUTHORIZED)?;
+        
+        // Reject tokens whose session was revoked (logout, refresh token reuse) or expired
+        let active = SessionService::is_active(state.db.pool(), claims.sid, user_id)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to check session: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        if !active {
+            tracing::warn!("❌ Session {} is no longer active for path: {}", claims.sid, path);
+            return Err(StatusCode::UNAUTHORIZED);
         }
+        
+        // In Axum 0.7, insert the value directly (not the Extension wrapper)
+        // The Extension extractor will wrap it automatically
+        request.extensions_mut().insert(user_id);
+        request.extensions_mut().insert(SessionId(claims.sid));
+        request.extensions_mut().insert(DeviceId(claims.did));
+        tracing::info!("✅ Authenticated user: {} for path: {}", user_id, path);
+        return Ok(next.run(request).await);
     }
     
     tracing::warn!("❌ No authorization header found for path: {}", path);
@@ -1,3 +1,80 @@
+use serde_json::Value;
+use std::fmt;
+
+use crate::models::WebSocketMessage;
+
+/// Longest Signal session reference accepted in a frame, in bytes
+pub const MAX_SESSION_ID_LEN: usize = 128;
+
+/// Frame types clients may send, and the only payload fields each may carry
+const INBOUND_SCHEMA: &[(&str, &[&str])] = &[
+    ("message", &["recipient_id", "message_type", "session_id", "client_message_id"]),
+    ("call_request", &["recipient_id", "call_type"]),
+    ("call_response", &["call_id", "response"]),
+    ("presence_update", &["user_id", "status", "last_seen"]),
+    ("typing_indicator", &["user_id", "conversation_id", "is_typing", "timestamp"]),
+    ("read_receipt", &["message_id", "reader_id", "read_at"]),
+    ("heartbeat", &["timestamp"]),
+    ("message_ack", &["message_ids"]),
+    ("pending_event_ack", &["event_ids"]),
+    ("sealed_message_ack", &["message_ids"]),
+];
+
+/// Event types the backend routes to clients, and the only payload fields each may carry
+const OUTBOUND_SCHEMA: &[(&str, &[&str])] = &[
+    (
+        "message_response",
+        &[
+            "id", "conversation_id", "sender_id", "recipient_id", "message_type", "timestamp",
+            "session_id", "is_read", "status", "delivered_at", "read_at", "client_message_id",
+        ],
+    ),
+    ("call_request_full", &["call_id", "caller_id", "recipient_id", "call_type", "timestamp"]),
+    ("call_response_full", &["call_id", "response", "timestamp"]),
+    ("presence_update", &["user_id", "status", "last_seen"]),
+    ("typing_indicator", &["user_id", "conversation_id", "is_typing", "timestamp"]),
+    ("heartbeat_response", &["timestamp"]),
+    ("error", &["message", "code", "retry_after"]),
+    ("message_status", &["message_id", "conversation_id", "status", "timestamp"]),
+    ("pending_event", &["event_id", "created_at", "event"]),
+    ("contact_deleted", &["user_id"]),
+    ("prekeys_low", &["device_id", "remaining"]),
+    ("identity_changed", &["user_id", "device_id", "identity_key", "log_index", "entry_hash"]),
+    // The envelope is end-to-end encrypted for the device, it is what sealed sender routes
+    ("sealed_message", &["id", "device_id", "envelope", "timestamp"]),
+];
+
+/// Payload fields with a length cap, in bytes
+const FIELD_LIMITS: &[(&str, usize)] = &[("session_id", MAX_SESSION_ID_LEN)];
+
+/// Why a frame was refused by the gateway
+/// 
+/// Only names types and fields, never values, so it can be logged and sent back safely.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GatewayRejection {
+    /// Not a JSON object made of `type` and `payload`, or a payload that doesn't parse
+    Malformed,
+    /// Unknown type, or one that only flows the other way
+    UnknownType(String),
+    UnexpectedField { frame_type: String, field: String },
+    FieldTooLong { frame_type: String, field: String, max: usize },
+}
+
+impl fmt::Display for GatewayRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GatewayRejection::Malformed => write!(f, "malformed frame"),
+            GatewayRejection::UnknownType(frame_type) => write!(f, "frame type '{}' is not accepted", frame_type),
+            GatewayRejection::UnexpectedField { frame_type, field } => {
+                write!(f, "field '{}' is not allowed in '{}' frames", field, frame_type)
+            }
+            GatewayRejection::FieldTooLong { frame_type, field, max } => {
+                write!(f, "field '{}' of '{}' frames exceeds {} bytes", field, frame_type, max)
+            }
+        }
+    }
+}
+
 /// Security and Signal Protocol Gateway Documentation
 /// 
 /// This module documents the security architecture where the backend
@@ -8,7 +85,8 @@
 /// 1. **Zero-Knowledge Gateway**: The backend never sees encrypted content
 /// 2. **Metadata Only**: Only routing metadata is stored and transmitted
 /// 3. **Client-Side Encryption**: All Signal Protocol operations happen on clients
-/// 4. **No Key Storage**: Encryption keys are never stored on the backend
+/// 4. **No Private Key Storage**: Private keys never leave the devices, the backend only
+///    publishes the public keys devices need to start Signal sessions
 /// 
 /// # Message Flow
 /// 
@@ -37,27 +115,34 @@
 ///    Protocol
 /// ```
 /// 
+/// With sealed sender, the sender is only named inside the envelope encrypted for each
+/// recipient device: the backend stores and routes the recipient device alone.
+/// 
 /// # What the Backend Stores
 /// 
 /// - Message IDs
-/// - Sender/Recipient IDs
+/// - Sender/Recipient IDs (recipient device only for sealed sender messages)
 /// - Timestamps
 /// - Message types
 /// - Session IDs (reference only)
 /// - Read receipts
+/// - Public keys of each device (identity key, signed prekey, one-time prekeys),
+///   handed out in prekey bundles so that a session can be started with anyone
+/// - An append-only, hash-chained log of every identity key published by each user's devices
+///   (key transparency), so that contacts can check the keys they are handed
+/// - Sealed sender envelopes, until their device acknowledges them, and the SHA-256 of
+///   each user's delivery token
 /// 
 /// # What the Backend Does NOT Store
 /// 
 /// - Encrypted message content
-/// - Encryption keys (public or private)
+/// - Private keys (identity, prekeys)
 /// - Signal Protocol session keys
-/// - Pre-keys
-/// - Identity keys
 /// 
 /// # Security Guarantees
 /// 
 /// 1. **Backend Blindness**: The backend cannot read messages
-/// 2. **No Key Access**: The backend has no access to encryption keys
+/// 2. **No Key Access**: The backend has no access to private or session keys
 /// 3. **Metadata Only Routing**: Only routing information is transmitted
 /// 4. **Client-Side Control**: Clients have full control over encryption
 /// 
@@ -67,31 +152,163 @@
 /// - ✅ RG8: End-to-end encryption
 /// - ✅ RG9: Content inaccessible to server
 /// - ✅ Zero-Knowledge Architecture
-
+/// 
+/// # Enforcement
+/// 
+/// Every WebSocket frame goes through the `SecurityGateway` whitelist: frames received
+/// from clients before they reach a handler, events before they are routed or queued.
+/// A frame with a field outside the whitelist (a client accidentally sending `content`,
+/// say) is rejected as a whole and the rejection is written to the `audit` log.
 pub struct SecurityGateway;
 
 impl SecurityGateway {
-    /// Verify that a message payload contains only metadata
+    /// Check a frame received from a client against the whitelist, then parse it
     /// 
-    /// This is a safety check to ensure no encrypted content
-    /// is accidentally transmitted via the gateway.
-    pub fn verify_metadata_only(_payload: &crate::models::MessageResponse) -> bool {
-        // Verify that the payload contains only metadata fields
-        // and no content fields
-        true // Always true by design - MessageResponse has no content field
+    /// Runs before any handler, so nothing outside the whitelist can be stored or routed.
+    pub fn check_inbound(text: &str) -> Result<WebSocketMessage, GatewayRejection> {
+        let frame: Value = serde_json::from_str(text).map_err(|_| GatewayRejection::Malformed)?;
+        Self::check(&frame, INBOUND_SCHEMA)?;
+        serde_json::from_value(frame).map_err(|_| GatewayRejection::Malformed)
+    }
+    
+    /// Verify that an event routed or queued by the backend contains only metadata
+    /// 
+    /// This is a safety net: an event that grew a field outside the whitelist is
+    /// never sent, rather than leaking it.
+    pub fn verify_metadata_only(event: &WebSocketMessage) -> Result<(), GatewayRejection> {
+        let frame = serde_json::to_value(event).map_err(|_| GatewayRejection::Malformed)?;
+        Self::check(&frame, OUTBOUND_SCHEMA)
+    }
+    
+    fn check(frame: &Value, schema: &[(&str, &[&str])]) -> Result<(), GatewayRejection> {
+        let frame = frame.as_object().ok_or(GatewayRejection::Malformed)?;
+        let frame_type = frame
+            .get("type")
+            .and_then(Value::as_str)
+            .ok_or(GatewayRejection::Malformed)?;
+        let allowed = schema
+            .iter()
+            .find(|(name, _)| *name == frame_type)
+            .map(|(_, fields)| *fields)
+            .ok_or_else(|| GatewayRejection::UnknownType(frame_type.to_string()))?;
+        
+        let unexpected = |field: &str| GatewayRejection::UnexpectedField {
+            frame_type: frame_type.to_string(),
+            field: field.to_string(),
+        };
+        if let Some(field) = frame.keys().find(|key| !matches!(key.as_str(), "type" | "payload")) {
+            return Err(unexpected(field));
+        }
+        let payload = frame
+            .get("payload")
+            .and_then(Value::as_object)
+            .ok_or(GatewayRejection::Malformed)?;
+        
+        for (field, value) in payload {
+            if !allowed.contains(&field.as_str()) {
+                return Err(unexpected(field));
+            }
+            if let Some((_, max)) = FIELD_LIMITS.iter().find(|(name, _)| name == field) {
+                if value.as_str().is_some_and(|value| value.len() > *max) {
+                    return Err(GatewayRejection::FieldTooLong {
+                        frame_type: frame_type.to_string(),
+                        field: field.clone(),
+                        max: *max,
+                    });
+                }
+            }
+        }
+        
+        // A replayed event is held to the same whitelist as when it is sent live
+        if let Some(event) = payload.get("event") {
+            Self::check(event, schema)?;
+        }
+        
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::models::MessageResponse;
+    use serde_json::json;
     use uuid::Uuid;
     use chrono::Utc;
     
+    fn frame(frame_type: &str, payload: Value) -> Value {
+        json!({ "type": frame_type, "payload": payload })
+    }
+    
+    /// One frame per inbound type, with every whitelisted field
+    fn inbound_samples() -> Vec<Value> {
+        let id = Uuid::new_v4();
+        let now = Utc::now();
+        vec![
+            frame("message", json!({
+                "recipient_id": id, "message_type": "text", "session_id": "session-id", "client_message_id": "c-1"
+            })),
+            frame("call_request", json!({ "recipient_id": id, "call_type": "audio" })),
+            frame("call_response", json!({ "call_id": "call-1", "response": "accepted" })),
+            frame("presence_update", json!({ "user_id": id, "status": "online", "last_seen": now })),
+            frame("typing_indicator", json!({
+                "user_id": id, "conversation_id": id, "is_typing": true, "timestamp": now
+            })),
+            frame("read_receipt", json!({ "message_id": id, "reader_id": id, "read_at": now })),
+            frame("heartbeat", json!({ "timestamp": now })),
+            frame("message_ack", json!({ "message_ids": [id] })),
+            frame("pending_event_ack", json!({ "event_ids": [id] })),
+            frame("sealed_message_ack", json!({ "message_ids": [id] })),
+        ]
+    }
+    
+    /// One event per outbound type, as built by the handlers
+    fn outbound_samples() -> Vec<WebSocketMessage> {
+        let id = Uuid::new_v4();
+        let now = Utc::now();
+        let message_response = frame("message_response", json!({
+            "id": id, "conversation_id": id, "sender_id": id, "recipient_id": id, "message_type": "text",
+            "timestamp": now, "session_id": "session-id", "is_read": false, "status": "sent",
+            "delivered_at": null, "read_at": null, "client_message_id": null
+        }));
+        [
+            message_response.clone(),
+            frame("call_request_full", json!({
+                "call_id": "call-1", "caller_id": id, "recipient_id": id, "call_type": "video", "timestamp": now
+            })),
+            frame("call_response_full", json!({ "call_id": "call-1", "response": "busy", "timestamp": now })),
+            frame("presence_update", json!({ "user_id": id, "status": "away", "last_seen": now })),
+            frame("typing_indicator", json!({
+                "user_id": id, "conversation_id": id, "is_typing": false, "timestamp": now
+            })),
+            frame("heartbeat_response", json!({ "timestamp": now })),
+            frame("error", json!({ "message": "Rate limit exceeded", "code": "rate_limited", "retry_after": 3 })),
+            frame("message_status", json!({ "message_id": id, "conversation_id": id, "status": "read", "timestamp": now })),
+            frame("pending_event", json!({ "event_id": id, "created_at": now, "event": message_response })),
+            frame("contact_deleted", json!({ "user_id": id })),
+            frame("prekeys_low", json!({ "device_id": id, "remaining": 9 })),
+            frame("identity_changed", json!({
+                "user_id": id, "device_id": id, "identity_key": "a2V5", "log_index": 2, "entry_hash": "aGFzaA=="
+            })),
+            frame("sealed_message", json!({ "id": id, "device_id": id, "envelope": "ZW52", "timestamp": now })),
+        ]
+        .into_iter()
+        .map(|event| serde_json::from_value(event).unwrap())
+        .collect()
+    }
+    
+    fn payload_fields(message: &WebSocketMessage) -> (String, Vec<String>) {
+        let value = serde_json::to_value(message).unwrap();
+        let fields = value["payload"].as_object().unwrap().keys().cloned().collect();
+        (value["type"].as_str().unwrap().to_string(), fields)
+    }
+    
+    fn allowed<'a>(schema: &[(&str, &'a [&'a str])], frame_type: &str) -> &'a [&'a str] {
+        schema.iter().find(|(name, _)| *name == frame_type).unwrap().1
+    }
+    
     #[test]
     fn test_metadata_only_structure() {
-        let message = MessageResponse {
+        let message = crate::models::MessageResponse {
             id: Uuid::new_v4(),
             conversation_id: Uuid::new_v4(),
             sender_id: Uuid::new_v4(),
@@ -100,10 +317,86 @@ mod tests {
             timestamp: Utc::now(),
             session_id: Some("session-id".to_string()),
             is_read: false,
+            status: "sent".to_string(),
+            delivered_at: None,
+            read_at: None,
+            client_message_id: None,
         };
         
         // Verify that MessageResponse has no content field
-        assert!(SecurityGateway::verify_metadata_only(&message));
+        assert!(SecurityGateway::verify_metadata_only(&WebSocketMessage::MessageResponse { payload: message }).is_ok());
+    }
+    
+    #[test]
+    fn test_inbound_whitelist_rejects_unknown_fields() {
+        for sample in inbound_samples() {
+            let frame_type = sample["type"].as_str().unwrap().to_string();
+            assert!(SecurityGateway::check_inbound(&sample.to_string()).is_ok(), "{}", frame_type);
+            
+            let mut with_content = sample.clone();
+            with_content["payload"]["content"] = json!("plaintext that must never reach the server");
+            assert_eq!(
+                SecurityGateway::check_inbound(&with_content.to_string()).unwrap_err(),
+                GatewayRejection::UnexpectedField { frame_type: frame_type.clone(), field: "content".to_string() }
+            );
+            
+            let mut beside_payload = sample.clone();
+            beside_payload["content"] = json!("ciphertext");
+            assert!(SecurityGateway::check_inbound(&beside_payload.to_string()).is_err());
+        }
+        
+        // Server events can't be injected by clients
+        let forged = frame("message_response", json!({}));
+        assert_eq!(
+            SecurityGateway::check_inbound(&forged.to_string()).unwrap_err(),
+            GatewayRejection::UnknownType("message_response".to_string())
+        );
+        assert_eq!(SecurityGateway::check_inbound("not json").unwrap_err(), GatewayRejection::Malformed);
+    }
+    
+    #[test]
+    fn test_session_id_is_capped() {
+        let mut sample = inbound_samples().remove(0);
+        sample["payload"]["session_id"] = json!("s".repeat(MAX_SESSION_ID_LEN));
+        assert!(SecurityGateway::check_inbound(&sample.to_string()).is_ok());
+        
+        sample["payload"]["session_id"] = json!("s".repeat(MAX_SESSION_ID_LEN + 1));
+        assert!(matches!(
+            SecurityGateway::check_inbound(&sample.to_string()),
+            Err(GatewayRejection::FieldTooLong { max: MAX_SESSION_ID_LEN, .. })
+        ));
+    }
+    
+    #[test]
+    fn test_handlers_only_receive_whitelisted_fields() {
+        // What a handler gets is the parsed frame: it can't hold anything outside the whitelist
+        for sample in inbound_samples() {
+            let message = SecurityGateway::check_inbound(&sample.to_string()).unwrap();
+            let (frame_type, fields) = payload_fields(&message);
+            let allowed = allowed(INBOUND_SCHEMA, &frame_type);
+            assert!(fields.iter().all(|field| allowed.contains(&field.as_str())), "{}", frame_type);
+        }
+    }
+    
+    #[test]
+    fn test_routed_events_are_metadata_only() {
+        let samples = outbound_samples();
+        assert_eq!(samples.len(), OUTBOUND_SCHEMA.len());
+        for event in &samples {
+            assert!(SecurityGateway::verify_metadata_only(event).is_ok(), "{:?}", event);
+            let (frame_type, fields) = payload_fields(event);
+            assert!(!fields.contains(&"content".to_string()), "{}", frame_type);
+        }
+        
+        // A queued event wrapping a frame outside the whitelist is refused too
+        let smuggled = frame("message", json!({ "recipient_id": Uuid::new_v4(), "message_type": "text" }));
+        let replay: WebSocketMessage = serde_json::from_value(frame("pending_event", json!({
+            "event_id": Uuid::new_v4(), "created_at": Utc::now(), "event": smuggled
+        })))
+        .unwrap();
+        assert_eq!(
+            SecurityGateway::verify_metadata_only(&replay).unwrap_err(),
+            GatewayRejection::UnknownType("message".to_string())
+        );
     }
 }
-
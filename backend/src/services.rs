@@ -1,447 +1,2902 @@
 use crate::models::*;
 use anyhow::Context;
-use bcrypt::{hash, verify, DEFAULT_COST};
+use crate::config::PasswordHashingConfig;
 use chrono::{DateTime, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use crate::jwt::JwtKeys;
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize)]
-struct Claims {
+pub struct Claims {
+    pub sub: String, // user id
+    pub sid: Uuid, // session id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub did: Option<Uuid>, // device id
+    pub exp: usize,
+    pub iat: usize,
+}
+
+impl Claims {
+    pub fn user_id(&self) -> anyhow::Result<Uuid> {
+        Uuid::parse_str(&self.sub).context("Invalid user ID in token")
+    }
+}
+
+/// Claims of the token proving the password step of a login with two-factor authentication
+/// 
+/// It has no session id, so it is never accepted as an access token.
+#[derive(Debug, Serialize, Deserialize)]
+struct MfaClaims {
     sub: String, // user id
+    typ: String, // always "mfa_pending"
     exp: usize,
     iat: usize,
 }
 
+const MFA_TOKEN_TYPE: &str = "mfa_pending";
+
 pub struct AuthService;
 
 impl AuthService {
-    pub fn hash_password(password: &str) -> anyhow::Result<String> {
-        hash(password, DEFAULT_COST).context("Failed to hash password")
+    pub fn argon2(config: &PasswordHashingConfig) -> anyhow::Result<argon2::Argon2<'static>> {
+        let params = argon2::Params::new(config.memory_kib, config.iterations, config.parallelism, None)
+            .map_err(|e| anyhow::anyhow!("Invalid Argon2 parameters: {}", e))?;
+        Ok(argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params))
     }
     
+    /// Argon2id hash in PHC string format (`$argon2id$v=19$m=...,t=...,p=...$salt$hash`)
+    pub fn hash_password(password: &str, config: &PasswordHashingConfig) -> anyhow::Result<String> {
+        use argon2::password_hash::{rand_core::OsRng, PasswordHasher, SaltString};
+        
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Self::argon2(config)?
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e))?;
+        Ok(hash.to_string())
+    }
+    
+    /// Check a password against an Argon2 hash, or a bcrypt hash from before the migration
     pub fn verify_password(password: &str, hash: &str) -> anyhow::Result<bool> {
-        verify(password, hash).context("Failed to verify password")
+        use argon2::password_hash::{PasswordHash, PasswordVerifier};
+        
+        if !hash.starts_with("$argon2") {
+            return bcrypt::verify(password, hash).context("Failed to verify password");
+        }
+        
+        let parsed = PasswordHash::new(hash)
+            .map_err(|e| anyhow::anyhow!("Invalid password hash: {}", e))?;
+        // The costs stored in the hash are used, not the configured ones
+        match argon2::Argon2::default().verify_password(password.as_bytes(), &parsed) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(anyhow::anyhow!("Failed to verify password: {}", e)),
+        }
+    }
+    
+    /// Take as long as a password check when there is no account to check against
+    /// 
+    /// Otherwise unknown emails are answered faster than wrong passwords, revealing which accounts exist.
+    pub fn verify_dummy_password(password: &str, config: &PasswordHashingConfig) {
+        use std::sync::OnceLock;
+        static DUMMY_HASH: OnceLock<Option<String>> = OnceLock::new();
+        
+        let hash = DUMMY_HASH.get_or_init(|| Self::hash_password(&Self::generate_opaque_token(), config).ok());
+        if let Some(hash) = hash {
+            let _ = Self::verify_password(password, hash);
+        }
+    }
+    
+    /// Whether a hash uses another algorithm or other costs than the configured ones
+    pub fn needs_rehash(hash: &str, config: &PasswordHashingConfig) -> bool {
+        let Ok(parsed) = argon2::password_hash::PasswordHash::new(hash) else {
+            return true;
+        };
+        if parsed.algorithm != argon2::Algorithm::Argon2id.ident() {
+            return true;
+        }
+        match argon2::Params::try_from(&parsed) {
+            Ok(params) => {
+                params.m_cost() != config.memory_kib
+                    || params.t_cost() != config.iterations
+                    || params.p_cost() != config.parallelism
+            }
+            Err(_) => true,
+        }
+    }
+    
+    /// Random URL-safe token (refresh tokens, tokens sent by email)
+    pub fn generate_opaque_token() -> String {
+        use base64::{engine::general_purpose, Engine as _};
+        use rand::RngCore;
+        
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+    }
+    
+    /// SHA-256 hex digest, the only form in which opaque tokens are stored
+    pub fn hash_opaque_token(token: &str) -> String {
+        use sha2::{Digest, Sha256};
+        
+        Sha256::digest(token.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
     }
     
-    pub fn generate_token(user_id: Uuid, secret: &str, expiration: i64) -> anyhow::Result<String> {
+    /// Generate a short-lived access token bound to a session and its device
+    pub fn generate_token(
+        session: &Session,
+        keys: &JwtKeys,
+        expiration: i64,
+    ) -> anyhow::Result<String> {
         let now = Utc::now().timestamp() as usize;
         let exp = now + expiration as usize;
         
         let claims = Claims {
-            sub: user_id.to_string(),
+            sub: session.user_id.to_string(),
+            sid: session.id,
+            did: session.device_id,
             exp,
             iat: now,
         };
         
-        encode(
-            &Header::default(),
-            &claims,
-            &EncodingKey::from_secret(secret.as_ref()),
-        )
-        .context("Failed to generate token")
+        keys.encode(&claims).context("Failed to generate token")
     }
     
-    pub fn verify_token(token: &str, secret: &str) -> anyhow::Result<Uuid> {
-        let token_data = decode::<Claims>(
-            token,
-            &DecodingKey::from_secret(secret.as_ref()),
-            &Validation::default(),
-        )
-        .context("Invalid token")?;
+    /// Token to present with the second factor, valid for `expiration` seconds
+    pub fn generate_mfa_token(user_id: Uuid, keys: &JwtKeys, expiration: i64) -> anyhow::Result<String> {
+        let now = Utc::now().timestamp() as usize;
+        let claims = MfaClaims {
+            sub: user_id.to_string(),
+            typ: MFA_TOKEN_TYPE.to_string(),
+            exp: now + expiration as usize,
+            iat: now,
+        };
+        
+        keys.encode(&claims).context("Failed to generate MFA token")
+    }
+    
+    /// User id of a valid "mfa pending" token
+    pub fn verify_mfa_token(token: &str, keys: &JwtKeys) -> anyhow::Result<Uuid> {
+        let claims: MfaClaims = keys.decode(token).context("Invalid MFA token")?;
         
-        Uuid::parse_str(&token_data.claims.sub).context("Invalid user ID in token")
+        if claims.typ != MFA_TOKEN_TYPE {
+            anyhow::bail!("Not an MFA token");
+        }
+        Uuid::parse_str(&claims.sub).context("Invalid user ID in token")
+    }
+    
+    /// Verify the signature and expiry of an access token
+    /// 
+    /// This does NOT check that the session is still active,
+    /// use `SessionService::is_active` for that.
+    pub fn verify_token(token: &str, keys: &JwtKeys) -> anyhow::Result<Claims> {
+        keys.decode(token).context("Invalid token")
     }
 }
 
-pub struct UserService;
+/// Result of presenting a refresh token
+pub enum RefreshOutcome {
+    /// The token was valid and has been replaced by a new one
+    Rotated {
+        session: Session,
+        refresh_token: String,
+    },
+    /// The token had already been used: the session has been revoked
+    Reused { session_id: Uuid },
+    /// Unknown token, or expired/revoked session
+    Invalid,
+}
 
-impl UserService {
-    pub async fn create_user(
+/// Service for server-side sessions and rotating refresh tokens
+/// 
+/// Refresh tokens are opaque random strings, only their SHA-256 hash is stored.
+/// Each token can be used exactly once; reusing a rotated token revokes the session.
+pub struct SessionService;
+
+impl SessionService {
+    /// Open a new session on a device and return it with its first refresh token
+    pub async fn create_session(
         pool: &PgPool,
-        email: &str,
-        username: &str,
-        password: &str,
-        name: Option<&str>,
-    ) -> anyhow::Result<User> {
-        let password_hash = AuthService::hash_password(password)?;
-        let user_id = Uuid::new_v4();
+        user_id: Uuid,
+        device_id: Uuid,
+        ttl_seconds: i64,
+    ) -> anyhow::Result<(Session, String)> {
+        let now = Utc::now();
+        let mut tx = pool.begin().await.context("Failed to start transaction")?;
         
-        let user = sqlx::query_as::<_, User>(
+        let session = sqlx::query_as::<_, Session>(
             r#"
-            INSERT INTO users (id, email, username, password_hash, name, created_at, updated_at)
-            VALUES ($1, $2, $3, $4, $5, $6, $6)
+            INSERT INTO sessions (id, user_id, device_id, created_at, last_used_at, expires_at)
+            VALUES ($1, $2, $3, $4, $4, $5)
             RETURNING *
             "#,
         )
+        .bind(Uuid::new_v4())
         .bind(user_id)
-        .bind(email)
-        .bind(username)
-        .bind(password_hash)
-        .bind(name)
-        .bind(Utc::now())
-        .fetch_one(pool)
+        .bind(device_id)
+        .bind(now)
+        .bind(now + chrono::Duration::seconds(ttl_seconds))
+        .fetch_one(&mut *tx)
         .await
-        .context("Failed to create user")?;
+        .context("Failed to create session")?;
         
-        Ok(user)
-    }
-    
-    pub async fn find_by_email(pool: &PgPool, email: &str) -> anyhow::Result<Option<User>> {
-        let user = sqlx::query_as::<_, User>(
-            "SELECT * FROM users WHERE email = $1",
-        )
-        .bind(email)
-        .fetch_optional(pool)
-        .await
-        .context("Failed to find user")?;
+        let refresh_token = Self::issue_refresh_token(&mut tx, session.id).await?;
+        tx.commit().await.context("Failed to commit session")?;
         
-        Ok(user)
+        Ok((session, refresh_token))
     }
     
-    pub async fn find_by_id(pool: &PgPool, user_id: Uuid) -> anyhow::Result<Option<User>> {
-        let user = sqlx::query_as::<_, User>(
-            "SELECT * FROM users WHERE id = $1",
+    /// When the user signed in to open the session, refreshing does not change it
+    pub async fn get_created_at(pool: &PgPool, session_id: Uuid) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let created_at = sqlx::query_scalar(
+            "SELECT created_at FROM sessions WHERE id = $1 AND revoked_at IS NULL",
         )
-        .bind(user_id)
+        .bind(session_id)
         .fetch_optional(pool)
         .await
-        .context("Failed to find user")?;
+        .context("Failed to get session")?;
         
-        Ok(user)
+        Ok(created_at)
     }
     
-    /// Find user by username
-    pub async fn find_by_username(pool: &PgPool, username: &str) -> anyhow::Result<Option<User>> {
-        let user = sqlx::query_as::<_, User>(
-            "SELECT * FROM users WHERE username = $1",
+    async fn issue_refresh_token(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        session_id: Uuid,
+    ) -> anyhow::Result<String> {
+        let refresh_token = AuthService::generate_opaque_token();
+        
+        sqlx::query(
+            "INSERT INTO refresh_tokens (id, session_id, token_hash) VALUES ($1, $2, $3)",
         )
-        .bind(username)
-        .fetch_optional(pool)
+        .bind(Uuid::new_v4())
+        .bind(session_id)
+        .bind(AuthService::hash_opaque_token(&refresh_token))
+        .execute(&mut **tx)
         .await
-        .context("Failed to find user by username")?;
+        .context("Failed to store refresh token")?;
         
-        Ok(user)
+        Ok(refresh_token)
     }
     
-    /// Search users by username, email or name
-    /// This function searches users by:
-    /// - Exact email match (highest priority)
-    /// - Username match (starts with)
-    /// - Email match (contains)
-    /// - Name match (contains)
-    pub async fn search_users(
+    /// Consume a refresh token and issue its replacement
+    /// 
+    /// The session lifetime slides forward by `ttl_seconds` on every rotation.
+    pub async fn rotate_refresh_token(
         pool: &PgPool,
-        query: &str,
-        limit: i64,
-        exclude_user_id: Option<Uuid>,
-    ) -> anyhow::Result<Vec<User>> {
-        // Trim and normalize the query
-        let query = query.trim();
-        if query.is_empty() {
-            return Ok(vec![]);
-        }
+        refresh_token: &str,
+        ttl_seconds: i64,
+    ) -> anyhow::Result<RefreshOutcome> {
+        let token_hash = AuthService::hash_opaque_token(refresh_token);
         
-        // Create search patterns
-        let exact_pattern = query.to_string();
-        let starts_with_pattern = format!("{}%", query);
-        let contains_pattern = format!("%{}%", query);
+        // Consuming the token and issuing its replacement succeed or fail together, a failure
+        // in between must not leave the session without a usable refresh token
+        let mut tx = pool.begin().await.context("Failed to start transaction")?;
         
-        let users = if let Some(exclude_id) = exclude_user_id {
-            sqlx::query_as::<_, User>(
-                r#"
-                SELECT * FROM users
-                WHERE (
-                    email = $1 
-                    OR email ILIKE $2 
-                    OR COALESCE(username, '') = $1
-                    OR COALESCE(username, '') ILIKE $2
-                    OR COALESCE(username, '') ILIKE $3
-                    OR COALESCE(name, '') ILIKE $3
-                    OR email ILIKE $3
-                )
-                AND id != $4
-                ORDER BY 
-                    CASE 
-                        WHEN email = $1 THEN 1
-                        WHEN COALESCE(username, '') = $1 THEN 2
-                        WHEN email ILIKE $2 THEN 3
-                        WHEN COALESCE(username, '') ILIKE $2 THEN 4
-                        WHEN email ILIKE $3 THEN 5
-                        WHEN COALESCE(username, '') ILIKE $3 THEN 6
-                        WHEN COALESCE(name, '') ILIKE $3 THEN 7
-                        ELSE 8
-                    END,
-                    created_at DESC
-                LIMIT $5
-                "#,
-            )
-            .bind(&exact_pattern)
-            .bind(&starts_with_pattern)
-            .bind(&contains_pattern)
-            .bind(exclude_id)
-            .bind(limit)
-            .fetch_all(pool)
-            .await
-            .context("Failed to search users")?
-        } else {
-            sqlx::query_as::<_, User>(
-                r#"
-                SELECT * FROM users
-                WHERE (
-                    email = $1 
-                    OR email ILIKE $2 
-                    OR COALESCE(username, '') = $1
-                    OR COALESCE(username, '') ILIKE $2
-                    OR COALESCE(username, '') ILIKE $3
-                    OR COALESCE(name, '') ILIKE $3
-                    OR email ILIKE $3
+        // Atomically mark the token as used so two concurrent refreshes cannot both succeed
+        let session_id: Option<Uuid> = sqlx::query_scalar(
+            r#"
+            UPDATE refresh_tokens
+            SET used_at = NOW()
+            WHERE token_hash = $1 AND used_at IS NULL
+            RETURNING session_id
+            "#,
+        )
+        .bind(&token_hash)
+        .fetch_optional(&mut *tx)
+        .await
+        .context("Failed to consume refresh token")?;
+        
+        let session_id = match session_id {
+            Some(id) => id,
+            None => {
+                // Either unknown, or already used: the latter means the token leaked
+                let reused_session: Option<Uuid> = sqlx::query_scalar(
+                    "SELECT session_id FROM refresh_tokens WHERE token_hash = $1",
                 )
-                ORDER BY 
-                    CASE 
-                        WHEN email = $1 THEN 1
-                        WHEN COALESCE(username, '') = $1 THEN 2
-                        WHEN email ILIKE $2 THEN 3
-                        WHEN COALESCE(username, '') ILIKE $2 THEN 4
-                        WHEN email ILIKE $3 THEN 5
-                        WHEN COALESCE(username, '') ILIKE $3 THEN 6
-                        WHEN COALESCE(name, '') ILIKE $3 THEN 7
-                        ELSE 8
-                    END,
-                    created_at DESC
-                LIMIT $4
-                "#,
-            )
-            .bind(&exact_pattern)
-            .bind(&starts_with_pattern)
-            .bind(&contains_pattern)
-            .bind(limit)
-            .fetch_all(pool)
-            .await
-            .context("Failed to search users")?
+                .bind(&token_hash)
+                .fetch_optional(pool)
+                .await
+                .context("Failed to look up refresh token")?;
+                
+                return match reused_session {
+                    Some(session_id) => {
+                        Self::revoke_session(pool, session_id).await?;
+                        Ok(RefreshOutcome::Reused { session_id })
+                    }
+                    None => Ok(RefreshOutcome::Invalid),
+                };
+            }
         };
         
-        Ok(users)
+        let now = Utc::now();
+        let session = sqlx::query_as::<_, Session>(
+            r#"
+            UPDATE sessions
+            SET last_used_at = $1, expires_at = $2
+            WHERE id = $3 AND revoked_at IS NULL AND expires_at > $1
+            RETURNING *
+            "#,
+        )
+        .bind(now)
+        .bind(now + chrono::Duration::seconds(ttl_seconds))
+        .bind(session_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .context("Failed to refresh session")?;
+        
+        let session = match session {
+            Some(session) => session,
+            None => return Ok(RefreshOutcome::Invalid),
+        };
+        
+        let refresh_token = Self::issue_refresh_token(&mut tx, session.id).await?;
+        tx.commit().await.context("Failed to commit refresh token rotation")?;
+        
+        Ok(RefreshOutcome::Rotated {
+            session,
+            refresh_token,
+        })
     }
     
-    /// Get all users (for contacts list)
-    pub async fn get_all_users(
+    /// Check that a session exists, belongs to the user, and is neither revoked nor expired
+    pub async fn is_active(
         pool: &PgPool,
-        limit: i64,
-        offset: i64,
-        exclude_user_id: Option<Uuid>,
-    ) -> anyhow::Result<Vec<User>> {
-        let users = if let Some(exclude_id) = exclude_user_id {
-            sqlx::query_as::<_, User>(
-                "SELECT * FROM users WHERE id != $1 ORDER BY created_at DESC LIMIT $2 OFFSET $3",
-            )
-            .bind(exclude_id)
-            .bind(limit)
-            .bind(offset)
-            .fetch_all(pool)
-            .await
-            .context("Failed to get users")?
-        } else {
-            sqlx::query_as::<_, User>(
-                "SELECT * FROM users ORDER BY created_at DESC LIMIT $1 OFFSET $2",
+        session_id: Uuid,
+        user_id: Uuid,
+    ) -> anyhow::Result<bool> {
+        let active = sqlx::query_scalar::<_, bool>(
+            r#"
+            SELECT EXISTS(
+                SELECT 1 FROM sessions
+                WHERE id = $1 AND user_id = $2
+                AND revoked_at IS NULL AND expires_at > NOW()
             )
-            .bind(limit)
-            .bind(offset)
-            .fetch_all(pool)
-            .await
-            .context("Failed to get users")?
-        };
+            "#,
+        )
+        .bind(session_id)
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+        .context("Failed to check session")?;
         
-        Ok(users)
+        Ok(active)
     }
-}
-
-/// Service for message metadata management
-/// 
-/// SECURITY: This service handles ONLY metadata routing.
-/// It does NOT store encrypted content or encryption keys.
-/// Signal Protocol encryption/decryption is handled entirely client-side.
-pub struct MessageService;
-
-impl MessageService {
-    /// Create message metadata entry
+    
+    /// List the active sessions of a user with their device information
+    pub async fn get_user_sessions(
+        pool: &PgPool,
+        user_id: Uuid,
+        current_session_id: Uuid,
+    ) -> anyhow::Result<Vec<SessionResponse>> {
+        let sessions = sqlx::query_as::<_, SessionResponse>(
+            r#"
+            SELECT
+                s.id,
+                s.device_id,
+                d.device_name,
+                d.platform,
+                d.last_ip,
+                d.last_user_agent,
+                s.created_at,
+                s.last_used_at,
+                s.expires_at,
+                s.id = $2 AS is_current
+            FROM sessions s
+            LEFT JOIN devices d ON d.id = s.device_id
+            WHERE s.user_id = $1 AND s.revoked_at IS NULL AND s.expires_at > NOW()
+            ORDER BY s.last_used_at DESC
+            "#,
+        )
+        .bind(user_id)
+        .bind(current_session_id)
+        .fetch_all(pool)
+        .await
+        .context("Failed to get user sessions")?;
+        
+        Ok(sessions)
+    }
+    
+    /// Revoke a session only if it belongs to the user
+    /// 
+    /// Returns false when no such active session exists for this user.
+    pub async fn revoke_user_session(
+        pool: &PgPool,
+        session_id: Uuid,
+        user_id: Uuid,
+    ) -> anyhow::Result<bool> {
+        let revoked = sqlx::query(
+            r#"
+            UPDATE sessions SET revoked_at = NOW()
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(session_id)
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .context("Failed to revoke session")?;
+        
+        Ok(revoked.rows_affected() > 0)
+    }
+    
+    pub async fn revoke_session(pool: &PgPool, session_id: Uuid) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE sessions SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL",
+        )
+        .bind(session_id)
+        .execute(pool)
+        .await
+        .context("Failed to revoke session")?;
+        
+        Ok(())
+    }
+    
+    /// Revoke every active session of a user except `keep`, returns the revoked ids
+    pub async fn revoke_other_sessions(
+        pool: &PgPool,
+        user_id: Uuid,
+        keep: Option<Uuid>,
+    ) -> anyhow::Result<Vec<Uuid>> {
+        let revoked = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            UPDATE sessions SET revoked_at = NOW()
+            WHERE user_id = $1 AND revoked_at IS NULL AND ($2::uuid IS NULL OR id <> $2)
+            RETURNING id
+            "#,
+        )
+        .bind(user_id)
+        .bind(keep)
+        .fetch_all(pool)
+        .await
+        .context("Failed to revoke sessions")?;
+        
+        Ok(revoked)
+    }
+    
+    /// Delete sessions that expired or were revoked more than a day ago
+    pub async fn cleanup_expired(pool: &PgPool) -> anyhow::Result<u64> {
+        let deleted = sqlx::query(
+            r#"
+            DELETE FROM sessions
+            WHERE expires_at < NOW() - INTERVAL '1 day'
+            OR revoked_at < NOW() - INTERVAL '1 day'
+            "#,
+        )
+        .execute(pool)
+        .await
+        .context("Failed to cleanup expired sessions")?;
+        
+        Ok(deleted.rows_affected())
+    }
+}
+
+/// Service for the registry of client devices
+pub struct DeviceService;
+
+impl DeviceService {
+    /// Register the device a user is logging in from
+    /// 
+    /// A known `device_id` belonging to the user is reused and refreshed,
+    /// otherwise a new device is created.
+    pub async fn register_device(
+        pool: &PgPool,
+        user_id: Uuid,
+        device_id: Option<Uuid>,
+        device_name: Option<&str>,
+        platform: Option<&str>,
+        ip: Option<&str>,
+        user_agent: Option<&str>,
+    ) -> anyhow::Result<Device> {
+        if let Some(device_id) = device_id {
+            let device = sqlx::query_as::<_, Device>(
+                r#"
+                UPDATE devices
+                SET device_name = COALESCE($3, device_name),
+                    platform = COALESCE($4, platform),
+                    last_ip = $5,
+                    last_user_agent = $6,
+                    last_seen_at = NOW()
+                WHERE id = $1 AND user_id = $2
+                RETURNING *
+                "#,
+            )
+            .bind(device_id)
+            .bind(user_id)
+            .bind(device_name)
+            .bind(platform)
+            .bind(ip)
+            .bind(user_agent)
+            .fetch_optional(pool)
+            .await
+            .context("Failed to update device")?;
+            
+            if let Some(device) = device {
+                return Ok(device);
+            }
+        }
+        
+        let device = sqlx::query_as::<_, Device>(
+            r#"
+            INSERT INTO devices (id, user_id, device_name, platform, last_ip, last_user_agent)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING *
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(device_name)
+        .bind(platform)
+        .bind(ip)
+        .bind(user_agent)
+        .fetch_one(pool)
+        .await
+        .context("Failed to create device")?;
+        
+        Ok(device)
+    }
+    
+    /// Whether every device belongs to one of the users
+    pub async fn all_belong_to(
+        pool: &PgPool,
+        device_ids: &[Uuid],
+        user_ids: &[Uuid],
+    ) -> anyhow::Result<bool> {
+        let owned = sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM devices WHERE id = ANY($1) AND user_id = ANY($2)",
+        )
+        .bind(device_ids)
+        .bind(user_ids)
+        .fetch_one(pool)
+        .await
+        .context("Failed to check devices")?;
+        
+        Ok(owned == device_ids.len() as i64)
+    }
+    
+    /// Record the last IP / user agent a device was seen with
+    pub async fn touch(
+        pool: &PgPool,
+        device_id: Uuid,
+        ip: Option<&str>,
+        user_agent: Option<&str>,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE devices
+            SET last_ip = COALESCE($2, last_ip),
+                last_user_agent = COALESCE($3, last_user_agent),
+                last_seen_at = NOW()
+            WHERE id = $1
+            "#,
+        )
+        .bind(device_id)
+        .bind(ip)
+        .bind(user_agent)
+        .execute(pool)
+        .await
+        .context("Failed to update device")?;
+        
+        Ok(())
+    }
+}
+
+/// One-time prekey handed out with a bundle, and how many the device has left
+pub struct ClaimedPrekey {
+    pub key_id: i32,
+    pub public_key: Vec<u8>,
+    pub remaining: i64,
+}
+
+/// Directory of the Signal public keys of each device
+/// 
+/// SECURITY: Only public keys are stored. A bundle fetch consumes one one-time prekey
+/// of each device so that no two senders get the same one.
+pub struct PrekeyService;
+
+impl PrekeyService {
+    /// Publish the identity key and signed prekey of a device, and optionally one-time prekeys
+    /// 
+    /// One-time prekeys of a previous identity key are dropped, they can't be used with the new one.
+    /// A new identity key is appended to the key transparency log, the entry is returned.
+    pub async fn upload_keys(
+        pool: &PgPool,
+        user_id: Uuid,
+        device_id: Uuid,
+        registration_id: i32,
+        identity_key: &[u8],
+        signed_prekey: (i32, &[u8], &[u8]),
+        one_time_prekeys: &[(i32, Vec<u8>)],
+    ) -> anyhow::Result<Option<IdentityKeyLogEntry>> {
+        let mut tx = pool.begin().await.context("Failed to start transaction")?;
+        
+        let previous_identity_key = sqlx::query_scalar::<_, Vec<u8>>(
+            "SELECT identity_key FROM device_keys WHERE device_id = $1 FOR UPDATE",
+        )
+        .bind(device_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .context("Failed to get device keys")?;
+        let identity_changed = previous_identity_key.as_deref() != Some(identity_key);
+        if previous_identity_key.is_some() && identity_changed {
+            sqlx::query("DELETE FROM one_time_prekeys WHERE device_id = $1")
+                .bind(device_id)
+                .execute(&mut *tx)
+                .await
+                .context("Failed to drop one-time prekeys")?;
+        }
+        
+        let (signed_prekey_id, signed_prekey, signature) = signed_prekey;
+        sqlx::query(
+            r#"
+            INSERT INTO device_keys (device_id, user_id, registration_id, identity_key,
+                                     signed_prekey_id, signed_prekey, signed_prekey_signature)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (device_id) DO UPDATE SET
+                registration_id = EXCLUDED.registration_id,
+                identity_key = EXCLUDED.identity_key,
+                signed_prekey_id = EXCLUDED.signed_prekey_id,
+                signed_prekey = EXCLUDED.signed_prekey,
+                signed_prekey_signature = EXCLUDED.signed_prekey_signature,
+                updated_at = NOW()
+            "#,
+        )
+        .bind(device_id)
+        .bind(user_id)
+        .bind(registration_id)
+        .bind(identity_key)
+        .bind(signed_prekey_id)
+        .bind(signed_prekey)
+        .bind(signature)
+        .execute(&mut *tx)
+        .await
+        .context("Failed to store device keys")?;
+        
+        Self::insert_one_time_prekeys(&mut tx, device_id, one_time_prekeys).await?;
+        
+        let log_entry = if identity_changed {
+            Some(IdentityKeyLogService::append(&mut tx, user_id, device_id, identity_key).await?)
+        } else {
+            None
+        };
+        
+        tx.commit().await.context("Failed to commit device keys")?;
+        Ok(log_entry)
+    }
+    
+    /// Add one-time prekeys to a device that already published its keys, `false` if it didn't
+    pub async fn add_one_time_prekeys(
+        pool: &PgPool,
+        device_id: Uuid,
+        one_time_prekeys: &[(i32, Vec<u8>)],
+    ) -> anyhow::Result<bool> {
+        let mut tx = pool.begin().await.context("Failed to start transaction")?;
+        
+        let has_keys = sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS(SELECT 1 FROM device_keys WHERE device_id = $1)",
+        )
+        .bind(device_id)
+        .fetch_one(&mut *tx)
+        .await
+        .context("Failed to get device keys")?;
+        if !has_keys {
+            return Ok(false);
+        }
+        Self::insert_one_time_prekeys(&mut tx, device_id, one_time_prekeys).await?;
+        
+        tx.commit().await.context("Failed to commit one-time prekeys")?;
+        Ok(true)
+    }
+    
+    async fn insert_one_time_prekeys(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        device_id: Uuid,
+        one_time_prekeys: &[(i32, Vec<u8>)],
+    ) -> anyhow::Result<()> {
+        if one_time_prekeys.is_empty() {
+            return Ok(());
+        }
+        
+        // A refilled stock may be warned about again
+        sqlx::query("UPDATE device_keys SET low_prekeys_notified = NULL WHERE device_id = $1")
+            .bind(device_id)
+            .execute(&mut **tx)
+            .await
+            .context("Failed to reset low prekeys notice")?;
+        
+        for (key_id, public_key) in one_time_prekeys {
+            sqlx::query(
+                r#"
+                INSERT INTO one_time_prekeys (device_id, key_id, public_key)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (device_id, key_id) DO UPDATE SET public_key = EXCLUDED.public_key, created_at = NOW()
+                "#,
+            )
+            .bind(device_id)
+            .bind(key_id)
+            .bind(public_key)
+            .execute(&mut **tx)
+            .await
+            .context("Failed to store one-time prekey")?;
+        }
+        
+        Ok(())
+    }
+    
+    pub async fn count_one_time_prekeys(pool: &PgPool, device_id: Uuid) -> anyhow::Result<i64> {
+        let count = sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM one_time_prekeys WHERE device_id = $1",
+        )
+        .bind(device_id)
+        .fetch_one(pool)
+        .await
+        .context("Failed to count one-time prekeys")?;
+        
+        Ok(count)
+    }
+    
+    /// Keys of the devices of a user that still have an open session
+    pub async fn get_device_keys(
+        pool: &PgPool,
+        user_id: Uuid,
+        device_id: Option<Uuid>,
+    ) -> anyhow::Result<Vec<DeviceKeys>> {
+        let keys = sqlx::query_as::<_, DeviceKeys>(
+            r#"
+            SELECT k.* FROM device_keys k
+            WHERE k.user_id = $1
+            AND ($2::UUID IS NULL OR k.device_id = $2)
+            AND EXISTS (
+                SELECT 1 FROM sessions s
+                WHERE s.device_id = k.device_id AND s.revoked_at IS NULL AND s.expires_at > NOW()
+            )
+            ORDER BY k.updated_at
+            "#,
+        )
+        .bind(user_id)
+        .bind(device_id)
+        .fetch_all(pool)
+        .await
+        .context("Failed to get device keys")?;
+        
+        Ok(keys)
+    }
+    
+    /// Take one one-time prekey of a device, concurrent fetches never get the same one
+    pub async fn claim_one_time_prekey(pool: &PgPool, device_id: Uuid) -> anyhow::Result<Option<ClaimedPrekey>> {
+        let claimed = sqlx::query_as::<_, (i32, Vec<u8>, i64)>(
+            r#"
+            WITH claimed AS (
+                DELETE FROM one_time_prekeys
+                WHERE (device_id, key_id) = (
+                    SELECT device_id, key_id FROM one_time_prekeys
+                    WHERE device_id = $1
+                    ORDER BY key_id
+                    LIMIT 1
+                    FOR UPDATE SKIP LOCKED
+                )
+                RETURNING key_id, public_key
+            )
+            SELECT key_id, public_key,
+                   (SELECT COUNT(*) FROM one_time_prekeys WHERE device_id = $1) - 1
+            FROM claimed
+            "#,
+        )
+        .bind(device_id)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to claim one-time prekey")?;
+        
+        Ok(claimed.map(|(key_id, public_key, remaining)| ClaimedPrekey {
+            key_id,
+            public_key,
+            remaining,
+        }))
+    }
+    
+    /// Record that a device is warned about its low stock, `false` if it already was
+    /// 
+    /// A device is warned once below the threshold, and once more when it runs out.
+    pub async fn mark_low_prekeys_notified(
+        pool: &PgPool,
+        device_id: Uuid,
+        remaining: i64,
+    ) -> anyhow::Result<bool> {
+        let marked = sqlx::query(
+            r#"
+            UPDATE device_keys SET low_prekeys_notified = $2
+            WHERE device_id = $1
+            AND (low_prekeys_notified IS NULL OR ($2 = 0 AND low_prekeys_notified > 0))
+            "#,
+        )
+        .bind(device_id)
+        .bind(remaining as i32)
+        .execute(pool)
+        .await
+        .context("Failed to record low prekeys notice")?;
+        
+        Ok(marked.rows_affected() > 0)
+    }
+}
+
+/// Service for single-use tokens sent by email
+/// 
+/// Only the SHA-256 hash of a token is stored. Issuing a new token
+/// invalidates the unused ones of the same purpose.
+pub struct UserTokenService;
+
+impl UserTokenService {
+    pub const UNLOCK_ACCOUNT: &'static str = "unlock_account";
+    pub const PASSWORD_RESET: &'static str = "password_reset";
+    pub const VERIFY_EMAIL: &'static str = "verify_email";
+    
+    pub async fn issue(
+        pool: &PgPool,
+        user_id: Uuid,
+        purpose: &str,
+        ttl: chrono::Duration,
+    ) -> anyhow::Result<String> {
+        let token = AuthService::generate_opaque_token();
+        
+        let mut tx = pool.begin().await.context("Failed to start transaction")?;
+        
+        sqlx::query(
+            "UPDATE user_tokens SET used_at = NOW() WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL",
+        )
+        .bind(user_id)
+        .bind(purpose)
+        .execute(&mut *tx)
+        .await
+        .context("Failed to invalidate previous tokens")?;
+        
+        sqlx::query(
+            r#"
+            INSERT INTO user_tokens (id, user_id, purpose, token_hash, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(purpose)
+        .bind(AuthService::hash_opaque_token(&token))
+        .bind(Utc::now() + ttl)
+        .execute(&mut *tx)
+        .await
+        .context("Failed to create token")?;
+        
+        tx.commit().await.context("Failed to commit token")?;
+        
+        Ok(token)
+    }
+    
+    /// When the last token of this purpose was sent to the user
+    pub async fn last_issued_at(
+        pool: &PgPool,
+        user_id: Uuid,
+        purpose: &str,
+    ) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let issued_at = sqlx::query_scalar::<_, Option<DateTime<Utc>>>(
+            "SELECT MAX(created_at) FROM user_tokens WHERE user_id = $1 AND purpose = $2",
+        )
+        .bind(user_id)
+        .bind(purpose)
+        .fetch_one(pool)
+        .await
+        .context("Failed to get last token")?;
+        
+        Ok(issued_at)
+    }
+    
+    /// User of a valid token, without using it
+    pub async fn find_valid(pool: &PgPool, purpose: &str, token: &str) -> anyhow::Result<Option<Uuid>> {
+        let user_id = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            SELECT user_id FROM user_tokens
+            WHERE token_hash = $1 AND purpose = $2 AND used_at IS NULL AND expires_at > NOW()
+            "#,
+        )
+        .bind(AuthService::hash_opaque_token(token))
+        .bind(purpose)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to find token")?;
+        
+        Ok(user_id)
+    }
+    
+    /// Use a token, returning its user if it was valid, unused and not expired
+    pub async fn consume(pool: &PgPool, purpose: &str, token: &str) -> anyhow::Result<Option<Uuid>> {
+        let user_id = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            UPDATE user_tokens SET used_at = NOW()
+            WHERE token_hash = $1 AND purpose = $2 AND used_at IS NULL AND expires_at > NOW()
+            RETURNING user_id
+            "#,
+        )
+        .bind(AuthService::hash_opaque_token(token))
+        .bind(purpose)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to consume token")?;
+        
+        Ok(user_id)
+    }
+    
+    pub async fn cleanup_expired(pool: &PgPool) -> anyhow::Result<u64> {
+        let deleted = sqlx::query("DELETE FROM user_tokens WHERE expires_at < NOW() OR used_at IS NOT NULL")
+            .execute(pool)
+            .await
+            .context("Failed to cleanup user tokens")?;
+        
+        Ok(deleted.rows_affected())
+    }
+}
+
+// Failures older than this are forgotten
+const LOGIN_FAILURE_WINDOW_SECS: i64 = 15 * 60;
+// Failures allowed before each new attempt must wait (1s, 2s, 4s... up to the max)
+const LOGIN_FREE_ATTEMPTS: i32 = 3;
+const LOGIN_MAX_DELAY_SECS: i64 = 60;
+const ACCOUNT_LOCK_THRESHOLD: i32 = 10;
+const IP_LOCK_THRESHOLD: i32 = 50;
+const LOGIN_LOCK_SECS: i64 = 15 * 60;
+
+/// Service protecting login against password guessing
+/// 
+/// Failures are counted per account (by email, whether it exists or not, so
+/// responses don't reveal registered addresses) and per client IP.
+pub struct LoginProtectionService;
+
+impl LoginProtectionService {
+    fn account_key(email: &str) -> String {
+        format!("account:{}", email.trim().to_lowercase())
+    }
+    
+    fn ip_key(ip: &str) -> String {
+        format!("ip:{}", ip)
+    }
+    
+    fn keys(email: &str, ip: Option<&str>) -> Vec<String> {
+        let mut keys = vec![Self::account_key(email)];
+        keys.extend(ip.map(Self::ip_key));
+        keys
+    }
+    
+    /// How long the next attempt must wait, `None` if it may proceed now
+    pub async fn check(
+        pool: &PgPool,
+        email: &str,
+        ip: Option<&str>,
+    ) -> anyhow::Result<Option<chrono::Duration>> {
+        let rows = sqlx::query_as::<_, LoginThrottle>(
+            "SELECT failures, last_failure_at, locked_until FROM login_throttle WHERE key = ANY($1)",
+        )
+        .bind(Self::keys(email, ip))
+        .fetch_all(pool)
+        .await
+        .context("Failed to check login throttle")?;
+        
+        let now = Utc::now();
+        Ok(rows.iter().filter_map(|row| Self::wait_time(row, now)).max())
+    }
+    
+    fn wait_time(row: &LoginThrottle, now: DateTime<Utc>) -> Option<chrono::Duration> {
+        if let Some(locked_until) = row.locked_until.filter(|until| *until > now) {
+            return Some(locked_until - now);
+        }
+        
+        let window = chrono::Duration::seconds(LOGIN_FAILURE_WINDOW_SECS);
+        if row.failures < LOGIN_FREE_ATTEMPTS || now - row.last_failure_at > window {
+            return None;
+        }
+        let exponent = (row.failures - LOGIN_FREE_ATTEMPTS).min(16) as u32;
+        let delay = chrono::Duration::seconds(2i64.pow(exponent).min(LOGIN_MAX_DELAY_SECS));
+        let retry_at = row.last_failure_at + delay;
+        (retry_at > now).then(|| retry_at - now)
+    }
+    
+    /// Count a failed attempt, returns true if it just locked the account
+    pub async fn record_failure(pool: &PgPool, email: &str, ip: Option<&str>) -> anyhow::Result<bool> {
+        if let Some(ip) = ip {
+            if Self::record_key_failure(pool, &Self::ip_key(ip), IP_LOCK_THRESHOLD).await? {
+                tracing::warn!("🔒 Login locked for IP {} after repeated failures", ip);
+            }
+        }
+        Self::record_key_failure(pool, &Self::account_key(email), ACCOUNT_LOCK_THRESHOLD).await
+    }
+    
+    async fn record_key_failure(pool: &PgPool, key: &str, threshold: i32) -> anyhow::Result<bool> {
+        let failures: i32 = sqlx::query_scalar(
+            r#"
+            INSERT INTO login_throttle (key, failures, last_failure_at)
+            VALUES ($1, 1, NOW())
+            ON CONFLICT (key) DO UPDATE SET
+                failures = CASE
+                    WHEN login_throttle.last_failure_at < NOW() - $2 * INTERVAL '1 second' THEN 1
+                    ELSE login_throttle.failures + 1
+                END,
+                last_failure_at = NOW()
+            RETURNING failures
+            "#,
+        )
+        .bind(key)
+        .bind(LOGIN_FAILURE_WINDOW_SECS as f64)
+        .fetch_one(pool)
+        .await
+        .context("Failed to record login failure")?;
+        
+        if failures < threshold {
+            return Ok(false);
+        }
+        
+        sqlx::query(
+            r#"
+            UPDATE login_throttle
+            SET locked_until = NOW() + $2 * INTERVAL '1 second', failures = 0
+            WHERE key = $1
+            "#,
+        )
+        .bind(key)
+        .bind(LOGIN_LOCK_SECS as f64)
+        .execute(pool)
+        .await
+        .context("Failed to lock login")?;
+        
+        Ok(true)
+    }
+    
+    /// Forget the failures of an account after a successful login or an unlock
+    pub async fn reset_account(pool: &PgPool, email: &str) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM login_throttle WHERE key = $1")
+            .bind(Self::account_key(email))
+            .execute(pool)
+            .await
+            .context("Failed to reset login throttle")?;
+        
+        Ok(())
+    }
+    
+    pub async fn cleanup(pool: &PgPool) -> anyhow::Result<u64> {
+        let deleted = sqlx::query(
+            r#"
+            DELETE FROM login_throttle
+            WHERE last_failure_at < NOW() - $1 * INTERVAL '1 second'
+            AND (locked_until IS NULL OR locked_until < NOW())
+            "#,
+        )
+        .bind(LOGIN_FAILURE_WINDOW_SECS as f64)
+        .execute(pool)
+        .await
+        .context("Failed to cleanup login throttle")?;
+        
+        Ok(deleted.rows_affected())
+    }
+}
+
+/// Service for the security log users can review
+pub struct SecurityEventService;
+
+impl SecurityEventService {
+    pub async fn record(
+        pool: &PgPool,
+        user_id: Uuid,
+        event_type: &str,
+        ip: Option<&str>,
+        user_agent: Option<&str>,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO security_events (id, user_id, event_type, ip, user_agent)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(event_type)
+        .bind(ip)
+        .bind(user_agent)
+        .execute(pool)
+        .await
+        .context("Failed to record security event")?;
+        
+        Ok(())
+    }
+    
+    pub async fn get_user_events(
+        pool: &PgPool,
+        user_id: Uuid,
+        limit: i64,
+    ) -> anyhow::Result<Vec<SecurityEvent>> {
+        let events = sqlx::query_as::<_, SecurityEvent>(
+            r#"
+            SELECT id, event_type, ip, user_agent, created_at FROM security_events
+            WHERE user_id = $1
+            ORDER BY created_at DESC
+            LIMIT $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+        .context("Failed to get security events")?;
+        
+        Ok(events)
+    }
+}
+
+const TOTP_ISSUER: &str = "Kisse";
+const TOTP_STEP: u64 = 30;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Service for TOTP two-factor authentication and recovery codes
+pub struct MfaService;
+
+impl MfaService {
+    fn totp(secret: &str, email: &str) -> anyhow::Result<totp_rs::TOTP> {
+        let secret = totp_rs::Secret::Encoded(secret.to_string())
+            .to_bytes()
+            .map_err(|e| anyhow::anyhow!("Invalid TOTP secret: {:?}", e))?;
+        // Authenticator apps use ':' as the issuer/account separator
+        totp_rs::TOTP::new(
+            totp_rs::Algorithm::SHA1,
+            6,
+            1,
+            TOTP_STEP,
+            secret,
+            Some(TOTP_ISSUER.to_string()),
+            email.replace(':', ""),
+        )
+        .context("Failed to build TOTP")
+    }
+    
+    pub async fn get(pool: &PgPool, user_id: Uuid) -> anyhow::Result<Option<UserMfa>> {
+        let mfa = sqlx::query_as::<_, UserMfa>(
+            "SELECT user_id, totp_secret, enabled_at FROM user_mfa WHERE user_id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to get MFA settings")?;
+        
+        Ok(mfa)
+    }
+    
+    pub async fn is_enabled(pool: &PgPool, user_id: Uuid) -> anyhow::Result<bool> {
+        Ok(Self::get(pool, user_id)
+            .await?
+            .is_some_and(|mfa| mfa.enabled_at.is_some()))
+    }
+    
+    /// Generate a new secret, replacing any enrollment that was not confirmed
+    pub async fn start_enrollment(pool: &PgPool, user: &User) -> anyhow::Result<TotpSetupResponse> {
+        let secret = totp_rs::Secret::generate_secret().to_encoded().to_string();
+        let totp = Self::totp(&secret, &user.email)?;
+        
+        sqlx::query(
+            r#"
+            INSERT INTO user_mfa (user_id, totp_secret)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE
+            SET totp_secret = $2, last_used_step = NULL, created_at = NOW()
+            WHERE user_mfa.enabled_at IS NULL
+            "#,
+        )
+        .bind(user.id)
+        .bind(&secret)
+        .execute(pool)
+        .await
+        .context("Failed to store TOTP secret")?;
+        
+        Ok(TotpSetupResponse {
+            otpauth_uri: totp.get_url(),
+            secret,
+        })
+    }
+    
+    /// Enable TOTP once the user proved their app generates valid codes
+    /// 
+    /// Returns the first recovery codes, or `None` if the code is wrong.
+    pub async fn confirm_enrollment(
+        pool: &PgPool,
+        user: &User,
+        code: &str,
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        let Some(mfa) = Self::get(pool, user.id).await? else {
+            return Ok(None);
+        };
+        if mfa.enabled_at.is_some() || !Self::verify_totp(pool, &mfa, &user.email, code).await? {
+            return Ok(None);
+        }
+        
+        sqlx::query("UPDATE user_mfa SET enabled_at = NOW() WHERE user_id = $1")
+            .bind(user.id)
+            .execute(pool)
+            .await
+            .context("Failed to enable MFA")?;
+        
+        Self::regenerate_recovery_codes(pool, user.id).await.map(Some)
+    }
+    
+    /// Check a TOTP code or, failing that, use a recovery code
+    pub async fn verify_code(pool: &PgPool, user: &User, code: &str) -> anyhow::Result<bool> {
+        let Some(mfa) = Self::get(pool, user.id).await?.filter(|mfa| mfa.enabled_at.is_some()) else {
+            return Ok(false);
+        };
+        
+        let code = code.trim();
+        if code.len() == 6 && code.chars().all(|c| c.is_ascii_digit()) {
+            return Self::verify_totp(pool, &mfa, &user.email, code).await;
+        }
+        Self::use_recovery_code(pool, user.id, code).await
+    }
+    
+    /// Accept a code of the current, previous or next time step, each step only once
+    async fn verify_totp(pool: &PgPool, mfa: &UserMfa, email: &str, code: &str) -> anyhow::Result<bool> {
+        let totp = Self::totp(&mfa.totp_secret, email)?;
+        let now = Utc::now().timestamp() as u64;
+        
+        let step = [now - TOTP_STEP, now, now + TOTP_STEP]
+            .into_iter()
+            .find(|time| totp.generate(*time) == code)
+            .map(|time| (time / TOTP_STEP) as i64);
+        let Some(step) = step else {
+            return Ok(false);
+        };
+        
+        // Atomic so two requests can't both use the same code
+        let accepted = sqlx::query(
+            r#"
+            UPDATE user_mfa SET last_used_step = $2
+            WHERE user_id = $1 AND (last_used_step IS NULL OR last_used_step < $2)
+            "#,
+        )
+        .bind(mfa.user_id)
+        .bind(step)
+        .execute(pool)
+        .await
+        .context("Failed to record TOTP step")?;
+        
+        Ok(accepted.rows_affected() == 1)
+    }
+    
+    fn normalize_recovery_code(code: &str) -> String {
+        code.chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_lowercase())
+            .collect()
+    }
+    
+    async fn use_recovery_code(pool: &PgPool, user_id: Uuid, code: &str) -> anyhow::Result<bool> {
+        let used = sqlx::query(
+            r#"
+            UPDATE mfa_recovery_codes SET used_at = NOW()
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(AuthService::hash_opaque_token(&Self::normalize_recovery_code(code)))
+        .execute(pool)
+        .await
+        .context("Failed to use recovery code")?;
+        
+        Ok(used.rows_affected() == 1)
+    }
+    
+    /// Replace all recovery codes, the clear codes are only returned here
+    pub async fn regenerate_recovery_codes(pool: &PgPool, user_id: Uuid) -> anyhow::Result<Vec<String>> {
+        use rand::Rng;
+        
+        const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+        let codes: Vec<String> = {
+            let mut rng = rand::thread_rng();
+            (0..RECOVERY_CODE_COUNT)
+                .map(|_| {
+                    let chars: String = (0..10)
+                        .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+                        .collect();
+                    format!("{}-{}", &chars[..5], &chars[5..])
+                })
+                .collect()
+        };
+        
+        let mut tx = pool.begin().await.context("Failed to start transaction")?;
+        
+        sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .context("Failed to delete recovery codes")?;
+        
+        for code in &codes {
+            sqlx::query(
+                "INSERT INTO mfa_recovery_codes (id, user_id, code_hash) VALUES ($1, $2, $3)",
+            )
+            .bind(Uuid::new_v4())
+            .bind(user_id)
+            .bind(AuthService::hash_opaque_token(&Self::normalize_recovery_code(code)))
+            .execute(&mut *tx)
+            .await
+            .context("Failed to store recovery code")?;
+        }
+        
+        tx.commit().await.context("Failed to commit recovery codes")?;
+        
+        Ok(codes)
+    }
+    
+    pub async fn recovery_codes_remaining(pool: &PgPool, user_id: Uuid) -> anyhow::Result<i64> {
+        let remaining: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*)::bigint FROM mfa_recovery_codes WHERE user_id = $1 AND used_at IS NULL",
+        )
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+        .context("Failed to count recovery codes")?;
+        
+        Ok(remaining)
+    }
+    
+    pub async fn disable(pool: &PgPool, user_id: Uuid) -> anyhow::Result<()> {
+        let mut tx = pool.begin().await.context("Failed to start transaction")?;
+        
+        sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .context("Failed to delete recovery codes")?;
+        sqlx::query("DELETE FROM user_mfa WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .context("Failed to disable MFA")?;
+        
+        tx.commit().await.context("Failed to commit MFA removal")?;
+        
+        Ok(())
+    }
+}
+
+/// Service for single sign-on identities and pending OIDC authorization requests
+pub struct OidcService;
+
+impl OidcService {
+    pub async fn save_login_state(
+        pool: &PgPool,
+        state: &str,
+        code_verifier: &str,
+        nonce: &str,
+        ttl: chrono::Duration,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO oidc_login_states (state_hash, code_verifier, nonce, expires_at)
+            VALUES ($1, $2, $3, $4)
+            "#,
+        )
+        .bind(AuthService::hash_opaque_token(state))
+        .bind(code_verifier)
+        .bind(nonce)
+        .bind(Utc::now() + ttl)
+        .execute(pool)
+        .await
+        .context("Failed to save OIDC login state")?;
+        
+        Ok(())
+    }
+    
+    /// PKCE verifier and nonce of a pending request, each state can be used once
+    pub async fn take_login_state(pool: &PgPool, state: &str) -> anyhow::Result<Option<(String, String)>> {
+        let pending = sqlx::query_as::<_, (String, String)>(
+            r#"
+            DELETE FROM oidc_login_states
+            WHERE state_hash = $1 AND expires_at > NOW()
+            RETURNING code_verifier, nonce
+            "#,
+        )
+        .bind(AuthService::hash_opaque_token(state))
+        .fetch_optional(pool)
+        .await
+        .context("Failed to take OIDC login state")?;
+        
+        Ok(pending)
+    }
+    
+    pub async fn find_user(pool: &PgPool, issuer: &str, subject: &str) -> anyhow::Result<Option<User>> {
+        let user = sqlx::query_as::<_, User>(
+            r#"
+            SELECT u.* FROM users u
+            INNER JOIN user_identities i ON i.user_id = u.id
+            WHERE i.issuer = $1 AND i.subject = $2
+            "#,
+        )
+        .bind(issuer)
+        .bind(subject)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to find user by identity")?;
+        
+        Ok(user)
+    }
+    
+    /// Link a provider account to a user, or record a new sign-in of a linked one
+    pub async fn link_identity(
+        pool: &PgPool,
+        user_id: Uuid,
+        issuer: &str,
+        subject: &str,
+        email: Option<&str>,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO user_identities (user_id, issuer, subject, email)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (issuer, subject) DO UPDATE SET email = $4, last_login_at = NOW()
+            "#,
+        )
+        .bind(user_id)
+        .bind(issuer)
+        .bind(subject)
+        .bind(email)
+        .execute(pool)
+        .await
+        .context("Failed to link identity")?;
+        
+        Ok(())
+    }
+    
+    pub async fn cleanup_expired_states(pool: &PgPool) -> anyhow::Result<u64> {
+        let deleted = sqlx::query("DELETE FROM oidc_login_states WHERE expires_at < NOW()")
+            .execute(pool)
+            .await
+            .context("Failed to cleanup OIDC login states")?;
+        
+        Ok(deleted.rows_affected())
+    }
+}
+
+pub struct UserService;
+
+impl UserService {
+    pub async fn mark_email_verified(pool: &PgPool, user_id: Uuid) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE users SET email_verified_at = COALESCE(email_verified_at, NOW()), updated_at = NOW() WHERE id = $1",
+        )
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .context("Failed to verify email")?;
+        
+        Ok(())
+    }
+    
+    pub async fn is_email_verified(pool: &PgPool, user_id: Uuid) -> anyhow::Result<bool> {
+        let verified: Option<bool> = sqlx::query_scalar(
+            "SELECT email_verified_at IS NOT NULL FROM users WHERE id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to check email verification")?;
+        
+        Ok(verified.unwrap_or(false))
+    }
+    
+    pub async fn update_password(
+        pool: &PgPool,
+        user_id: Uuid,
+        password: &str,
+        hashing: &PasswordHashingConfig,
+    ) -> anyhow::Result<()> {
+        let password_hash = AuthService::hash_password(password, hashing)?;
+        
+        sqlx::query("UPDATE users SET password_hash = $2, password_set = TRUE, updated_at = NOW() WHERE id = $1")
+            .bind(user_id)
+            .bind(password_hash)
+            .execute(pool)
+            .await
+            .context("Failed to update password")?;
+        
+        Ok(())
+    }
+    
+    pub async fn create_user(
+        pool: &PgPool,
+        email: &str,
+        username: &str,
+        password: &str,
+        name: Option<&str>,
+        hashing: &PasswordHashingConfig,
+    ) -> anyhow::Result<User> {
+        let password_hash = AuthService::hash_password(password, hashing)?;
+        let user_id = Uuid::new_v4();
+        
+        let user = sqlx::query_as::<_, User>(
+            r#"
+            INSERT INTO users (id, email, username, password_hash, name, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $6)
+            RETURNING *
+            "#,
+        )
+        .bind(user_id)
+        .bind(email)
+        .bind(username)
+        .bind(password_hash)
+        .bind(name)
+        .bind(Utc::now())
+        .fetch_one(pool)
+        .await
+        .context("Failed to create user")?;
+        
+        Ok(user)
+    }
+    
+    /// Account created on first single sign-on, its email was verified by the provider
+    /// 
+    /// It gets a random password nobody knows, a password can be chosen later with a reset.
+    pub async fn create_sso_user(
+        pool: &PgPool,
+        email: &str,
+        username_hint: Option<&str>,
+        name: Option<&str>,
+        hashing: &PasswordHashingConfig,
+    ) -> anyhow::Result<User> {
+        let base = username_hint
+            .unwrap_or_else(|| email.split('@').next().unwrap_or(email))
+            .to_lowercase();
+        let username = Self::available_username(pool, &base).await?;
+        let password_hash = AuthService::hash_password(&AuthService::generate_opaque_token(), hashing)?;
+        
+        let user = sqlx::query_as::<_, User>(
+            r#"
+            INSERT INTO users (id, email, username, password_hash, password_set, name, email_verified_at, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, FALSE, $5, $6, $6, $6)
+            RETURNING *
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(email)
+        .bind(username)
+        .bind(password_hash)
+        .bind(name)
+        .bind(Utc::now())
+        .fetch_one(pool)
+        .await
+        .context("Failed to create user")?;
+        
+        Ok(user)
+    }
+    
+    /// `base`, or `base` followed by a number when it is taken
+    async fn available_username(pool: &PgPool, base: &str) -> anyhow::Result<String> {
+        let mut base: String = base
+            .chars()
+            .filter(|c| c.is_alphanumeric() || matches!(c, '.' | '_' | '-'))
+            .take(40)
+            .collect();
+        while base.chars().count() < 3 {
+            base.push('_');
+        }
+        
+        let mut username = base.clone();
+        let mut counter = 1;
+        while Self::find_by_username(pool, &username).await?.is_some() {
+            username = format!("{}{}", base, counter);
+            counter += 1;
+        }
+        
+        Ok(username)
+    }
+    
+    /// Usernames are made of letters, digits, `.`, `_` and `-`
+    pub fn is_valid_username(username: &str) -> bool {
+        username
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    }
+    
+    /// Change the username and/or the name, `None` when the username is taken
+    pub async fn update_profile(
+        pool: &PgPool,
+        user_id: Uuid,
+        username: Option<&str>,
+        name: Option<Option<&str>>,
+    ) -> anyhow::Result<Option<User>> {
+        let result = sqlx::query_as::<_, User>(
+            r#"
+            UPDATE users SET
+                username_changed_at = CASE
+                    WHEN $2::TEXT IS NOT NULL AND $2 IS DISTINCT FROM username THEN NOW()
+                    ELSE username_changed_at
+                END,
+                username = COALESCE($2, username),
+                name = CASE WHEN $3 THEN $4 ELSE name END,
+                updated_at = NOW()
+            WHERE id = $1
+            RETURNING *
+            "#,
+        )
+        .bind(user_id)
+        .bind(username)
+        .bind(name.is_some())
+        .bind(name.flatten())
+        .fetch_one(pool)
+        .await;
+        
+        match result {
+            Ok(user) => Ok(Some(user)),
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(None),
+            Err(e) => Err(e).context("Failed to update profile"),
+        }
+    }
+    
+    /// Point the user to a new (or no) avatar and return the previous one
+    pub async fn set_avatar(
+        pool: &PgPool,
+        user_id: Uuid,
+        avatar_id: Option<Uuid>,
+    ) -> anyhow::Result<Option<Uuid>> {
+        let previous = sqlx::query_scalar::<_, Option<Uuid>>(
+            r#"
+            UPDATE users u SET avatar_id = $2, avatar_url = $3, updated_at = NOW()
+            FROM (SELECT id, avatar_id FROM users WHERE id = $1 FOR UPDATE) previous
+            WHERE u.id = previous.id
+            RETURNING previous.avatar_id
+            "#,
+        )
+        .bind(user_id)
+        .bind(avatar_id)
+        .bind(avatar_id.map(crate::avatar::url))
+        .fetch_one(pool)
+        .await
+        .context("Failed to update avatar")?;
+        
+        Ok(previous)
+    }
+    
+    pub async fn find_by_email(pool: &PgPool, email: &str) -> anyhow::Result<Option<User>> {
+        let user = sqlx::query_as::<_, User>(
+            "SELECT * FROM users WHERE email = $1",
+        )
+        .bind(email)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to find user")?;
+        
+        Ok(user)
+    }
+    
+    pub async fn find_by_id(pool: &PgPool, user_id: Uuid) -> anyhow::Result<Option<User>> {
+        let user = sqlx::query_as::<_, User>(
+            "SELECT * FROM users WHERE id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to find user")?;
+        
+        Ok(user)
+    }
+    
+    /// Find user by username
+    pub async fn find_by_username(pool: &PgPool, username: &str) -> anyhow::Result<Option<User>> {
+        let user = sqlx::query_as::<_, User>(
+            "SELECT * FROM users WHERE username = $1",
+        )
+        .bind(username)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to find user by username")?;
+        
+        Ok(user)
+    }
+    
+    /// Search users by username, email or name
+    /// This function searches users by:
+    /// - Exact email match (highest priority)
+    /// - Username match (starts with)
+    /// - Email match (contains)
+    /// - Name match (contains)
+    pub async fn search_users(
+        pool: &PgPool,
+        query: &str,
+        limit: i64,
+        exclude_user_id: Option<Uuid>,
+        include_unverified: bool,
+    ) -> anyhow::Result<Vec<User>> {
+        // Trim and normalize the query
+        let query = query.trim();
+        if query.is_empty() {
+            return Ok(vec![]);
+        }
+        
+        // Create search patterns
+        let exact_pattern = query.to_string();
+        let starts_with_pattern = format!("{}%", query);
+        let contains_pattern = format!("%{}%", query);
+        
+        let users = if let Some(exclude_id) = exclude_user_id {
+            sqlx::query_as::<_, User>(
+                r#"
+                SELECT * FROM users
+                WHERE (
+                    email = $1 
+                    OR email ILIKE $2 
+                    OR COALESCE(username, '') = $1
+                    OR COALESCE(username, '') ILIKE $2
+                    OR COALESCE(username, '') ILIKE $3
+                    OR COALESCE(name, '') ILIKE $3
+                    OR email ILIKE $3
+                )
+                AND id != $4
+                AND ($6 OR email_verified_at IS NOT NULL)
+                ORDER BY 
+                    CASE 
+                        WHEN email = $1 THEN 1
+                        WHEN COALESCE(username, '') = $1 THEN 2
+                        WHEN email ILIKE $2 THEN 3
+                        WHEN COALESCE(username, '') ILIKE $2 THEN 4
+                        WHEN email ILIKE $3 THEN 5
+                        WHEN COALESCE(username, '') ILIKE $3 THEN 6
+                        WHEN COALESCE(name, '') ILIKE $3 THEN 7
+                        ELSE 8
+                    END,
+                    created_at DESC
+                LIMIT $5
+                "#,
+            )
+            .bind(&exact_pattern)
+            .bind(&starts_with_pattern)
+            .bind(&contains_pattern)
+            .bind(exclude_id)
+            .bind(limit)
+            .bind(include_unverified)
+            .fetch_all(pool)
+            .await
+            .context("Failed to search users")?
+        } else {
+            sqlx::query_as::<_, User>(
+                r#"
+                SELECT * FROM users
+                WHERE (
+                    email = $1 
+                    OR email ILIKE $2 
+                    OR COALESCE(username, '') = $1
+                    OR COALESCE(username, '') ILIKE $2
+                    OR COALESCE(username, '') ILIKE $3
+                    OR COALESCE(name, '') ILIKE $3
+                    OR email ILIKE $3
+                )
+                AND ($5 OR email_verified_at IS NOT NULL)
+                ORDER BY 
+                    CASE 
+                        WHEN email = $1 THEN 1
+                        WHEN COALESCE(username, '') = $1 THEN 2
+                        WHEN email ILIKE $2 THEN 3
+                        WHEN COALESCE(username, '') ILIKE $2 THEN 4
+                        WHEN email ILIKE $3 THEN 5
+                        WHEN COALESCE(username, '') ILIKE $3 THEN 6
+                        WHEN COALESCE(name, '') ILIKE $3 THEN 7
+                        ELSE 8
+                    END,
+                    created_at DESC
+                LIMIT $4
+                "#,
+            )
+            .bind(&exact_pattern)
+            .bind(&starts_with_pattern)
+            .bind(&contains_pattern)
+            .bind(limit)
+            .bind(include_unverified)
+            .fetch_all(pool)
+            .await
+            .context("Failed to search users")?
+        };
+        
+        Ok(users)
+    }
+    
+    /// Get all users (for contacts list)
+    pub async fn get_all_users(
+        pool: &PgPool,
+        limit: i64,
+        offset: i64,
+        exclude_user_id: Option<Uuid>,
+        include_unverified: bool,
+    ) -> anyhow::Result<Vec<User>> {
+        let users = if let Some(exclude_id) = exclude_user_id {
+            sqlx::query_as::<_, User>(
+                r#"
+                SELECT * FROM users
+                WHERE id != $1 AND ($4 OR email_verified_at IS NOT NULL)
+                ORDER BY created_at DESC LIMIT $2 OFFSET $3
+                "#,
+            )
+            .bind(exclude_id)
+            .bind(limit)
+            .bind(offset)
+            .bind(include_unverified)
+            .fetch_all(pool)
+            .await
+            .context("Failed to get users")?
+        } else {
+            sqlx::query_as::<_, User>(
+                r#"
+                SELECT * FROM users
+                WHERE ($3 OR email_verified_at IS NOT NULL)
+                ORDER BY created_at DESC LIMIT $1 OFFSET $2
+                "#,
+            )
+            .bind(limit)
+            .bind(offset)
+            .bind(include_unverified)
+            .fetch_all(pool)
+            .await
+            .context("Failed to get users")?
+        };
+        
+        Ok(users)
+    }
+}
+
+/// Service for account deletion: grace period, then erasure of everything the user owns
+pub struct AccountDeletionService;
+
+impl AccountDeletionService {
+    pub async fn schedule(
+        pool: &PgPool,
+        user_id: Uuid,
+        grace_period: chrono::Duration,
+    ) -> anyhow::Result<DateTime<Utc>> {
+        let scheduled_at = sqlx::query_scalar::<_, DateTime<Utc>>(
+            "UPDATE users SET deletion_scheduled_at = $2, updated_at = NOW() WHERE id = $1 RETURNING deletion_scheduled_at",
+        )
+        .bind(user_id)
+        .bind(Utc::now() + grace_period)
+        .fetch_one(pool)
+        .await
+        .context("Failed to schedule account deletion")?;
+        
+        Ok(scheduled_at)
+    }
+    
+    /// Whether a deletion was pending
+    pub async fn cancel(pool: &PgPool, user_id: Uuid) -> anyhow::Result<bool> {
+        let result = sqlx::query(
+            "UPDATE users SET deletion_scheduled_at = NULL, updated_at = NOW() WHERE id = $1 AND deletion_scheduled_at IS NOT NULL",
+        )
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .context("Failed to cancel account deletion")?;
+        
+        Ok(result.rows_affected() > 0)
+    }
+    
+    pub async fn get_due_accounts(pool: &PgPool) -> anyhow::Result<Vec<Uuid>> {
+        let user_ids = sqlx::query_scalar::<_, Uuid>(
+            "SELECT id FROM users WHERE deletion_scheduled_at <= NOW()",
+        )
+        .fetch_all(pool)
+        .await
+        .context("Failed to get accounts to delete")?;
+        
+        Ok(user_ids)
+    }
+    
+    /// Erase an account and return its former contacts
+    /// 
+    /// Channels created by the user go to their oldest admin (or member), channels
+    /// left without members are deleted. The remaining rows go with the user (ON DELETE CASCADE).
+    pub async fn erase(pool: &PgPool, user_id: Uuid) -> anyhow::Result<Vec<Uuid>> {
+        let mut tx = pool.begin().await.context("Failed to start transaction")?;
+        
+        let contacts = sqlx::query_scalar::<_, Uuid>(CONTACTS_SQL)
+            .bind(user_id)
+            .fetch_all(&mut *tx)
+            .await
+            .context("Failed to get contacts")?;
+        
+        let heirs = sqlx::query_as::<_, (Uuid, Uuid)>(
+            r#"
+            UPDATE channels c SET creator_id = heir.user_id, updated_at = NOW()
+            FROM (
+                SELECT DISTINCT ON (m.channel_id) m.channel_id, m.user_id
+                FROM channel_members m
+                INNER JOIN channels ch ON ch.id = m.channel_id
+                WHERE ch.creator_id = $1 AND m.user_id <> $1
+                ORDER BY m.channel_id, (m.role = 'admin') DESC, m.joined_at ASC
+            ) heir
+            WHERE c.id = heir.channel_id
+            RETURNING c.id, heir.user_id
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await
+        .context("Failed to hand over channels")?;
+        for (channel_id, heir_id) in heirs {
+            sqlx::query("UPDATE channel_members SET role = 'admin' WHERE channel_id = $1 AND user_id = $2")
+                .bind(channel_id)
+                .bind(heir_id)
+                .execute(&mut *tx)
+                .await
+                .context("Failed to promote new channel owner")?;
+        }
+        
+        // The identity key log rejects deletions outside of an account erasure (migration 029)
+        sqlx::query("SELECT set_config('kisse.erasing_account', 'on', true)")
+            .execute(&mut *tx)
+            .await
+            .context("Failed to allow identity key log erasure")?;
+        
+        // Every table holding the user's rows is listed rather than left to the cascades from
+        // users, so nothing is missed if a foreign key changes
+        for statement in [
+            "DELETE FROM channels WHERE creator_id = $1",
+            "DELETE FROM encrypted_content WHERE message_id IN (SELECT id FROM messages WHERE sender_id = $1 OR recipient_id = $1)",
+            "DELETE FROM messages WHERE sender_id = $1 OR recipient_id = $1",
+            "DELETE FROM conversations WHERE user1_id = $1 OR user2_id = $1",
+            "DELETE FROM channel_messages WHERE sender_id = $1",
+            "DELETE FROM channel_members WHERE user_id = $1",
+            "DELETE FROM story_views WHERE viewer_id = $1",
+            "DELETE FROM stories WHERE user_id = $1",
+            "DELETE FROM calls WHERE caller_id = $1 OR recipient_id = $1",
+            "DELETE FROM user_presence WHERE user_id = $1",
+            "DELETE FROM refresh_tokens WHERE session_id IN (SELECT id FROM sessions WHERE user_id = $1)",
+            "DELETE FROM sessions WHERE user_id = $1",
+            "DELETE FROM one_time_prekeys WHERE device_id IN (SELECT id FROM devices WHERE user_id = $1)",
+            "DELETE FROM device_keys WHERE user_id = $1",
+            "DELETE FROM identity_key_log WHERE user_id = $1",
+            "DELETE FROM pending_events WHERE recipient_id = $1",
+            "DELETE FROM sealed_messages WHERE recipient_id = $1",
+            "DELETE FROM encrypted_content WHERE device_id IN (SELECT id FROM devices WHERE user_id = $1)",
+            "DELETE FROM devices WHERE user_id = $1",
+            "DELETE FROM user_mfa WHERE user_id = $1",
+            "DELETE FROM mfa_recovery_codes WHERE user_id = $1",
+            "DELETE FROM user_tokens WHERE user_id = $1",
+            "DELETE FROM user_identities WHERE user_id = $1",
+            "DELETE FROM data_exports WHERE user_id = $1",
+            "DELETE FROM security_events WHERE user_id = $1",
+            "DELETE FROM login_throttle WHERE key = (SELECT 'account:' || LOWER(TRIM(email)) FROM users WHERE id = $1)",
+            "DELETE FROM users WHERE id = $1",
+        ] {
+            sqlx::query(statement)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await
+                .with_context(|| format!("Failed to erase account: {}", statement))?;
+        }
+        
+        tx.commit().await.context("Failed to commit account erasure")?;
+        
+        Ok(contacts)
+    }
+}
+
+pub struct DataExportService;
+
+impl DataExportService {
+    const COLUMNS: &'static str = "id, status, size_bytes, created_at, completed_at, expires_at";
+    
+    /// Queue an export, `None` if one is already pending for the user
+    pub async fn request(pool: &PgPool, user_id: Uuid) -> anyhow::Result<Option<DataExport>> {
+        let export = sqlx::query_as::<_, DataExport>(&format!(
+            r#"
+            INSERT INTO data_exports (user_id)
+            SELECT $1
+            WHERE NOT EXISTS (
+                SELECT 1 FROM data_exports WHERE user_id = $1 AND status IN ('pending', 'processing')
+            )
+            RETURNING {}
+            "#,
+            Self::COLUMNS
+        ))
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to request data export")?;
+        
+        Ok(export)
+    }
+    
+    pub async fn list_for_user(pool: &PgPool, user_id: Uuid) -> anyhow::Result<Vec<DataExport>> {
+        let exports = sqlx::query_as::<_, DataExport>(&format!(
+            "SELECT {} FROM data_exports WHERE user_id = $1 ORDER BY created_at DESC",
+            Self::COLUMNS
+        ))
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+        .context("Failed to list data exports")?;
+        
+        Ok(exports)
+    }
+    
+    /// Take the oldest pending export, safe with several backend instances
+    pub async fn claim_next(pool: &PgPool) -> anyhow::Result<Option<(Uuid, Uuid)>> {
+        let claimed = sqlx::query_as::<_, (Uuid, Uuid)>(
+            r#"
+            UPDATE data_exports SET status = 'processing'
+            WHERE id = (
+                SELECT id FROM data_exports
+                WHERE status = 'pending'
+                ORDER BY created_at
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, user_id
+            "#,
+        )
+        .fetch_optional(pool)
+        .await
+        .context("Failed to claim data export")?;
+        
+        Ok(claimed)
+    }
+    
+    /// Mark the archive as ready and return the download token
+    pub async fn complete(
+        pool: &PgPool,
+        export_id: Uuid,
+        file_path: &str,
+        size_bytes: u64,
+        expires_at: DateTime<Utc>,
+    ) -> anyhow::Result<String> {
+        let token = AuthService::generate_opaque_token();
+        
+        sqlx::query(
+            r#"
+            UPDATE data_exports
+            SET status = 'ready', file_path = $2, size_bytes = $3, download_token_hash = $4,
+                completed_at = NOW(), expires_at = $5
+            WHERE id = $1
+            "#,
+        )
+        .bind(export_id)
+        .bind(file_path)
+        .bind(size_bytes as i64)
+        .bind(AuthService::hash_opaque_token(&token))
+        .bind(expires_at)
+        .execute(pool)
+        .await
+        .context("Failed to complete data export")?;
+        
+        Ok(token)
+    }
+    
+    pub async fn fail(pool: &PgPool, export_id: Uuid, error: &str) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE data_exports SET status = 'failed', error = $2, completed_at = NOW() WHERE id = $1",
+        )
+        .bind(export_id)
+        .bind(error)
+        .execute(pool)
+        .await
+        .context("Failed to mark data export as failed")?;
+        
+        Ok(())
+    }
+    
+    /// Archive path of a ready, unexpired export
+    pub async fn find_download(pool: &PgPool, token: &str) -> anyhow::Result<Option<String>> {
+        let file_path = sqlx::query_scalar::<_, String>(
+            r#"
+            SELECT file_path FROM data_exports
+            WHERE download_token_hash = $1 AND status = 'ready' AND expires_at > NOW()
+            "#,
+        )
+        .bind(AuthService::hash_opaque_token(token))
+        .fetch_optional(pool)
+        .await
+        .context("Failed to find data export")?;
+        
+        Ok(file_path)
+    }
+    
+    /// Delete expired exports with their archives, return how many were deleted
+    /// 
+    /// Exports left processing by a stopped instance are marked as failed so the user can ask again.
+    pub async fn cleanup_expired(pool: &PgPool) -> anyhow::Result<usize> {
+        sqlx::query(
+            r#"
+            UPDATE data_exports SET status = 'failed', error = 'interrupted', completed_at = NOW()
+            WHERE status = 'processing' AND created_at < NOW() - INTERVAL '1 hour'
+            "#,
+        )
+        .execute(pool)
+        .await
+        .context("Failed to fail interrupted data exports")?;
+        
+        let file_paths = sqlx::query_scalar::<_, Option<String>>(
+            r#"
+            DELETE FROM data_exports
+            WHERE expires_at <= NOW() OR (status = 'failed' AND created_at < NOW() - INTERVAL '7 days')
+            RETURNING file_path
+            "#,
+        )
+        .fetch_all(pool)
+        .await
+        .context("Failed to delete expired data exports")?;
+        
+        Self::remove_files(&file_paths).await;
+        Ok(file_paths.len())
+    }
+    
+    /// Delete every export of a user, before the account is erased
+    pub async fn delete_for_user(pool: &PgPool, user_id: Uuid) -> anyhow::Result<()> {
+        let file_paths = sqlx::query_scalar::<_, Option<String>>(
+            "DELETE FROM data_exports WHERE user_id = $1 RETURNING file_path",
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+        .context("Failed to delete data exports")?;
+        
+        Self::remove_files(&file_paths).await;
+        Ok(())
+    }
+    
+    async fn remove_files(file_paths: &[Option<String>]) {
+        for file_path in file_paths.iter().flatten() {
+            if let Err(e) = tokio::fs::remove_file(file_path).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!("Failed to remove export archive {}: {}", file_path, e);
+                }
+            }
+        }
+    }
+}
+
+/// Service for message metadata management
+/// 
+/// SECURITY: This service handles ONLY metadata routing.
+/// It does NOT store encrypted content or encryption keys.
+/// Signal Protocol encryption/decryption is handled entirely client-side.
+pub struct MessageService;
+
+impl MessageService {
+    /// Create message metadata entry
     /// 
     /// This function stores ONLY metadata (IDs, timestamps, session reference).
     /// The encrypted content is handled separately by the client.
+    /// 
+    /// When the sender provides a `client_message_id` that was already used,
+    /// the existing message is returned instead of creating a duplicate.
+    /// The boolean is true when a new message was created.
     pub async fn create_message(
         pool: &PgPool,
-        sender_id: Uuid,
+        sender_id: Uuid,
+        recipient_id: Uuid,
+        message_type: &str,
+        session_id: Option<&str>,
+        client_message_id: Option<&str>,
+    ) -> anyhow::Result<(Message, bool)> {
+        if let Some(existing) = Self::find_by_client_id(pool, sender_id, client_message_id).await? {
+            if existing.recipient_id != recipient_id {
+                anyhow::bail!("Client message id reused for another recipient");
+            }
+            return Ok((existing, false));
+        }
+        
+        // Get or create conversation
+        let conversation = ConversationService::get_or_create_conversation(
+            pool,
+            sender_id,
+            recipient_id,
+        )
+        .await?;
+        
+        let message_id = Uuid::new_v4();
+        let timestamp = Utc::now();
+        
+        // A concurrent retry may insert the same key between the lookup and here
+        let message = sqlx::query_as::<_, Message>(
+            r#"
+            INSERT INTO messages (id, conversation_id, sender_id, recipient_id, message_type, timestamp, session_id, is_read, client_message_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, false, $8)
+            ON CONFLICT (sender_id, client_message_id) WHERE client_message_id IS NOT NULL
+            DO NOTHING
+            RETURNING *
+            "#,
+        )
+        .bind(message_id)
+        .bind(conversation.id)
+        .bind(sender_id)
+        .bind(recipient_id)
+        .bind(message_type)
+        .bind(timestamp)
+        .bind(session_id)
+        .bind(client_message_id)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to create message")?;
+        
+        let message = match message {
+            Some(message) => message,
+            None => {
+                let existing = Self::find_by_client_id(pool, sender_id, client_message_id)
+                    .await?
+                    .context("Conflicting message not found")?;
+                return Ok((existing, false));
+            }
+        };
+        
+        // Update conversation
+        sqlx::query(
+            r#"
+            UPDATE conversations 
+            SET last_message_id = $1, last_message_time = $2, updated_at = $2
+            WHERE id = $3
+            "#,
+        )
+        .bind(message_id)
+        .bind(timestamp)
+        .bind(conversation.id)
+        .execute(pool)
+        .await
+        .context("Failed to update conversation")?;
+        
+        Ok((message, true))
+    }
+    
+    async fn find_by_client_id(
+        pool: &PgPool,
+        sender_id: Uuid,
+        client_message_id: Option<&str>,
+    ) -> anyhow::Result<Option<Message>> {
+        let client_message_id = match client_message_id {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        
+        let message = sqlx::query_as::<_, Message>(
+            "SELECT * FROM messages WHERE sender_id = $1 AND client_message_id = $2",
+        )
+        .bind(sender_id)
+        .bind(client_message_id)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to find message by client id")?;
+        
+        Ok(message)
+    }
+    
+    /// Mark messages as delivered to their recipient
+    /// 
+    /// Returns only the messages whose state changed, so that the sender
+    /// is notified once per message even if several devices acknowledge it.
+    pub async fn mark_as_delivered(
+        pool: &PgPool,
+        message_ids: &[Uuid],
         recipient_id: Uuid,
-        message_type: &str,
-        session_id: Option<&str>,
-    ) -> anyhow::Result<Message> {
-        // Get or create conversation
-        let conversation = ConversationService::get_or_create_conversation(
-            pool,
-            sender_id,
-            recipient_id,
+    ) -> anyhow::Result<Vec<Message>> {
+        let messages = sqlx::query_as::<_, Message>(
+            r#"
+            UPDATE messages
+            SET delivered_at = $1
+            WHERE id = ANY($2) AND recipient_id = $3 AND delivered_at IS NULL
+            RETURNING *
+            "#,
         )
-        .await?;
-        
-        let message_id = Uuid::new_v4();
-        let timestamp = Utc::now();
+        .bind(Utc::now())
+        .bind(message_ids)
+        .bind(recipient_id)
+        .fetch_all(pool)
+        .await
+        .context("Failed to mark messages as delivered")?;
         
+        Ok(messages)
+    }
+    
+    /// Mark a message as read by its recipient
+    /// 
+    /// Returns the updated message, or None if it was already read
+    /// or the reader is not its recipient.
+    pub async fn mark_as_read(
+        pool: &PgPool,
+        message_id: Uuid,
+        reader_id: Uuid,
+    ) -> anyhow::Result<Option<Message>> {
         let message = sqlx::query_as::<_, Message>(
             r#"
-            INSERT INTO messages (id, conversation_id, sender_id, recipient_id, message_type, timestamp, session_id, is_read)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, false)
+            UPDATE messages 
+            SET is_read = true,
+                read_at = $1,
+                delivered_at = COALESCE(delivered_at, $1)
+            WHERE id = $2 AND recipient_id = $3 AND is_read = false
             RETURNING *
             "#,
         )
+        .bind(Utc::now())
         .bind(message_id)
-        .bind(conversation.id)
-        .bind(sender_id)
+        .bind(reader_id)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to mark message as read")?;
+        
+        Ok(message)
+    }
+    
+    pub async fn get_conversation_messages(
+        pool: &PgPool,
+        conversation_id: Uuid,
+        user_id: Uuid,
+        page: &PageRequest,
+    ) -> anyhow::Result<PaginatedResponse<Message>> {
+        let cursor = page.cursor();
+        let (order, cmp) = page.sql_order();
+        
+        let messages = sqlx::query_as::<_, Message>(&format!(
+            r#"
+            SELECT * FROM messages
+            WHERE conversation_id = $1 AND (sender_id = $2 OR recipient_id = $2)
+            AND ($3::timestamptz IS NULL OR (timestamp, id) {cmp} ($3, $4::uuid))
+            ORDER BY timestamp {order}, id {order}
+            LIMIT $5
+            "#,
+        ))
+        .bind(conversation_id)
+        .bind(user_id)
+        .bind(cursor.map(|c| c.timestamp))
+        .bind(cursor.map(|c| c.id))
+        .bind(page.limit + 1)
+        .fetch_all(pool)
+        .await
+        .context("Failed to get messages")?;
+        
+        Ok(page.build_page(messages, |m| Cursor {
+            timestamp: m.timestamp,
+            id: m.id,
+        }))
+    }
+}
+
+/// Service for encrypted content storage
+/// 
+/// SECURITY: This service stores encrypted content as opaque binary data.
+/// The backend cannot read or decrypt the content - it's just a storage layer.
+/// All encryption/decryption is handled client-side using Signal Protocol.
+pub struct EncryptedContentService;
+
+impl EncryptedContentService {
+    /// Store the encrypted content of a message, one ciphertext per destination device
+    /// 
+    /// Each blob is `(device_id, content, content_hash)`, a blob without device is readable
+    /// by every device. The content is stored as opaque binary data.
+    /// The backend cannot read or decrypt it.
+    pub async fn store_content(
+        pool: &PgPool,
+        message_id: Uuid,
+        blobs: &[(Option<Uuid>, Vec<u8>, Option<String>)],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> anyhow::Result<()> {
+        let mut tx = pool.begin().await.context("Failed to start transaction")?;
+        
+        for (device_id, content_data, content_hash) in blobs {
+            sqlx::query(
+                r#"
+                INSERT INTO encrypted_content (message_id, device_id, content_data, content_hash, expires_at)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (message_id, device_id)
+                DO UPDATE SET 
+                    content_data = $3,
+                    content_hash = $4,
+                    expires_at = $5
+                "#,
+            )
+            .bind(message_id)
+            .bind(device_id)
+            .bind(content_data)
+            .bind(content_hash)
+            .bind(expires_at)
+            .execute(&mut *tx)
+            .await
+            .context("Failed to store encrypted content")?;
+        }
+        
+        tx.commit().await.context("Failed to commit encrypted content")?;
+        Ok(())
+    }
+    
+    /// Retrieve the encrypted content of a message for a device
+    /// 
+    /// The ciphertext of the device comes first, then the one shared by every device.
+    /// Returns the encrypted content as opaque binary data.
+    /// The backend cannot decrypt it.
+    pub async fn get_content(
+        pool: &PgPool,
+        message_id: Uuid,
+        device_id: Option<Uuid>,
+    ) -> anyhow::Result<Option<EncryptedContent>> {
+        let content = sqlx::query_as::<_, EncryptedContent>(
+            r#"
+            SELECT message_id, device_id, content_data, content_hash, created_at
+            FROM encrypted_content
+            WHERE message_id = $1
+            AND (device_id IS NULL OR device_id = $2)
+            AND (expires_at IS NULL OR expires_at > NOW())
+            ORDER BY device_id NULLS LAST
+            LIMIT 1
+            "#,
+        )
+        .bind(message_id)
+        .bind(device_id)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to get encrypted content")?;
+        
+        Ok(content)
+    }
+    
+    /// Delete expired content
+    pub async fn cleanup_expired(pool: &PgPool) -> anyhow::Result<u64> {
+        let deleted = sqlx::query(
+            "DELETE FROM encrypted_content WHERE expires_at < NOW()",
+        )
+        .execute(pool)
+        .await
+        .context("Failed to cleanup expired content")?;
+        
+        Ok(deleted.rows_affected())
+    }
+}
+
+/// Service for the offline event queue
+/// 
+/// Stores WebSocket events for recipients without a live connection.
+/// Events are the metadata-only WebSocket payloads, never message content.
+pub struct PendingEventService;
+
+impl PendingEventService {
+    pub async fn enqueue(
+        pool: &PgPool,
+        recipient_id: Uuid,
+        event: &WebSocketMessage,
+        ttl: chrono::Duration,
+    ) -> anyhow::Result<PendingEvent> {
+        let event_json = serde_json::to_string(event).context("Failed to serialize event")?;
+        let now = Utc::now();
+        
+        let pending = sqlx::query_as::<_, PendingEvent>(
+            r#"
+            INSERT INTO pending_events (id, recipient_id, event, created_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING *
+            "#,
+        )
+        .bind(Uuid::new_v4())
         .bind(recipient_id)
-        .bind(message_type)
-        .bind(timestamp)
-        .bind(session_id)
+        .bind(event_json)
+        .bind(now)
+        .bind(now + ttl)
         .fetch_one(pool)
         .await
-        .context("Failed to create message")?;
+        .context("Failed to enqueue pending event")?;
         
-        // Update conversation
-        sqlx::query(
+        Ok(pending)
+    }
+    
+    /// Get a page of the unexpired events of a recipient queued after `after_seq`, in delivery order
+    pub async fn get_pending(
+        pool: &PgPool,
+        recipient_id: Uuid,
+        after_seq: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<PendingEvent>> {
+        let events = sqlx::query_as::<_, PendingEvent>(
             r#"
-            UPDATE conversations 
-            SET last_message_id = $1, last_message_time = $2, updated_at = $2
-            WHERE id = $3
+            SELECT * FROM pending_events
+            WHERE recipient_id = $1 AND seq > $2 AND expires_at > NOW()
+            ORDER BY seq ASC
+            LIMIT $3
             "#,
         )
-        .bind(message_id)
-        .bind(timestamp)
-        .bind(conversation.id)
+        .bind(recipient_id)
+        .bind(after_seq)
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+        .context("Failed to get pending events")?;
+        
+        Ok(events)
+    }
+    
+    /// Count the unexpired events of a recipient queued up to `through_seq` and not acknowledged yet
+    pub async fn count_unacknowledged(
+        pool: &PgPool,
+        recipient_id: Uuid,
+        through_seq: i64,
+    ) -> anyhow::Result<i64> {
+        let count: i64 = sqlx::query_scalar(
+            r#"
+            SELECT COUNT(*) FROM pending_events
+            WHERE recipient_id = $1 AND seq <= $2 AND expires_at > NOW()
+            "#,
+        )
+        .bind(recipient_id)
+        .bind(through_seq)
+        .fetch_one(pool)
+        .await
+        .context("Failed to count pending events")?;
+        
+        Ok(count)
+    }
+    
+    /// Delete events acknowledged by the recipient
+    pub async fn acknowledge(
+        pool: &PgPool,
+        recipient_id: Uuid,
+        event_ids: &[Uuid],
+    ) -> anyhow::Result<u64> {
+        let deleted = sqlx::query(
+            "DELETE FROM pending_events WHERE recipient_id = $1 AND id = ANY($2)",
+        )
+        .bind(recipient_id)
+        .bind(event_ids)
+        .execute(pool)
+        .await
+        .context("Failed to acknowledge pending events")?;
+        
+        Ok(deleted.rows_affected())
+    }
+    
+    pub async fn cleanup_expired(pool: &PgPool) -> anyhow::Result<u64> {
+        let deleted = sqlx::query(
+            "DELETE FROM pending_events WHERE expires_at < NOW()",
+        )
         .execute(pool)
         .await
-        .context("Failed to update conversation")?;
+        .context("Failed to cleanup expired pending events")?;
+        
+        Ok(deleted.rows_affected())
+    }
+}
+
+/// Key transparency log of identity keys
+/// 
+/// Every identity key a device publishes is appended to the log of its user, each entry
+/// committing to the previous one, so clients can detect a key swapped behind their back.
+pub struct IdentityKeyLogService;
+
+impl IdentityKeyLogService {
+    /// SHA-256(previous hash || log index || device id || identity key)
+    pub fn entry_hash(previous_hash: &[u8], log_index: i64, device_id: Uuid, identity_key: &[u8]) -> Vec<u8> {
+        use sha2::{Digest, Sha256};
+        
+        let mut hasher = Sha256::new();
+        hasher.update(previous_hash);
+        hasher.update(log_index.to_be_bytes());
+        hasher.update(device_id.as_bytes());
+        hasher.update(identity_key);
+        hasher.finalize().to_vec()
+    }
+    
+    /// Whether consecutive entries are correctly chained, starting from the first one's previous hash
+    pub fn verify_chain(entries: &[IdentityKeyLogEntry]) -> bool {
+        let Some(first) = entries.first() else {
+            return true;
+        };
+        let mut previous_hash = first.previous_hash.clone();
+        for (log_index, entry) in (first.log_index..).zip(entries) {
+            if entry.log_index != log_index
+                || entry.previous_hash != previous_hash
+                || entry.entry_hash
+                    != Self::entry_hash(&previous_hash, log_index, entry.device_id, &entry.identity_key)
+            {
+                return false;
+            }
+            previous_hash = entry.entry_hash.clone();
+        }
+        true
+    }
+    
+    /// Append an identity key to the log of a user
+    /// 
+    /// The user row is locked so that two devices publishing at once are chained one after the other.
+    pub async fn append(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        user_id: Uuid,
+        device_id: Uuid,
+        identity_key: &[u8],
+    ) -> anyhow::Result<IdentityKeyLogEntry> {
+        sqlx::query("SELECT id FROM users WHERE id = $1 FOR UPDATE")
+            .bind(user_id)
+            .execute(&mut **tx)
+            .await
+            .context("Failed to lock user")?;
+        
+        let head = sqlx::query_as::<_, (i64, Vec<u8>)>(
+            "SELECT log_index, entry_hash FROM identity_key_log WHERE user_id = $1 ORDER BY log_index DESC LIMIT 1",
+        )
+        .bind(user_id)
+        .fetch_optional(&mut **tx)
+        .await
+        .context("Failed to get identity key log head")?;
+        let (log_index, previous_hash) = match head {
+            Some((log_index, entry_hash)) => (log_index + 1, entry_hash),
+            None => (0, vec![0u8; 32]),
+        };
+        
+        let entry = sqlx::query_as::<_, IdentityKeyLogEntry>(
+            r#"
+            INSERT INTO identity_key_log (user_id, log_index, device_id, identity_key, previous_hash, entry_hash)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING *
+            "#,
+        )
+        .bind(user_id)
+        .bind(log_index)
+        .bind(device_id)
+        .bind(identity_key)
+        .bind(&previous_hash)
+        .bind(Self::entry_hash(&previous_hash, log_index, device_id, identity_key))
+        .fetch_one(&mut **tx)
+        .await
+        .context("Failed to append to identity key log")?;
+        
+        Ok(entry)
+    }
+    
+    /// Latest entry of an identity key followed by every later entry up to the head of the log
+    /// 
+    /// Empty when the key was never published by the user (or that device).
+    pub async fn get_inclusion_proof(
+        pool: &PgPool,
+        user_id: Uuid,
+        identity_key: &[u8],
+        device_id: Option<Uuid>,
+    ) -> anyhow::Result<Vec<IdentityKeyLogEntry>> {
+        let entries = sqlx::query_as::<_, IdentityKeyLogEntry>(
+            r#"
+            SELECT * FROM identity_key_log
+            WHERE user_id = $1 AND log_index >= (
+                SELECT MAX(log_index) FROM identity_key_log
+                WHERE user_id = $1 AND identity_key = $2
+                AND ($3::uuid IS NULL OR device_id = $3)
+            )
+            ORDER BY log_index ASC
+            "#,
+        )
+        .bind(user_id)
+        .bind(identity_key)
+        .bind(device_id)
+        .fetch_all(pool)
+        .await
+        .context("Failed to get inclusion proof")?;
+        
+        Ok(entries)
+    }
+    
+    /// Whether publishing `identity_key` on a device would log more than `max` keys since `since`
+    /// 
+    /// Republishing the current key of the device logs nothing and is never refused.
+    pub async fn exceeds_change_limit(
+        pool: &PgPool,
+        user_id: Uuid,
+        device_id: Uuid,
+        identity_key: &[u8],
+        since: DateTime<Utc>,
+        max: i64,
+    ) -> anyhow::Result<bool> {
+        let exceeded = sqlx::query_scalar::<_, Option<bool>>(
+            r#"
+            SELECT COUNT(*) FILTER (WHERE created_at > $4) >= $5
+                AND (ARRAY_AGG(identity_key ORDER BY log_index DESC))[1] IS DISTINCT FROM $3
+            FROM identity_key_log
+            WHERE user_id = $1 AND device_id = $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(device_id)
+        .bind(identity_key)
+        .bind(since)
+        .bind(max)
+        .fetch_one(pool)
+        .await
+        .context("Failed to count identity key changes")?;
+        
+        Ok(exceeded.unwrap_or(false))
+    }
+}
+
+/// Service for sealed sender messages
+/// 
+/// The server never learns who sent a sealed message: the sender proves they may write to the
+/// recipient with a delivery token derived from the recipient's profile, and the envelopes
+/// are only stored for the recipient's devices until they acknowledge them.
+pub struct SealedMessageService;
+
+impl SealedMessageService {
+    /// Sealed envelopes not acknowledged by then are dropped
+    const TTL_DAYS: i64 = 30;
+    
+    /// Envelopes a device can have waiting, in number and in total size
+    const MAX_PENDING_PER_DEVICE: i64 = 1000;
+    const MAX_PENDING_BYTES_PER_DEVICE: i64 = 64 * 1024 * 1024;
+    
+    /// Register the delivery token of a user, `None` refuses sealed sender messages
+    pub async fn set_access_key(
+        pool: &PgPool,
+        user_id: Uuid,
+        access_key: Option<&[u8]>,
+    ) -> anyhow::Result<()> {
+        sqlx::query("UPDATE users SET unidentified_access_key_hash = $1 WHERE id = $2")
+            .bind(access_key.map(Self::hash_access_key))
+            .bind(user_id)
+            .execute(pool)
+            .await
+            .context("Failed to set unidentified access key")?;
+        
+        Ok(())
+    }
+    
+    /// Whether the delivery token allows sending sealed messages to the recipient
+    pub async fn verify_access_key(
+        pool: &PgPool,
+        recipient_id: Uuid,
+        access_key: &[u8],
+    ) -> anyhow::Result<bool> {
+        let allowed = sqlx::query_scalar::<_, bool>(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM users
+                WHERE id = $1 AND unidentified_access_key_hash = $2
+            )
+            "#,
+        )
+        .bind(recipient_id)
+        .bind(Self::hash_access_key(access_key))
+        .fetch_one(pool)
+        .await
+        .context("Failed to verify unidentified access key")?;
         
-        Ok(message)
+        Ok(allowed)
     }
     
-    pub async fn mark_as_read(
+    fn hash_access_key(access_key: &[u8]) -> String {
+        use base64::{engine::general_purpose, Engine as _};
+        AuthService::hash_opaque_token(&general_purpose::STANDARD.encode(access_key))
+    }
+    
+    /// Store one envelope per destination device, `envelopes` is `(device_id, envelope)`
+    /// 
+    /// Returns `None` and stores nothing when a destination device has no room left.
+    pub async fn store(
         pool: &PgPool,
-        message_id: Uuid,
-        reader_id: Uuid,
-    ) -> anyhow::Result<()> {
-        sqlx::query(
+        recipient_id: Uuid,
+        envelopes: &[(Uuid, Vec<u8>)],
+    ) -> anyhow::Result<Option<Vec<SealedMessage>>> {
+        let expires_at = Utc::now() + chrono::Duration::days(Self::TTL_DAYS);
+        let mut tx = pool.begin().await.context("Failed to start transaction")?;
+        
+        // Lock the destination devices so that concurrent senders cannot overfill them
+        let device_ids: Vec<Uuid> = envelopes.iter().map(|(device_id, _)| *device_id).collect();
+        sqlx::query("SELECT id FROM devices WHERE id = ANY($1) ORDER BY id FOR UPDATE")
+            .bind(&device_ids)
+            .execute(&mut *tx)
+            .await
+            .context("Failed to lock devices")?;
+        
+        let stored = sqlx::query_as::<_, (Uuid, i64, i64)>(
             r#"
-            UPDATE messages 
-            SET is_read = true, read_at = $1
-            WHERE id = $2 AND recipient_id = $3
+            SELECT device_id, COUNT(*), COALESCE(SUM(octet_length(envelope)), 0)::BIGINT
+            FROM sealed_messages
+            WHERE device_id = ANY($1) AND expires_at > NOW()
+            GROUP BY device_id
             "#,
         )
-        .bind(Utc::now())
-        .bind(message_id)
-        .bind(reader_id)
-        .execute(pool)
+        .bind(&device_ids)
+        .fetch_all(&mut *tx)
         .await
-        .context("Failed to mark message as read")?;
+        .context("Failed to count sealed messages")?;
         
-        Ok(())
+        for (device_id, envelope) in envelopes {
+            let (count, bytes) = stored
+                .iter()
+                .find(|(id, _, _)| id == device_id)
+                .map_or((0, 0), |(_, count, bytes)| (*count, *bytes));
+            if count >= Self::MAX_PENDING_PER_DEVICE
+                || bytes + envelope.len() as i64 > Self::MAX_PENDING_BYTES_PER_DEVICE
+            {
+                return Ok(None);
+            }
+        }
+        
+        let mut messages = Vec::with_capacity(envelopes.len());
+        for (device_id, envelope) in envelopes {
+            let message = sqlx::query_as::<_, SealedMessage>(
+                r#"
+                INSERT INTO sealed_messages (recipient_id, device_id, envelope, expires_at)
+                VALUES ($1, $2, $3, $4)
+                RETURNING id, seq, device_id, envelope, created_at
+                "#,
+            )
+            .bind(recipient_id)
+            .bind(device_id)
+            .bind(envelope)
+            .bind(expires_at)
+            .fetch_one(&mut *tx)
+            .await
+            .context("Failed to store sealed message")?;
+            messages.push(message);
+        }
+        
+        tx.commit().await.context("Failed to commit sealed messages")?;
+        Ok(Some(messages))
     }
     
-    pub async fn get_conversation_messages(
+    /// Get a page of the unacknowledged envelopes of a device stored after `after_seq`, in delivery order
+    pub async fn get_pending(
         pool: &PgPool,
-        conversation_id: Uuid,
-        user_id: Uuid,
+        device_id: Uuid,
+        after_seq: i64,
         limit: i64,
-    ) -> anyhow::Result<Vec<Message>> {
-        let messages = sqlx::query_as::<_, Message>(
+    ) -> anyhow::Result<Vec<SealedMessage>> {
+        let messages = sqlx::query_as::<_, SealedMessage>(
             r#"
-            SELECT * FROM messages
-            WHERE conversation_id = $1 AND (sender_id = $2 OR recipient_id = $2)
-            ORDER BY timestamp DESC
+            SELECT id, seq, device_id, envelope, created_at FROM sealed_messages
+            WHERE device_id = $1 AND seq > $2 AND expires_at > NOW()
+            ORDER BY seq ASC
             LIMIT $3
             "#,
         )
-        .bind(conversation_id)
-        .bind(user_id)
+        .bind(device_id)
+        .bind(after_seq)
         .bind(limit)
         .fetch_all(pool)
         .await
-        .context("Failed to get messages")?;
+        .context("Failed to get sealed messages")?;
         
         Ok(messages)
     }
-}
-
-/// Service for encrypted content storage
-/// 
-/// SECURITY: This service stores encrypted content as opaque binary data.
-/// The backend cannot read or decrypt the content - it's just a storage layer.
-/// All encryption/decryption is handled client-side using Signal Protocol.
-pub struct EncryptedContentService;
-
-impl EncryptedContentService {
-    /// Store encrypted content for a message
-    /// 
-    /// The content is stored as opaque binary data.
-    /// The backend cannot read or decrypt it.
-    pub async fn store_content(
+    
+    /// Count the envelopes of a device stored up to `through_seq` and not acknowledged yet
+    pub async fn count_unacknowledged(
         pool: &PgPool,
-        message_id: Uuid,
-        content_data: &[u8],
-        content_hash: Option<&str>,
-        expires_at: Option<DateTime<Utc>>,
-    ) -> anyhow::Result<()> {
-        sqlx::query(
+        device_id: Uuid,
+        through_seq: i64,
+    ) -> anyhow::Result<i64> {
+        let count: i64 = sqlx::query_scalar(
             r#"
-            INSERT INTO encrypted_content (message_id, content_data, content_hash, expires_at)
-            VALUES ($1, $2, $3, $4)
-            ON CONFLICT (message_id) 
-            DO UPDATE SET 
-                content_data = $2,
-                content_hash = $3,
-                expires_at = $4
+            SELECT COUNT(*) FROM sealed_messages
+            WHERE device_id = $1 AND seq <= $2 AND expires_at > NOW()
             "#,
         )
-        .bind(message_id)
-        .bind(content_data)
-        .bind(content_hash)
-        .bind(expires_at)
-        .execute(pool)
+        .bind(device_id)
+        .bind(through_seq)
+        .fetch_one(pool)
         .await
-        .context("Failed to store encrypted content")?;
+        .context("Failed to count sealed messages")?;
         
-        Ok(())
+        Ok(count)
     }
     
-    /// Retrieve encrypted content for a message
-    /// 
-    /// Returns the encrypted content as opaque binary data.
-    /// The backend cannot decrypt it.
-    pub async fn get_content(
+    /// Delete envelopes acknowledged by the recipient
+    pub async fn acknowledge(
         pool: &PgPool,
-        message_id: Uuid,
-    ) -> anyhow::Result<Option<Vec<u8>>> {
-        let result = sqlx::query_scalar::<_, Option<Vec<u8>>>(
-            r#"
-            SELECT content_data FROM encrypted_content
-            WHERE message_id = $1
-            AND (expires_at IS NULL OR expires_at > NOW())
-            "#,
+        recipient_id: Uuid,
+        message_ids: &[Uuid],
+    ) -> anyhow::Result<u64> {
+        let deleted = sqlx::query(
+            "DELETE FROM sealed_messages WHERE recipient_id = $1 AND id = ANY($2)",
         )
-        .bind(message_id)
-        .fetch_optional(pool)
+        .bind(recipient_id)
+        .bind(message_ids)
+        .execute(pool)
         .await
-        .context("Failed to get encrypted content")?;
+        .context("Failed to acknowledge sealed messages")?;
         
-        Ok(result.flatten())
+        Ok(deleted.rows_affected())
     }
     
-    /// Delete expired content
     pub async fn cleanup_expired(pool: &PgPool) -> anyhow::Result<u64> {
-        let deleted = sqlx::query(
-            "DELETE FROM encrypted_content WHERE expires_at < NOW()",
-        )
-        .execute(pool)
-        .await
-        .context("Failed to cleanup expired content")?;
+        let deleted = sqlx::query("DELETE FROM sealed_messages WHERE expires_at < NOW()")
+            .execute(pool)
+            .await
+            .context("Failed to cleanup expired sealed messages")?;
         
         Ok(deleted.rows_affected())
     }
@@ -482,6 +2937,7 @@ impl StoryService {
         Ok(story)
     }
     
+    #[allow(dead_code)]
     pub async fn get_user_stories(
         pool: &PgPool,
         user_id: Uuid,
@@ -593,6 +3049,7 @@ impl StoryService {
         Ok(())
     }
     
+    #[allow(dead_code)]
     pub async fn delete_expired_stories(pool: &PgPool) -> anyhow::Result<u64> {
         let deleted = sqlx::query(
             "DELETE FROM stories WHERE expires_at < NOW()",
@@ -708,6 +3165,7 @@ impl ChannelService {
         Ok(responses)
     }
     
+    #[allow(dead_code)]
     pub async fn add_member(
         pool: &PgPool,
         channel_id: Uuid,
@@ -725,6 +3183,7 @@ impl ChannelService {
         Ok(())
     }
     
+    #[allow(dead_code)]
     pub async fn remove_member(
         pool: &PgPool,
         channel_id: Uuid,
@@ -742,6 +3201,7 @@ impl ChannelService {
         Ok(())
     }
     
+    #[allow(dead_code)]
     pub async fn create_message(
         pool: &PgPool,
         channel_id: Uuid,
@@ -785,18 +3245,24 @@ impl ChannelService {
     pub async fn get_channel_messages(
         pool: &PgPool,
         channel_id: Uuid,
-        limit: i64,
-    ) -> anyhow::Result<Vec<ChannelMessageResponse>> {
-        let messages = sqlx::query_as::<_, ChannelMessage>(
+        page: &PageRequest,
+    ) -> anyhow::Result<PaginatedResponse<ChannelMessageResponse>> {
+        let cursor = page.cursor();
+        let (order, cmp) = page.sql_order();
+        
+        let messages = sqlx::query_as::<_, ChannelMessage>(&format!(
             r#"
             SELECT * FROM channel_messages
             WHERE channel_id = $1
-            ORDER BY timestamp DESC
-            LIMIT $2
+            AND ($2::timestamptz IS NULL OR (timestamp, id) {cmp} ($2, $3::uuid))
+            ORDER BY timestamp {order}, id {order}
+            LIMIT $4
             "#,
-        )
+        ))
         .bind(channel_id)
-        .bind(limit)
+        .bind(cursor.map(|c| c.timestamp))
+        .bind(cursor.map(|c| c.id))
+        .bind(page.limit + 1)
         .fetch_all(pool)
         .await
         .context("Failed to get channel messages")?;
@@ -821,13 +3287,52 @@ impl ChannelService {
             });
         }
         
-        Ok(responses)
+        Ok(page.build_page(responses, |m| Cursor {
+            timestamp: m.timestamp,
+            id: m.id,
+        }))
     }
 }
 
 pub struct ConversationService;
 
 impl ConversationService {
+    /// Users sharing a one-to-one conversation with the given user
+    pub async fn get_partners(pool: &PgPool, user_id: Uuid) -> anyhow::Result<Vec<Uuid>> {
+        let partners = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            SELECT user2_id FROM conversations WHERE user1_id = $1
+            UNION
+            SELECT user1_id FROM conversations WHERE user2_id = $1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+        .context("Failed to get conversation partners")?;
+        
+        Ok(partners)
+    }
+    
+    /// Users taking part in a conversation, or members of a channel with that id
+    pub async fn get_participants(pool: &PgPool, conversation_id: Uuid) -> anyhow::Result<Vec<Uuid>> {
+        let participants = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            SELECT user1_id FROM conversations WHERE id = $1
+            UNION
+            SELECT user2_id FROM conversations WHERE id = $1
+            UNION
+            SELECT user_id FROM channel_members WHERE channel_id = $1
+            "#,
+        )
+        .bind(conversation_id)
+        .fetch_all(pool)
+        .await
+        .context("Failed to get conversation participants")?;
+        
+        Ok(participants)
+    }
+    
     pub async fn get_or_create_conversation(
         pool: &PgPool,
         user1_id: Uuid,
@@ -933,8 +3438,9 @@ impl ConversationService {
             .await
             .unwrap_or(0);
             
-            // Get participant presence - default to offline on error
-            let participant_status = crate::services::PresenceService::get_presence(pool, participant_id)
+            // Get participant presence - default to offline on error or when hidden
+            let participant_status =
+                crate::services::PresenceService::get_visible_presence(pool, user_id, participant_id)
                 .await
                 .ok()
                 .flatten()
@@ -1086,9 +3592,12 @@ impl CallService {
     pub async fn get_user_call_history(
         pool: &PgPool,
         user_id: Uuid,
-        limit: i64,
-    ) -> anyhow::Result<Vec<CallHistoryResponse>> {
-        let rows = sqlx::query(
+        page: &PageRequest,
+    ) -> anyhow::Result<PaginatedResponse<CallHistoryResponse>> {
+        let cursor = page.cursor();
+        let (order, cmp) = page.sql_order();
+        
+        let rows = sqlx::query(&format!(
             r#"
             SELECT 
                 c.id,
@@ -1106,13 +3615,16 @@ impl CallService {
             FROM calls c
             LEFT JOIN users caller ON caller.id = c.caller_id
             LEFT JOIN users recipient ON recipient.id = c.recipient_id
-            WHERE c.caller_id = $1 OR c.recipient_id = $1
-            ORDER BY c.created_at DESC
-            LIMIT $2
+            WHERE (c.caller_id = $1 OR c.recipient_id = $1)
+            AND ($2::timestamptz IS NULL OR (c.created_at, c.id) {cmp} ($2, $3::uuid))
+            ORDER BY c.created_at {order}, c.id {order}
+            LIMIT $4
             "#,
-        )
+        ))
         .bind(user_id)
-        .bind(limit)
+        .bind(cursor.map(|c| c.timestamp))
+        .bind(cursor.map(|c| c.id))
+        .bind(page.limit + 1)
         .fetch_all(pool)
         .await
         .context("Failed to get call history")?;
@@ -1136,7 +3648,10 @@ impl CallService {
             });
         }
         
-        Ok(calls)
+        Ok(page.build_page(calls, |c| Cursor {
+            timestamp: c.created_at,
+            id: c.id,
+        }))
     }
     
     pub async fn get_active_call(
@@ -1163,6 +3678,17 @@ impl CallService {
 
 pub struct PresenceService;
 
+/// Users sharing a conversation or a channel with `$1`
+const CONTACTS_SQL: &str = r#"
+    SELECT user2_id AS contact_id FROM conversations WHERE user1_id = $1
+    UNION
+    SELECT user1_id FROM conversations WHERE user2_id = $1
+    UNION
+    SELECT other.user_id FROM channel_members own
+    JOIN channel_members other ON other.channel_id = own.channel_id
+    WHERE own.user_id = $1 AND other.user_id <> $1
+"#;
+
 impl PresenceService {
     pub async fn update_presence(
         pool: &PgPool,
@@ -1196,34 +3722,94 @@ impl PresenceService {
         Ok(presence)
     }
     
-    pub async fn get_presence(
+    /// Presence of the given users, leaving out those who hide it from the viewer
+    pub async fn get_visible_presences(
+        pool: &PgPool,
+        viewer_id: Uuid,
+        user_ids: &[Uuid],
+    ) -> anyhow::Result<Vec<UserPresence>> {
+        let presences = sqlx::query_as::<_, UserPresence>(&format!(
+            r#"
+            SELECT p.* FROM user_presence p
+            JOIN users u ON u.id = p.user_id
+            WHERE p.user_id = ANY($2)
+            AND (
+                p.user_id = $1
+                OR u.last_seen_visibility = 'everyone'
+                OR (u.last_seen_visibility = 'contacts' AND p.user_id IN ({CONTACTS_SQL}))
+            )
+            "#,
+        ))
+        .bind(viewer_id)
+        .bind(user_ids)
+        .fetch_all(pool)
+        .await
+        .context("Failed to get presences")?;
+        
+        Ok(presences)
+    }
+    
+    pub async fn get_visible_presence(
         pool: &PgPool,
+        viewer_id: Uuid,
         user_id: Uuid,
     ) -> anyhow::Result<Option<UserPresence>> {
-        let presence = sqlx::query_as::<_, UserPresence>(
-            "SELECT * FROM user_presence WHERE user_id = $1",
+        let mut presences = Self::get_visible_presences(pool, viewer_id, &[user_id]).await?;
+        Ok(presences.pop())
+    }
+    
+    /// Users sharing a conversation or a channel with the given user
+    pub async fn get_contacts(pool: &PgPool, user_id: Uuid) -> anyhow::Result<Vec<Uuid>> {
+        let contacts = sqlx::query_scalar::<_, Uuid>(CONTACTS_SQL)
+            .bind(user_id)
+            .fetch_all(pool)
+            .await
+            .context("Failed to get contacts")?;
+        
+        Ok(contacts)
+    }
+    
+    /// Users who receive the presence changes of the given user
+    /// 
+    /// Presence is only pushed to contacts, and to no one when it is hidden.
+    pub async fn get_presence_audience(pool: &PgPool, user_id: Uuid) -> anyhow::Result<Vec<Uuid>> {
+        if Self::get_settings(pool, user_id).await?.last_seen_visibility == "nobody" {
+            return Ok(Vec::new());
+        }
+        Self::get_contacts(pool, user_id).await
+    }
+    
+    pub async fn get_settings(pool: &PgPool, user_id: Uuid) -> anyhow::Result<PresenceSettings> {
+        let last_seen_visibility: String = sqlx::query_scalar(
+            "SELECT last_seen_visibility FROM users WHERE id = $1",
         )
         .bind(user_id)
-        .fetch_optional(pool)
+        .fetch_one(pool)
         .await
-        .context("Failed to get presence")?;
+        .context("Failed to get presence settings")?;
         
-        Ok(presence)
+        Ok(PresenceSettings { last_seen_visibility })
     }
     
-    pub async fn get_multiple_presences(
+    pub async fn update_settings(
         pool: &PgPool,
-        user_ids: &[Uuid],
-    ) -> anyhow::Result<Vec<UserPresence>> {
-        let presences = sqlx::query_as::<_, UserPresence>(
-            "SELECT * FROM user_presence WHERE user_id = ANY($1)",
+        user_id: Uuid,
+        last_seen_visibility: &str,
+    ) -> anyhow::Result<PresenceSettings> {
+        let last_seen_visibility: String = sqlx::query_scalar(
+            r#"
+            UPDATE users SET last_seen_visibility = $2, updated_at = NOW()
+            WHERE id = $1
+            RETURNING last_seen_visibility
+            "#,
         )
-        .bind(user_ids)
-        .fetch_all(pool)
+        .bind(user_id)
+        .bind(last_seen_visibility)
+        .fetch_one(pool)
         .await
-        .context("Failed to get presences")?;
+        .context("Failed to update presence settings")?;
         
-        Ok(presences)
+        Ok(PresenceSettings { last_seen_visibility })
     }
     
     pub async fn mark_offline_after_timeout(
@@ -1247,3 +3833,355 @@ impl PresenceService {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn cheap_hashing(iterations: u32) -> PasswordHashingConfig {
+        PasswordHashingConfig {
+            memory_kib: 1024,
+            iterations,
+            parallelism: 1,
+        }
+    }
+    
+    #[test]
+    fn test_legacy_bcrypt_hash_is_accepted_then_upgraded() {
+        let config = cheap_hashing(1);
+        let legacy = bcrypt::hash("password123", 4).unwrap();
+        
+        assert!(AuthService::verify_password("password123", &legacy).unwrap());
+        assert!(!AuthService::verify_password("wrong", &legacy).unwrap());
+        assert!(AuthService::needs_rehash(&legacy, &config));
+        
+        let upgraded = AuthService::hash_password("password123", &config).unwrap();
+        assert!(upgraded.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+        assert!(AuthService::verify_password("password123", &upgraded).unwrap());
+        assert!(!AuthService::verify_password("wrong", &upgraded).unwrap());
+        assert!(!AuthService::needs_rehash(&upgraded, &config));
+        
+        // Raising the costs upgrades hashes again, old ones still verify meanwhile
+        assert!(AuthService::needs_rehash(&upgraded, &cheap_hashing(2)));
+        assert!(AuthService::verify_password("password123", &upgraded).unwrap());
+    }
+    
+    #[test]
+    fn test_identity_key_log_chain_detects_tampering() {
+        let user_id = Uuid::new_v4();
+        let mut previous_hash = vec![0u8; 32];
+        let mut entries = Vec::new();
+        for log_index in 0..3 {
+            let device_id = Uuid::new_v4();
+            let identity_key = vec![log_index as u8; 33];
+            let entry_hash = IdentityKeyLogService::entry_hash(&previous_hash, log_index, device_id, &identity_key);
+            entries.push(IdentityKeyLogEntry {
+                user_id,
+                log_index,
+                device_id,
+                identity_key,
+                previous_hash: previous_hash.clone(),
+                entry_hash: entry_hash.clone(),
+                created_at: Utc::now(),
+            });
+            previous_hash = entry_hash;
+        }
+        assert!(IdentityKeyLogService::verify_chain(&entries));
+        assert!(IdentityKeyLogService::verify_chain(&entries[1..]));
+        
+        // A swapped key, a dropped entry or a reordered log break the chain
+        let mut swapped = entries.clone();
+        swapped[1].identity_key = vec![9; 33];
+        assert!(!IdentityKeyLogService::verify_chain(&swapped));
+        assert!(!IdentityKeyLogService::verify_chain(&[entries[0].clone(), entries[2].clone()]));
+        assert!(!IdentityKeyLogService::verify_chain(&[entries[1].clone(), entries[0].clone()]));
+    }
+    
+    async fn insert_user(pool: &PgPool, username: &str) -> Uuid {
+        sqlx::query_scalar(
+            "INSERT INTO users (email, username, password_hash) VALUES ($1, $2, 'x') RETURNING id",
+        )
+        .bind(format!("{}@example.com", username))
+        .bind(username)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+    
+    #[sqlx::test]
+    async fn test_erase_hands_over_channels_and_leaves_no_rows(pool: PgPool) {
+        let alice = insert_user(&pool, "alice").await;
+        let bob = insert_user(&pool, "bob").await;
+        let carol = insert_user(&pool, "carol").await;
+        
+        // The oldest admin inherits the channel, before older plain members
+        let shared = ChannelService::create_channel(&pool, alice, "shared", None, None, false).await.unwrap();
+        ChannelService::add_member(&pool, shared.id, carol).await.unwrap();
+        ChannelService::add_member(&pool, shared.id, bob).await.unwrap();
+        sqlx::query("UPDATE channel_members SET role = 'admin' WHERE channel_id = $1 AND user_id = $2")
+            .bind(shared.id)
+            .bind(bob)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let solo = ChannelService::create_channel(&pool, alice, "solo", None, None, false).await.unwrap();
+        
+        let device = DeviceService::register_device(&pool, alice, None, None, None, None, None).await.unwrap();
+        SessionService::create_session(&pool, alice, device.id, 3600).await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        IdentityKeyLogService::append(&mut tx, alice, device.id, &[5; 33]).await.unwrap();
+        tx.commit().await.unwrap();
+        MessageService::create_message(&pool, alice, bob, "text", None, None).await.unwrap();
+        SecurityEventService::record(&pool, alice, "login", None, None).await.unwrap();
+        
+        let mut contacts = AccountDeletionService::erase(&pool, alice).await.unwrap();
+        contacts.sort();
+        let mut expected = vec![bob, carol];
+        expected.sort();
+        assert_eq!(contacts, expected);
+        
+        let (creator_id, heir_role): (Uuid, String) = sqlx::query_as(
+            r#"
+            SELECT c.creator_id, m.role FROM channels c
+            INNER JOIN channel_members m ON m.channel_id = c.id AND m.user_id = c.creator_id
+            WHERE c.id = $1
+            "#,
+        )
+        .bind(shared.id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(creator_id, bob);
+        assert_eq!(heir_role, "admin");
+        let solo_left: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM channels WHERE id = $1)")
+            .bind(solo.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(!solo_left);
+        
+        // Every column referencing users, whatever its delete rule
+        let references: Vec<(String, String)> = sqlx::query_as(
+            r#"
+            SELECT c.conrelid::regclass::text, a.attname::text
+            FROM pg_constraint c
+            INNER JOIN pg_attribute a ON a.attrelid = c.conrelid AND a.attnum = ANY(c.conkey)
+            WHERE c.contype = 'f' AND c.confrelid = 'users'::regclass
+            "#,
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert!(!references.is_empty());
+        for (table, column) in references.into_iter().chain([("users".to_string(), "id".to_string())]) {
+            let sql = format!("SELECT COUNT(*) FROM {} WHERE {} = $1", table, column);
+            let remaining: i64 = sqlx::query_scalar(&sql).bind(alice).fetch_one(&pool).await.unwrap();
+            assert_eq!(remaining, 0, "{}.{} still references the erased user", table, column);
+        }
+    }
+    
+    #[sqlx::test]
+    async fn test_identity_key_log_only_goes_away_with_the_account(pool: PgPool) {
+        let alice = insert_user(&pool, "alice").await;
+        let mut tx = pool.begin().await.unwrap();
+        IdentityKeyLogService::append(&mut tx, alice, Uuid::new_v4(), &[5; 33]).await.unwrap();
+        tx.commit().await.unwrap();
+        
+        for statement in [
+            "UPDATE identity_key_log SET identity_key = '\\x00' WHERE user_id = $1",
+            "DELETE FROM identity_key_log WHERE user_id = $1",
+            "DELETE FROM users WHERE id = $1",
+        ] {
+            let result = sqlx::query(statement).bind(alice).execute(&pool).await;
+            assert!(result.is_err(), "{} must be rejected", statement);
+        }
+        
+        AccountDeletionService::erase(&pool, alice).await.unwrap();
+        assert!(UserService::find_by_id(&pool, alice).await.unwrap().is_none());
+    }
+    
+    #[sqlx::test]
+    async fn test_refresh_token_rotation_and_reuse(pool: PgPool) {
+        let alice = insert_user(&pool, "alice").await;
+        let device = DeviceService::register_device(&pool, alice, None, None, None, None, None).await.unwrap();
+        let (session, first) = SessionService::create_session(&pool, alice, device.id, 3600).await.unwrap();
+        
+        let second = match SessionService::rotate_refresh_token(&pool, &first, 3600).await.unwrap() {
+            RefreshOutcome::Rotated { session: rotated, refresh_token } => {
+                assert_eq!(rotated.id, session.id);
+                assert_ne!(refresh_token, first);
+                refresh_token
+            }
+            _ => panic!("a fresh refresh token must rotate"),
+        };
+        assert!(matches!(
+            SessionService::rotate_refresh_token(&pool, "unknown", 3600).await.unwrap(),
+            RefreshOutcome::Invalid
+        ));
+        assert!(SessionService::is_active(&pool, session.id, alice).await.unwrap());
+        
+        // Presenting a consumed token again means it leaked: the whole session goes
+        match SessionService::rotate_refresh_token(&pool, &first, 3600).await.unwrap() {
+            RefreshOutcome::Reused { session_id } => assert_eq!(session_id, session.id),
+            _ => panic!("a consumed refresh token must be detected as reused"),
+        }
+        assert!(!SessionService::is_active(&pool, session.id, alice).await.unwrap());
+        assert!(matches!(
+            SessionService::rotate_refresh_token(&pool, &second, 3600).await.unwrap(),
+            RefreshOutcome::Invalid
+        ));
+    }
+    
+    #[sqlx::test]
+    async fn test_pending_events_are_paged_in_order_until_acknowledged(pool: PgPool) {
+        let alice = insert_user(&pool, "alice").await;
+        let bob = insert_user(&pool, "bob").await;
+        let mut queued = Vec::new();
+        for status in ["online", "away", "offline"] {
+            let event = WebSocketMessage::PresenceUpdate {
+                payload: PresenceUpdate {
+                    user_id: bob,
+                    status: status.to_string(),
+                    last_seen: Utc::now(),
+                },
+            };
+            let pending = PendingEventService::enqueue(&pool, alice, &event, chrono::Duration::days(1))
+                .await
+                .unwrap();
+            queued.push(pending);
+        }
+        let expired = WebSocketMessage::PresenceUpdate {
+            payload: PresenceUpdate {
+                user_id: bob,
+                status: "online".to_string(),
+                last_seen: Utc::now(),
+            },
+        };
+        PendingEventService::enqueue(&pool, alice, &expired, chrono::Duration::seconds(-1)).await.unwrap();
+        
+        let first_page = PendingEventService::get_pending(&pool, alice, 0, 2).await.unwrap();
+        let ids: Vec<Uuid> = first_page.iter().map(|event| event.id).collect();
+        assert_eq!(ids, vec![queued[0].id, queued[1].id]);
+        let cursor = first_page[1].seq;
+        assert_eq!(PendingEventService::count_unacknowledged(&pool, alice, cursor).await.unwrap(), 2);
+        assert!(PendingEventService::get_pending(&pool, bob, 0, 2).await.unwrap().is_empty());
+        
+        // Only the recipient can acknowledge, and the page is done once all of it is
+        assert_eq!(PendingEventService::acknowledge(&pool, bob, &ids).await.unwrap(), 0);
+        assert_eq!(PendingEventService::acknowledge(&pool, alice, &ids[..1]).await.unwrap(), 1);
+        assert_eq!(PendingEventService::count_unacknowledged(&pool, alice, cursor).await.unwrap(), 1);
+        assert_eq!(PendingEventService::acknowledge(&pool, alice, &ids[1..]).await.unwrap(), 1);
+        assert_eq!(PendingEventService::count_unacknowledged(&pool, alice, cursor).await.unwrap(), 0);
+        
+        // The next page starts after the cursor and skips expired events
+        let next_page = PendingEventService::get_pending(&pool, alice, cursor, 2).await.unwrap();
+        let ids: Vec<Uuid> = next_page.iter().map(|event| event.id).collect();
+        assert_eq!(ids, vec![queued[2].id]);
+    }
+    
+    #[sqlx::test]
+    async fn test_message_history_pages_both_ways(pool: PgPool) {
+        let alice = insert_user(&pool, "alice").await;
+        let bob = insert_user(&pool, "bob").await;
+        let base = Utc::now();
+        let mut sent = Vec::new();
+        for i in 0..5 {
+            let (message, _) = MessageService::create_message(&pool, alice, bob, "text", None, None)
+                .await
+                .unwrap();
+            sqlx::query("UPDATE messages SET timestamp = $1 WHERE id = $2")
+                .bind(base + chrono::Duration::seconds(i))
+                .bind(message.id)
+                .execute(&pool)
+                .await
+                .unwrap();
+            sent.push(message);
+        }
+        let conversation_id = sent[0].conversation_id;
+        let page_of = |before: Option<&str>, after: Option<&str>| PageRequest {
+            limit: 2,
+            before: before.and_then(Cursor::decode),
+            after: after.and_then(Cursor::decode),
+        };
+        let ids = |page: &PaginatedResponse<Message>| page.items.iter().map(|m| m.id).collect::<Vec<_>>();
+        
+        // Newest first, scrolling back with `before` until nothing is left
+        let newest =
+            MessageService::get_conversation_messages(&pool, conversation_id, bob, &page_of(None, None))
+                .await
+                .unwrap();
+        assert_eq!(ids(&newest), vec![sent[4].id, sent[3].id]);
+        assert!(newest.has_more);
+        let older = MessageService::get_conversation_messages(
+            &pool,
+            conversation_id,
+            bob,
+            &page_of(newest.oldest_cursor.as_deref(), None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ids(&older), vec![sent[2].id, sent[1].id]);
+        assert!(older.has_more);
+        let oldest = MessageService::get_conversation_messages(
+            &pool,
+            conversation_id,
+            bob,
+            &page_of(older.oldest_cursor.as_deref(), None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ids(&oldest), vec![sent[0].id]);
+        assert!(!oldest.has_more);
+        
+        // Catching up with `after` returns the next newer items, still newest first
+        let newer = MessageService::get_conversation_messages(
+            &pool,
+            conversation_id,
+            bob,
+            &page_of(None, oldest.newest_cursor.as_deref()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ids(&newer), vec![sent[2].id, sent[1].id]);
+        assert!(newer.has_more);
+        assert_eq!(newer.newest_cursor, older.newest_cursor);
+        let latest = MessageService::get_conversation_messages(
+            &pool,
+            conversation_id,
+            bob,
+            &page_of(None, newest.newest_cursor.as_deref()),
+        )
+        .await
+        .unwrap();
+        assert!(latest.items.is_empty());
+        assert!(!latest.has_more);
+    }
+    
+    #[sqlx::test]
+    async fn test_presence_audience_is_contacts_unless_hidden(pool: PgPool) {
+        let alice = insert_user(&pool, "alice").await;
+        let bob = insert_user(&pool, "bob").await;
+        let carol = insert_user(&pool, "carol").await;
+        insert_user(&pool, "dave").await;
+        
+        // A conversation partner and a channel co-member, never a stranger
+        MessageService::create_message(&pool, bob, alice, "text", None, None).await.unwrap();
+        let channel = ChannelService::create_channel(&pool, carol, "shared", None, None, false)
+            .await
+            .unwrap();
+        ChannelService::add_member(&pool, channel.id, alice).await.unwrap();
+        let mut expected = vec![bob, carol];
+        expected.sort();
+        
+        for visibility in ["everyone", "contacts", "nobody"] {
+            PresenceService::update_settings(&pool, alice, visibility).await.unwrap();
+            let mut audience = PresenceService::get_presence_audience(&pool, alice).await.unwrap();
+            audience.sort();
+            if visibility == "nobody" {
+                assert!(audience.is_empty());
+            } else {
+                assert_eq!(audience, expected, "audience with {} visibility", visibility);
+            }
+        }
+    }
+}
+
@@ -8,17 +8,54 @@ use axum::{
 use futures::{SinkExt, StreamExt};
 use serde::Deserialize;
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::{broadcast, RwLock};
-use tracing;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, watch, RwLock};
 use uuid::Uuid;
 
+use crate::client_info::ClientInfo;
 use crate::models::*;
+use crate::rate_limit::{self, RateLimitDecision};
+use crate::security::SecurityGateway;
 use crate::services::*;
 use crate::AppState;
 
 type Tx = broadcast::Sender<String>;
-type PeerMap = Arc<RwLock<HashMap<Uuid, Tx>>>;
+// Live connections by user id then connection id (one per device / client)
+type PeerMap = Arc<RwLock<HashMap<Uuid, HashMap<Uuid, Connection>>>>;
+
+/// A single live WebSocket of a user
+struct Connection {
+    tx: Tx,
+    close_tx: watch::Sender<bool>,
+    session_id: Uuid,
+    device_id: Option<Uuid>,
+    // Last pending event seq replayed to this connection, 0 before the first page
+    pending_events_cursor: AtomicI64,
+    // Same for the sealed messages of the device
+    sealed_messages_cursor: AtomicI64,
+}
+
+// The offline queue is replayed one page at a time, the next page once the previous one is acknowledged
+const REPLAY_PAGE_SIZE: i64 = 50;
+// Sealed envelopes are up to 256 KiB each
+const SEALED_REPLAY_PAGE_SIZE: i64 = 10;
+
+// Users currently typing, by (user id, conversation id)
+type TypingMap = Arc<Mutex<HashMap<(Uuid, Uuid), TypingState>>>;
+
+/// "is typing" state forwarded to the other participants of a conversation
+struct TypingState {
+    recipients: Vec<Uuid>,
+    last_sent: Instant,
+    expires_at: Instant,
+}
+
+// A repeated "is typing" is forwarded at most once per throttle window
+const TYPING_THROTTLE: Duration = Duration::from_secs(3);
+// Clients that stop sending "is typing" without a stop event are considered idle after this
+const TYPING_TIMEOUT: Duration = Duration::from_secs(8);
 
 #[derive(Deserialize)]
 pub struct WsQuery {
@@ -29,85 +66,181 @@ pub async fn handle_websocket(
     ws: WebSocketUpgrade,
     Extension(state): Extension<Arc<AppState>>,
     Query(query): Query<WsQuery>,
+    client: ClientInfo,
 ) -> Response {
-    // Verify token
-    let user_id = match AuthService::verify_token(&query.token, &state.config.jwt_secret) {
+    let ip = client.ip.as_deref().unwrap_or("unknown");
+    if let RateLimitDecision::Limited { retry_after } = state
+        .rate_limiter
+        .check("ws_connect", ip, state.config.rate_limit.ws_connect)
+        .await
+    {
+        return rate_limit::too_many_requests(retry_after);
+    }
+    
+    // Verify token and its session
+    let claims = match AuthService::verify_token(&query.token, &state.jwt_keys) {
+        Ok(claims) => claims,
+        Err(_) => return unauthorized(),
+    };
+    let user_id = match claims.user_id() {
         Ok(id) => id,
-        Err(_) => {
+        Err(_) => return unauthorized(),
+    };
+    match SessionService::is_active(state.db.pool(), claims.sid, user_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            tracing::warn!("❌ WebSocket rejected, session {} is no longer active", claims.sid);
+            return unauthorized();
+        }
+        Err(e) => {
+            tracing::error!("Failed to check session: {:?}", e);
             return axum::response::Response::builder()
-                .status(axum::http::StatusCode::UNAUTHORIZED)
-                .body(axum::body::Body::from("Unauthorized"))
-                .unwrap()
-                .into();
+                .status(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body(axum::body::Body::empty())
+                .unwrap();
         }
-    };
-    
-    // Get or create peer map
-    let peer_map = get_peer_map();
-    
-    // Create channel for this connection
-    let (tx, _rx) = broadcast::channel(100);
-    peer_map.write().await.insert(user_id, tx.clone());
-    
-    tracing::info!("🔌 WebSocket connection established for user: {}", user_id);
-    
-    let state_clone = state.clone();
-    let peer_map_clone = peer_map.clone();
-    let user_id_clone = user_id;
-    
-    // Update and broadcast presence
-    broadcast_presence_update(&peer_map, user_id, "online", &state).await;
+    }
     
-    // Send current presence status to the newly connected user for all their contacts
-    if let Ok(conversations) = crate::services::ConversationService::get_user_conversations(
-        state.db.pool(),
-        user_id,
-    )
-    .await
-    {
-        let participant_ids: Vec<Uuid> = conversations
-            .iter()
-            .map(|c| c.participant_id)
-            .collect();
-        
-        if !participant_ids.is_empty() {
-            if let Ok(presences) = crate::services::PresenceService::get_multiple_presences(
-                state.db.pool(),
-                &participant_ids,
-            )
-            .await
-            {
-                for presence in presences {
-                    let update = WebSocketMessage::PresenceUpdate {
-                        payload: PresenceUpdate {
-                            user_id: presence.user_id,
-                            status: presence.status,
-                            last_seen: presence.last_seen,
-                        },
-                    };
-                    send_to_user(&peer_map, user_id, &update).await;
-                }
-            }
+    if let Some(device_id) = claims.did {
+        if let Err(e) = DeviceService::touch(
+            state.db.pool(),
+            device_id,
+            client.ip.as_deref(),
+            client.user_agent.as_deref(),
+        )
+        .await
+        {
+            tracing::warn!("Failed to update device {}: {:?}", device_id, e);
         }
     }
     
+    let session_id = claims.sid;
+    let device_id = claims.did;
+    
     ws.on_upgrade(move |socket| async move {
         // Handle WebSocket
         let (mut sender, mut receiver) = socket.split();
         
+        // Get or create peer map
+        let peer_map = get_peer_map();
+        
+        // Create channels for this connection
+        // Subscribe right away so that the presence snapshot below is not lost
+        let (tx, mut rx) = broadcast::channel(100);
+        let (close_tx, close_rx) = watch::channel(false);
+        let connection_id = Uuid::new_v4();
+        let is_first_connection = {
+            let mut peers = peer_map.write().await;
+            let connections = peers.entry(user_id).or_default();
+            connections.insert(
+                connection_id,
+                Connection {
+                    tx,
+                    close_tx,
+                    session_id,
+                    device_id,
+                    pending_events_cursor: AtomicI64::new(0),
+                    sealed_messages_cursor: AtomicI64::new(0),
+                },
+            );
+            connections.len() == 1
+        };
+        
+        tracing::info!(
+            "🔌 WebSocket connection {} established for user: {}",
+            connection_id,
+            user_id
+        );
+        
+        // Spawn task to send messages to this connection, before anything is queued on it
+        let mut close_rx_send = close_rx.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    msg = rx.recv() => match msg {
+                        Ok(msg) => {
+                            if sender.send(Message::Text(msg)).await.is_err() {
+                                break;
+                            }
+                        }
+                        // Live events are only stored for users without any connection, the skipped
+                        // ones are lost: close so the client reconnects and resyncs over HTTP
+                        // rather than carrying on with a silent gap
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            tracing::warn!(
+                                "⚠️ WebSocket connection {} lagging, {} events skipped, closing",
+                                connection_id,
+                                skipped
+                            );
+                            let _ = sender.send(Message::Close(None)).await;
+                            break;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    _ = close_rx_send.changed() => {
+                        let _ = sender.send(Message::Close(None)).await;
+                        break;
+                    }
+                }
+            }
+        });
+        
+        // Update and broadcast presence, other devices already made the user online
+        if is_first_connection {
+            broadcast_presence_update(&peer_map, user_id, "online", &state).await;
+        }
+        
+        // Send current presence status to the newly connected user for all their contacts
+        match PresenceService::get_contacts(state.db.pool(), user_id).await {
+            Ok(contacts) if !contacts.is_empty() => {
+                if let Ok(presences) =
+                    PresenceService::get_visible_presences(state.db.pool(), user_id, &contacts).await
+                {
+                    for presence in presences {
+                        let update = WebSocketMessage::PresenceUpdate {
+                            payload: PresenceUpdate {
+                                user_id: presence.user_id,
+                                status: presence.status,
+                                last_seen: presence.last_seen,
+                            },
+                        };
+                        send_to_connection(&peer_map, user_id, connection_id, &update).await;
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("Failed to load contacts of user {}: {:?}", user_id, e),
+        }
+        
+        // Replay events queued while the user was offline, in order, the following pages come with the acks
+        replay_pending_events(&peer_map, user_id, connection_id, &state).await;
+        replay_sealed_messages(&peer_map, user_id, connection_id, &state).await;
+        
         // Spawn task to handle incoming messages
-        let peer_map_msg = peer_map_clone.clone();
-        let state_msg = state_clone.clone();
-        let user_id_msg = user_id_clone;
-        let mut rx = tx.subscribe();
+        let peer_map_msg = peer_map.clone();
+        let state_msg = state.clone();
+        let user_id_msg = user_id;
+        let mut close_rx_msg = close_rx;
         
         tokio::spawn(async move {
-            while let Some(msg) = receiver.next().await {
+            loop {
+                let msg = tokio::select! {
+                    msg = receiver.next() => match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                    _ = close_rx_msg.changed() => {
+                        tracing::info!("🔒 Session {} revoked, closing WebSocket", session_id);
+                        break;
+                    }
+                };
+                
                 match msg {
                     Ok(Message::Text(text)) => {
                         if let Err(e) = handle_websocket_message(
                             &text,
                             user_id_msg,
+                            connection_id,
                             &peer_map_msg,
                             &state_msg,
                         )
@@ -127,30 +260,135 @@ pub async fn handle_websocket(
                 }
             }
             
-            // Cleanup on disconnect
-            peer_map_msg.write().await.remove(&user_id_msg);
-            broadcast_presence_update(&peer_map_msg, user_id_msg, "offline", &state_msg).await;
-            tracing::info!("🔌 WebSocket disconnected for user: {}", user_id_msg);
-        });
-        
-        // Spawn task to send messages to this connection
-        tokio::spawn(async move {
-            while let Ok(msg) = rx.recv().await {
-                if sender.send(Message::Text(msg)).await.is_err() {
-                    break;
+            // Cleanup on disconnect, the user is offline only once their last connection is gone
+            let is_last_connection = {
+                let mut peers = peer_map_msg.write().await;
+                match peers.get_mut(&user_id_msg) {
+                    Some(connections) => {
+                        connections.remove(&connection_id);
+                        if connections.is_empty() {
+                            peers.remove(&user_id_msg);
+                            true
+                        } else {
+                            false
+                        }
+                    }
+                    None => true,
                 }
+            };
+            if is_last_connection {
+                clear_user_typing(&peer_map_msg, user_id_msg).await;
+                broadcast_presence_update(&peer_map_msg, user_id_msg, "offline", &state_msg).await;
             }
+            tracing::info!(
+                "🔌 WebSocket connection {} disconnected for user: {}",
+                connection_id,
+                user_id_msg
+            );
         });
     })
 }
 
+/// Close every live WebSocket opened with the given session
+/// 
+/// Called when a session is revoked (logout, session deletion).
+pub async fn disconnect_session(session_id: Uuid) {
+    let peers = get_peer_map();
+    let peers = peers.read().await;
+    for connection in peers.values().flat_map(|connections| connections.values()) {
+        if connection.session_id == session_id {
+            let _ = connection.close_tx.send(true);
+        }
+    }
+}
+
+/// Close every live WebSocket of a user
+pub async fn disconnect_user(user_id: Uuid) {
+    let peers = get_peer_map();
+    let peers = peers.read().await;
+    for connection in peers.get(&user_id).into_iter().flat_map(|connections| connections.values()) {
+        let _ = connection.close_tx.send(true);
+    }
+}
+
+fn unauthorized() -> Response {
+    axum::response::Response::builder()
+        .status(axum::http::StatusCode::UNAUTHORIZED)
+        .body(axum::body::Body::from("Unauthorized"))
+        .unwrap()
+}
+
 async fn handle_websocket_message(
     text: &str,
     user_id: Uuid,
+    connection_id: Uuid,
     peer_map: &PeerMap,
     state: &AppState,
 ) -> anyhow::Result<()> {
-    let message: WebSocketMessage = serde_json::from_str(text)?;
+    // Only whitelisted metadata gets past this point, to any handler
+    let message = match SecurityGateway::check_inbound(text) {
+        Ok(message) => message,
+        Err(rejection) => {
+            // The rejected values are never logged, they may be exactly what must not leak
+            tracing::warn!(
+                target: "audit",
+                "🛡️ WebSocket frame rejected for user {} on connection {}: {}",
+                user_id,
+                connection_id,
+                rejection
+            );
+            let error = WebSocketMessage::Error {
+                payload: ErrorPayload {
+                    message: rejection.to_string(),
+                    code: Some("frame_rejected".to_string()),
+                    retry_after: None,
+                },
+            };
+            send_to_connection(peer_map, user_id, connection_id, &error).await;
+            return Ok(());
+        }
+    };
+    
+    // Frames that create messages or ring other users are limited per user
+    let limit = match &message {
+        WebSocketMessage::Message { .. } => Some(("ws_message", state.config.rate_limit.ws_message)),
+        WebSocketMessage::CallRequest { .. } => Some(("ws_call", state.config.rate_limit.ws_call)),
+        _ => None,
+    };
+    if let Some((scope, quota)) = limit {
+        if let RateLimitDecision::Limited { retry_after } =
+            state.rate_limiter.check(scope, &user_id.to_string(), quota).await
+        {
+            let error = WebSocketMessage::Error {
+                payload: ErrorPayload {
+                    message: "Rate limit exceeded".to_string(),
+                    code: Some("rate_limited".to_string()),
+                    retry_after: Some(rate_limit::retry_after_secs(retry_after)),
+                },
+            };
+            send_to_connection(peer_map, user_id, connection_id, &error).await;
+            return Ok(());
+        }
+    }
+    
+    // Accounts without a verified email may be kept from messaging or calling
+    let policy = &state.config.unverified_policy;
+    let restricted = match &message {
+        WebSocketMessage::Message { .. } => !policy.can_message,
+        WebSocketMessage::CallRequest { .. } => !policy.can_call,
+        _ => false,
+    };
+    if restricted && !UserService::is_email_verified(state.db.pool(), user_id).await? {
+        let error = WebSocketMessage::Error {
+            payload: ErrorPayload {
+                message: "Email address not verified".to_string(),
+                code: Some("email_not_verified".to_string()),
+                retry_after: None,
+            },
+        };
+        send_to_connection(peer_map, user_id, connection_id, &error).await;
+        return Ok(());
+    }
     
     match message {
         WebSocketMessage::Message { payload } => {
@@ -163,16 +401,27 @@ async fn handle_websocket_message(
             handle_call_response(payload, user_id, peer_map, state).await?;
         }
         WebSocketMessage::TypingIndicator { payload } => {
-            handle_typing_indicator(payload, user_id, peer_map).await?;
+            handle_typing_indicator(payload, user_id, peer_map, state).await?;
         }
         WebSocketMessage::ReadReceipt { payload } => {
-            handle_read_receipt(payload, user_id, state).await?;
+            handle_read_receipt(payload, user_id, peer_map, state).await?;
         }
         WebSocketMessage::PresenceUpdate { payload } => {
             handle_presence_update(payload, user_id, peer_map, state).await?;
         }
+        WebSocketMessage::MessageAck { payload } => {
+            handle_message_ack(payload, user_id, peer_map, state).await?;
+        }
+        WebSocketMessage::PendingEventAck { payload } => {
+            PendingEventService::acknowledge(state.db.pool(), user_id, &payload.event_ids).await?;
+            replay_pending_events(peer_map, user_id, connection_id, state).await;
+        }
+        WebSocketMessage::SealedMessageAck { payload } => {
+            SealedMessageService::acknowledge(state.db.pool(), user_id, &payload.message_ids).await?;
+            replay_sealed_messages(peer_map, user_id, connection_id, state).await;
+        }
         WebSocketMessage::Heartbeat { payload: _ } => {
-            handle_heartbeat(user_id, peer_map).await?;
+            handle_heartbeat(user_id, connection_id, peer_map).await?;
         }
         _ => {}
     }
@@ -191,13 +440,23 @@ async fn handle_message(
     peer_map: &PeerMap,
     state: &AppState,
 ) -> anyhow::Result<()> {
+    let client_message_id = payload
+        .client_message_id
+        .as_deref()
+        .map(str::trim)
+        .filter(|id| !id.is_empty());
+    if client_message_id.is_some_and(|id| id.len() > 64) {
+        anyhow::bail!("client_message_id must be at most 64 characters");
+    }
+    
     // Store ONLY metadata in database (no content, no keys)
-    let message = MessageService::create_message(
+    let (message, created) = MessageService::create_message(
         state.db.pool(),
         sender_id,
         payload.recipient_id,
         &payload.message_type,
         payload.session_id.as_deref(),
+        client_message_id,
     )
     .await?;
     
@@ -209,12 +468,17 @@ async fn handle_message(
     };
     send_to_user(peer_map, sender_id, &confirmation).await;
     
+    // A retried frame only needs the confirmation, the recipient was already notified
+    if !created {
+        return Ok(());
+    }
+    
     // Route metadata to recipient via WebSocket
     // The encrypted content is handled separately by clients
     let ws_message = WebSocketMessage::MessageResponse {
         payload: message_response.clone(),
     };
-    send_to_user(peer_map, payload.recipient_id, &ws_message).await;
+    send_or_queue(peer_map, state, payload.recipient_id, &ws_message).await;
     
     Ok(())
 }
@@ -279,7 +543,7 @@ async fn handle_call_request(
             timestamp: chrono::Utc::now(),
         },
     };
-    send_to_user(peer_map, payload.recipient_id, &call_request).await;
+    send_or_queue(peer_map, state, payload.recipient_id, &call_request).await;
     
     Ok(())
 }
@@ -314,7 +578,7 @@ async fn handle_call_response(
                     timestamp: chrono::Utc::now(),
                 },
             };
-            send_to_user(peer_map, call.caller_id, &response).await;
+            send_or_queue(peer_map, state, call.caller_id, &response).await;
         }
         "reject" | "busy" => {
             // Reject or busy
@@ -333,7 +597,7 @@ async fn handle_call_response(
                     timestamp: chrono::Utc::now(),
                 },
             };
-            send_to_user(peer_map, call.caller_id, &response).await;
+            send_or_queue(peer_map, state, call.caller_id, &response).await;
         }
         "end" => {
             // End call
@@ -347,7 +611,7 @@ async fn handle_call_response(
                     timestamp: chrono::Utc::now(),
                 },
             };
-            send_to_user(peer_map, call.caller_id, &response).await;
+            send_or_queue(peer_map, state, call.caller_id, &response).await;
             send_to_user(peer_map, call.recipient_id, &response).await;
         }
         _ => {}
@@ -356,72 +620,449 @@ async fn handle_call_response(
     Ok(())
 }
 
+/// Forward typing state to the other participants of the conversation or channel
+/// 
+/// Repeated "is typing" events are throttled, stale ones expire (see `expire_typing_indicators`).
 async fn handle_typing_indicator(
     payload: TypingIndicator,
-    _user_id: Uuid,
+    user_id: Uuid,
     peer_map: &PeerMap,
+    state: &AppState,
 ) -> anyhow::Result<()> {
-    let ws_message = WebSocketMessage::TypingIndicator { payload };
-    // Send to conversation participants
-    // This is simplified - in production, get conversation participants
-    broadcast_to_all(peer_map, &ws_message).await;
+    let key = (user_id, payload.conversation_id);
+    let now = Instant::now();
+    
+    if !payload.is_typing {
+        let stopped = get_typing_map().lock().unwrap().remove(&key);
+        if let Some(typing) = stopped {
+            send_typing_stopped(peer_map, user_id, payload.conversation_id, &typing.recipients).await;
+        }
+        return Ok(());
+    }
+    
+    // Still typing: only push the expiry back until the throttle window is over
+    if let Some(typing) = get_typing_map().lock().unwrap().get_mut(&key) {
+        if now.duration_since(typing.last_sent) < TYPING_THROTTLE {
+            typing.expires_at = now + TYPING_TIMEOUT;
+            return Ok(());
+        }
+    }
+    
+    let participants =
+        ConversationService::get_participants(state.db.pool(), payload.conversation_id).await?;
+    if !participants.contains(&user_id) {
+        anyhow::bail!(
+            "User {} is not a participant of conversation {}",
+            user_id,
+            payload.conversation_id
+        );
+    }
+    let recipients: Vec<Uuid> = participants.into_iter().filter(|id| *id != user_id).collect();
+    
+    let ws_message = WebSocketMessage::TypingIndicator {
+        payload: TypingIndicator {
+            user_id,
+            conversation_id: payload.conversation_id,
+            is_typing: true,
+            timestamp: chrono::Utc::now(),
+        },
+    };
+    for recipient in &recipients {
+        send_to_user(peer_map, *recipient, &ws_message).await;
+    }
+    
+    get_typing_map().lock().unwrap().insert(
+        key,
+        TypingState {
+            recipients,
+            last_sent: now,
+            expires_at: now + TYPING_TIMEOUT,
+        },
+    );
     Ok(())
 }
 
+/// Send a stop event for typing states whose client went silent
+pub async fn expire_typing_indicators() {
+    let now = Instant::now();
+    let expired: Vec<((Uuid, Uuid), TypingState)> = {
+        let typing_map = get_typing_map();
+        let mut typing = typing_map.lock().unwrap();
+        let keys: Vec<(Uuid, Uuid)> = typing
+            .iter()
+            .filter(|(_, state)| state.expires_at <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        keys.into_iter()
+            .filter_map(|key| typing.remove(&key).map(|state| (key, state)))
+            .collect()
+    };
+    
+    let peer_map = get_peer_map();
+    for ((user_id, conversation_id), typing) in expired {
+        send_typing_stopped(&peer_map, user_id, conversation_id, &typing.recipients).await;
+    }
+}
+
+/// Stop every typing state of a user, called when their last connection closes
+async fn clear_user_typing(peer_map: &PeerMap, user_id: Uuid) {
+    let stopped: Vec<(Uuid, TypingState)> = {
+        let typing_map = get_typing_map();
+        let mut typing = typing_map.lock().unwrap();
+        let keys: Vec<(Uuid, Uuid)> = typing
+            .keys()
+            .filter(|(typing_user, _)| *typing_user == user_id)
+            .copied()
+            .collect();
+        keys.into_iter()
+            .filter_map(|key| typing.remove(&key).map(|state| (key.1, state)))
+            .collect()
+    };
+    
+    for (conversation_id, typing) in stopped {
+        send_typing_stopped(peer_map, user_id, conversation_id, &typing.recipients).await;
+    }
+}
+
+async fn send_typing_stopped(
+    peer_map: &PeerMap,
+    user_id: Uuid,
+    conversation_id: Uuid,
+    recipients: &[Uuid],
+) {
+    let ws_message = WebSocketMessage::TypingIndicator {
+        payload: TypingIndicator {
+            user_id,
+            conversation_id,
+            is_typing: false,
+            timestamp: chrono::Utc::now(),
+        },
+    };
+    for recipient in recipients {
+        send_to_user(peer_map, *recipient, &ws_message).await;
+    }
+}
+
 async fn handle_read_receipt(
     payload: ReadReceipt,
     reader_id: Uuid,
+    peer_map: &PeerMap,
     state: &AppState,
 ) -> anyhow::Result<()> {
-    MessageService::mark_as_read(state.db.pool(), payload.message_id, reader_id).await?;
+    if let Some(message) =
+        MessageService::mark_as_read(state.db.pool(), payload.message_id, reader_id).await?
+    {
+        send_message_status(peer_map, state, &message).await;
+    }
+    Ok(())
+}
+
+/// Handle delivery acknowledgements from a recipient device
+async fn handle_message_ack(
+    payload: MessageAckPayload,
+    recipient_id: Uuid,
+    peer_map: &PeerMap,
+    state: &AppState,
+) -> anyhow::Result<()> {
+    let messages =
+        MessageService::mark_as_delivered(state.db.pool(), &payload.message_ids, recipient_id)
+            .await?;
+    for message in messages {
+        send_message_status(peer_map, state, &message).await;
+    }
     Ok(())
 }
 
-async fn handle_heartbeat(user_id: Uuid, peer_map: &PeerMap) -> anyhow::Result<()> {
+/// Push the current delivery state of a message to its sender
+async fn send_message_status(
+    peer_map: &PeerMap,
+    state: &AppState,
+    message: &crate::models::Message,
+) {
+    let timestamp = message
+        .read_at
+        .or(message.delivered_at)
+        .unwrap_or_else(chrono::Utc::now);
+    let status = WebSocketMessage::MessageStatus {
+        payload: MessageStatusPayload {
+            message_id: message.id,
+            conversation_id: message.conversation_id,
+            status: message.status().to_string(),
+            timestamp,
+        },
+    };
+    send_or_queue(peer_map, state, message.sender_id, &status).await;
+}
+
+/// Notify the sender that a message was read through the REST API
+pub async fn notify_message_read(state: &AppState, message: &crate::models::Message) {
+    send_message_status(&get_peer_map(), state, message).await;
+}
+
+async fn handle_heartbeat(
+    user_id: Uuid,
+    connection_id: Uuid,
+    peer_map: &PeerMap,
+) -> anyhow::Result<()> {
     let response = WebSocketMessage::HeartbeatResponse {
         payload: HeartbeatPayload {
             timestamp: chrono::Utc::now(),
         },
     };
-    send_to_user(peer_map, user_id, &response).await;
+    send_to_connection(peer_map, user_id, connection_id, &response).await;
     Ok(())
 }
 
+/// Send an event to every live connection (device) of a user
 async fn send_to_user(peer_map: &PeerMap, user_id: Uuid, message: &WebSocketMessage) {
     let peers = peer_map.read().await;
-    if let Some(tx) = peers.get(&user_id) {
-        if let Ok(json) = serde_json::to_string(message) {
-            let _ = tx.send(json);
+    if let Some(connections) = peers.get(&user_id) {
+        if let Some(json) = encode_event(message) {
+            for connection in connections.values() {
+                let _ = connection.tx.send(json.clone());
+            }
         }
     }
 }
 
-async fn broadcast_to_all(peer_map: &PeerMap, message: &WebSocketMessage) {
+/// Send an event to a user, or queue it for replay if they have no live connection
+async fn send_or_queue(
+    peer_map: &PeerMap,
+    state: &AppState,
+    user_id: Uuid,
+    message: &WebSocketMessage,
+) {
+    // Checked before the event can be stored in the queue
+    if let Err(rejection) = SecurityGateway::verify_metadata_only(message) {
+        tracing::error!(target: "audit", "🛡️ Event for user {} dropped: {}", user_id, rejection);
+        return;
+    }
+    
+    let is_connected = peer_map.read().await.contains_key(&user_id);
+    if is_connected {
+        send_to_user(peer_map, user_id, message).await;
+        return;
+    }
+    
+    if let Err(e) = PendingEventService::enqueue(
+        state.db.pool(),
+        user_id,
+        message,
+        pending_event_ttl(message),
+    )
+    .await
+    {
+        tracing::error!("Failed to queue event for offline user {}: {:?}", user_id, e);
+    }
+}
+
+/// How long an undelivered event stays relevant
+/// 
+/// Call signalling is useless once the call timed out (see background tasks),
+/// message metadata and receipts are kept for a month.
+fn pending_event_ttl(message: &WebSocketMessage) -> chrono::Duration {
+    match message {
+        WebSocketMessage::CallRequestFull { .. } | WebSocketMessage::CallResponseFull { .. } => {
+            chrono::Duration::seconds(60)
+        }
+        _ => chrono::Duration::days(30),
+    }
+}
+
+/// Push the next page of events queued while the user was offline to one of their connections
+/// 
+/// Does nothing while the page already replayed to the connection is not fully acknowledged.
+/// Queued events keep their order among themselves, but live events bypass the queue and can
+/// overtake the pages not replayed yet.
+async fn replay_pending_events(
+    peer_map: &PeerMap,
+    user_id: Uuid,
+    connection_id: Uuid,
+    state: &AppState,
+) {
+    let cursor = {
+        let peers = peer_map.read().await;
+        match peers.get(&user_id).and_then(|connections| connections.get(&connection_id)) {
+            Some(connection) => connection.pending_events_cursor.load(Ordering::Relaxed),
+            None => return,
+        }
+    };
+    
+    if cursor > 0 {
+        match PendingEventService::count_unacknowledged(state.db.pool(), user_id, cursor).await {
+            Ok(0) => {}
+            Ok(_) => return,
+            Err(e) => {
+                tracing::error!("Failed to count pending events for user {}: {:?}", user_id, e);
+                return;
+            }
+        }
+    }
+    
+    let events = match PendingEventService::get_pending(
+        state.db.pool(),
+        user_id,
+        cursor,
+        REPLAY_PAGE_SIZE,
+    )
+    .await
+    {
+        Ok(events) => events,
+        Err(e) => {
+            tracing::error!("Failed to load pending events for user {}: {:?}", user_id, e);
+            return;
+        }
+    };
+    
+    if let Some(last) = events.last() {
+        let peers = peer_map.read().await;
+        if let Some(connection) = peers.get(&user_id).and_then(|connections| connections.get(&connection_id)) {
+            connection.pending_events_cursor.store(last.seq, Ordering::Relaxed);
+        }
+    }
+    
+    for pending in events {
+        let event: WebSocketMessage = match serde_json::from_str(&pending.event) {
+            Ok(event) => event,
+            Err(e) => {
+                tracing::warn!("Dropping unreadable pending event {}: {}", pending.id, e);
+                continue;
+            }
+        };
+        let replay = WebSocketMessage::PendingEvent {
+            payload: PendingEventPayload {
+                event_id: pending.id,
+                created_at: pending.created_at,
+                event: Box::new(event),
+            },
+        };
+        send_to_connection(peer_map, user_id, connection_id, &replay).await;
+    }
+}
+
+/// Push the next page of sealed messages waiting for the device of a connection
+/// 
+/// Does nothing for connections without a device, or while the page already replayed is not fully acknowledged.
+async fn replay_sealed_messages(
+    peer_map: &PeerMap,
+    user_id: Uuid,
+    connection_id: Uuid,
+    state: &AppState,
+) {
+    let (device_id, cursor) = {
+        let peers = peer_map.read().await;
+        match peers.get(&user_id).and_then(|connections| connections.get(&connection_id)) {
+            Some(Connection { device_id: Some(device_id), sealed_messages_cursor, .. }) => {
+                (*device_id, sealed_messages_cursor.load(Ordering::Relaxed))
+            }
+            _ => return,
+        }
+    };
+    
+    if cursor > 0 {
+        match SealedMessageService::count_unacknowledged(state.db.pool(), device_id, cursor).await {
+            Ok(0) => {}
+            Ok(_) => return,
+            Err(e) => {
+                tracing::error!("Failed to count sealed messages for device {}: {:?}", device_id, e);
+                return;
+            }
+        }
+    }
+    
+    let messages = match SealedMessageService::get_pending(
+        state.db.pool(),
+        device_id,
+        cursor,
+        SEALED_REPLAY_PAGE_SIZE,
+    )
+    .await
+    {
+        Ok(messages) => messages,
+        Err(e) => {
+            tracing::error!("Failed to load sealed messages for device {}: {:?}", device_id, e);
+            return;
+        }
+    };
+    
+    if let Some(last) = messages.last() {
+        let peers = peer_map.read().await;
+        if let Some(connection) = peers.get(&user_id).and_then(|connections| connections.get(&connection_id)) {
+            connection.sealed_messages_cursor.store(last.seq, Ordering::Relaxed);
+        }
+    }
+    
+    for message in &messages {
+        send_to_connection(peer_map, user_id, connection_id, &sealed_message_event(message)).await;
+    }
+}
+
+fn sealed_message_event(message: &SealedMessage) -> WebSocketMessage {
+    use base64::{engine::general_purpose, Engine as _};
+    WebSocketMessage::SealedMessage {
+        payload: SealedMessagePayload {
+            id: message.id,
+            device_id: message.device_id,
+            envelope: general_purpose::STANDARD.encode(&message.envelope),
+            timestamp: message.created_at,
+        },
+    }
+}
+
+/// Send an event to one specific connection of a user
+async fn send_to_connection(
+    peer_map: &PeerMap,
+    user_id: Uuid,
+    connection_id: Uuid,
+    message: &WebSocketMessage,
+) {
     let peers = peer_map.read().await;
-    if let Ok(json) = serde_json::to_string(message) {
-        for tx in peers.values() {
-            let _ = tx.send(json.clone());
+    if let Some(connection) = peers
+        .get(&user_id)
+        .and_then(|connections| connections.get(&connection_id))
+    {
+        if let Some(json) = encode_event(message) {
+            let _ = connection.tx.send(json);
         }
     }
 }
 
+/// Serialize an event for the wire, unless it carries fields outside the gateway whitelist
+fn encode_event(message: &WebSocketMessage) -> Option<String> {
+    if let Err(rejection) = SecurityGateway::verify_metadata_only(message) {
+        tracing::error!(target: "audit", "🛡️ Outgoing WebSocket event dropped: {}", rejection);
+        return None;
+    }
+    serde_json::to_string(message).ok()
+}
+
 async fn handle_presence_update(
     payload: PresenceUpdate,
     user_id: Uuid,
     peer_map: &PeerMap,
     state: &AppState,
 ) -> anyhow::Result<()> {
+    let valid_statuses = ["online", "offline", "away", "busy"];
+    if !valid_statuses.contains(&payload.status.as_str()) {
+        anyhow::bail!("Invalid presence status: {}", payload.status);
+    }
+    
     // Update presence in database
-    let _ = crate::services::PresenceService::update_presence(
+    let presence = crate::services::PresenceService::update_presence(
         state.db.pool(),
         user_id,
         &payload.status,
     )
     .await?;
     
-    // Broadcast to all connected users
-    let update = WebSocketMessage::PresenceUpdate { payload };
-    broadcast_to_all(peer_map, &update).await;
+    let update = WebSocketMessage::PresenceUpdate {
+        payload: PresenceUpdate {
+            user_id,
+            status: presence.status,
+            last_seen: presence.last_seen,
+        },
+    };
+    send_to_presence_audience(peer_map, state, user_id, &update).await;
     
     Ok(())
 }
@@ -433,12 +1074,13 @@ async fn broadcast_presence_update(
     state: &AppState,
 ) {
     // Update in database
-    if let Ok(_) = crate::services::PresenceService::update_presence(
+    if crate::services::PresenceService::update_presence(
         state.db.pool(),
         user_id,
         status,
     )
     .await
+    .is_ok()
     {
         let update = WebSocketMessage::PresenceUpdate {
             payload: PresenceUpdate {
@@ -448,8 +1090,112 @@ async fn broadcast_presence_update(
             },
         };
         
-        // Broadcast to all connected users
-        broadcast_to_all(peer_map, &update).await;
+        send_to_presence_audience(peer_map, state, user_id, &update).await;
+    }
+}
+
+/// Send a presence change to the contacts allowed to see it
+async fn send_to_presence_audience(
+    peer_map: &PeerMap,
+    state: &AppState,
+    user_id: Uuid,
+    update: &WebSocketMessage,
+) {
+    let audience = match PresenceService::get_presence_audience(state.db.pool(), user_id).await {
+        Ok(audience) => audience,
+        Err(e) => {
+            tracing::error!("Failed to get presence audience of user {}: {:?}", user_id, e);
+            return;
+        }
+    };
+    for contact_id in audience {
+        send_to_user(peer_map, contact_id, update).await;
+    }
+}
+
+/// Show the user as offline to the contacts currently receiving their presence
+/// 
+/// Called before the presence becomes hidden so contacts don't keep a stale status.
+pub async fn hide_presence(state: &AppState, user_id: Uuid) {
+    let update = WebSocketMessage::PresenceUpdate {
+        payload: PresenceUpdate {
+            user_id,
+            status: "offline".to_string(),
+            last_seen: chrono::Utc::now(),
+        },
+    };
+    send_to_presence_audience(&get_peer_map(), state, user_id, &update).await;
+}
+
+/// Tell the former contacts of an erased account to drop it, offline ones at their next connection
+pub async fn notify_account_deleted(state: &AppState, user_id: Uuid, contacts: &[Uuid]) {
+    disconnect_user(user_id).await;
+    
+    let peer_map = get_peer_map();
+    let event = WebSocketMessage::ContactDeleted {
+        payload: ContactDeletedPayload { user_id },
+    };
+    for contact_id in contacts {
+        send_or_queue(&peer_map, state, *contact_id, &event).await;
+    }
+}
+
+/// Ask a user's devices to upload more one-time prekeys, offline devices get it at their next connection
+pub async fn notify_prekeys_low(state: &AppState, user_id: Uuid, device_id: Uuid, remaining: i64) {
+    let event = WebSocketMessage::PrekeysLow {
+        payload: PrekeysLowPayload {
+            device_id,
+            remaining,
+        },
+    };
+    send_or_queue(&get_peer_map(), state, user_id, &event).await;
+}
+
+/// Tell everyone sharing a conversation with the user that one of their devices has a new identity key
+pub async fn notify_identity_changed(state: &AppState, entry: &IdentityKeyLogEntry) {
+    use base64::{engine::general_purpose, Engine as _};
+    
+    // Channel co-members have no session with the user's devices, they don't need to know
+    let contacts = match ConversationService::get_partners(state.db.pool(), entry.user_id).await {
+        Ok(contacts) => contacts,
+        Err(e) => {
+            tracing::error!("Failed to get conversation partners of user {}: {:?}", entry.user_id, e);
+            return;
+        }
+    };
+    
+    let peer_map = get_peer_map();
+    let event = WebSocketMessage::IdentityChanged {
+        payload: IdentityChangedPayload {
+            user_id: entry.user_id,
+            device_id: entry.device_id,
+            identity_key: general_purpose::STANDARD.encode(&entry.identity_key),
+            log_index: entry.log_index,
+            entry_hash: general_purpose::STANDARD.encode(&entry.entry_hash),
+        },
+    };
+    for contact_id in contacts {
+        send_or_queue(&peer_map, state, contact_id, &event).await;
+    }
+}
+
+/// Push sealed messages to the live connections of their destination device
+/// 
+/// They stay stored until acknowledged, offline devices get them at their next connection.
+pub async fn notify_sealed_messages(user_id: Uuid, messages: &[SealedMessage]) {
+    let peer_map = get_peer_map();
+    let peers = peer_map.read().await;
+    let Some(connections) = peers.get(&user_id) else {
+        return;
+    };
+    for message in messages {
+        if let Some(json) = encode_event(&sealed_message_event(message)) {
+            for connection in connections.values() {
+                if connection.device_id == Some(message.device_id) {
+                    let _ = connection.tx.send(json.clone());
+                }
+            }
+        }
     }
 }
 
@@ -461,3 +1207,10 @@ fn get_peer_map() -> PeerMap {
         .clone()
 }
 
+fn get_typing_map() -> TypingMap {
+    use std::sync::OnceLock;
+    static TYPING_MAP: OnceLock<TypingMap> = OnceLock::new();
+    TYPING_MAP
+        .get_or_init(|| Arc::new(Mutex::new(HashMap::new())))
+        .clone()
+}